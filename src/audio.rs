@@ -1,7 +1,31 @@
+pub mod codec;
+pub mod decode;
 pub mod input;
+pub mod jitter;
 pub mod message;
+pub mod mix;
 mod output;
+pub mod replay;
+pub mod resample;
+pub mod samples;
+pub mod sequence;
+pub mod wav;
 
-pub use input::WavAudioInput;
-pub use message::{AudioMessage, DeserializationError, Serializable};
-pub use output::{SpeakerOutput, SpeakerOutputBuilder, WavAudioOutput, WavOutputError};
+pub use codec::{CodecError, EncodedFrame};
+pub use decode::DecodedAudioInput;
+pub use input::{AudioSource, AudioSourceError, SineWave, WavAudioInput, open_audio_file};
+pub use jitter::{JitterBuffer, JitterStats};
+pub use message::{
+    AudioMessage, Codec, DeserializationError, Features, Hello, PROTOCOL_VERSION, PcmFormat,
+    SamplesFrame, Serializable, Welcome,
+};
+pub use mix::ChannelMixer;
+pub use output::{
+    AudioSink, AudioSinkError, NullSink, SpeakerOutput, SpeakerOutputBuilder, SpeakerSink,
+    WavAudioOutput, WavFileSink, WavOutputError,
+};
+pub use replay::ReplayWindow;
+pub use resample::Resampler;
+pub use samples::SampleBuffer;
+pub use sequence::{FrameOrder, SequenceTracker};
+pub use wav::{streaming_wav_header, write_wav_samples};
@@ -0,0 +1,194 @@
+pub mod adpcm;
+mod bits;
+pub mod g711;
+pub mod lossless;
+
+use crate::audio::message::{Codec, PcmFormat, SamplesFrame};
+use crate::audio::samples::SampleBuffer;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CodecError {
+    /// The payload ended before every sample was decoded.
+    Truncated,
+    /// The payload holds a subframe or parameter the decoder doesn't know.
+    InvalidData,
+    /// The decoded samples don't add up to the announced count.
+    SampleCountMismatch { expected: usize, decoded: usize },
+}
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Truncated => write!(f, "Encoded payload is truncated"),
+            CodecError::InvalidData => write!(f, "Encoded payload is invalid"),
+            CodecError::SampleCountMismatch { expected, decoded } => {
+                write!(f, "Expected {} samples, decoded {}", expected, decoded)
+            }
+        }
+    }
+}
+
+/// A [`SamplesFrame`] whose samples are compressed with a negotiated codec.
+#[derive(Debug, PartialEq, Clone)]
+pub struct EncodedFrame {
+    pub sequence: u64,
+    pub position: u64,
+    pub codec: Codec,
+    /// Format of the samples once decoded.
+    pub format: PcmFormat,
+    pub channels: u16,
+    /// Number of samples once decoded, counting every channel.
+    pub sample_count: u32,
+    pub payload: Vec<u8>,
+}
+
+impl EncodedFrame {
+    /// Compresses `frame`, whose samples are interleaved over `channels` channels.
+    /// Lossy codecs work on 16-bit samples, converting the frame first if needed.
+    pub fn encode(frame: &SamplesFrame, codec: Codec, channels: u16) -> Self {
+        let samples = &frame.samples;
+        // A partial frame can only be coded as a single channel
+        let channels = if channels > 0 && samples.len().is_multiple_of(channels as usize) {
+            channels
+        } else {
+            1
+        };
+        let mut lossy_samples = Vec::new();
+        if !codec.is_lossless() {
+            samples.extend_i16(&mut lossy_samples);
+        }
+        let payload = match codec {
+            Codec::Pcm => {
+                let mut payload = Vec::new();
+                samples.write_le(&mut payload);
+                payload
+            }
+            Codec::Lossless => lossless::encode(samples, channels as usize),
+            Codec::ImaAdpcm => adpcm::encode(&lossy_samples, channels as usize),
+            Codec::MuLaw => g711::encode_mu_law(&lossy_samples),
+            Codec::ALaw => g711::encode_a_law(&lossy_samples),
+        };
+        EncodedFrame {
+            sequence: frame.sequence,
+            position: frame.position,
+            codec,
+            format: codec.decoded_format(samples.format()),
+            channels,
+            sample_count: samples.len() as u32,
+            payload,
+        }
+    }
+
+    pub fn decode(&self) -> Result<SamplesFrame, CodecError> {
+        let expected = self.sample_count as usize;
+        let samples = match self.codec {
+            Codec::Pcm => {
+                if self.payload.len() != expected * self.format.bytes_per_sample() {
+                    return Err(CodecError::Truncated);
+                }
+                SampleBuffer::read_le(self.format, &self.payload)
+            }
+            Codec::Lossless => lossless::decode(
+                &self.payload,
+                self.format,
+                self.channels.max(1) as usize,
+                expected,
+            )?,
+            Codec::ImaAdpcm => SampleBuffer::I16(adpcm::decode(
+                &self.payload,
+                self.channels.max(1) as usize,
+                expected,
+            )?),
+            Codec::MuLaw => SampleBuffer::I16(g711::decode_mu_law(&self.payload)),
+            Codec::ALaw => SampleBuffer::I16(g711::decode_a_law(&self.payload)),
+        };
+        if samples.len() != expected {
+            return Err(CodecError::SampleCountMismatch {
+                expected,
+                decoded: samples.len(),
+            });
+        }
+        Ok(SamplesFrame {
+            sequence: self.sequence,
+            position: self.position,
+            samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EncodedFrame;
+    use crate::audio::message::{Codec, PcmFormat, SamplesFrame};
+    use crate::audio::samples::SampleBuffer;
+
+    #[test]
+    fn lossy_codecs_track_the_signal() {
+        let tone: Vec<i16> = (0..2_000)
+            .map(|n| ((n as f64 * 0.03).sin() * 20_000.0) as i16)
+            .collect();
+        let frame = SamplesFrame {
+            sequence: 1,
+            position: 0,
+            samples: SampleBuffer::I16(tone.clone()),
+        };
+        for (codec, bytes, tolerance) in [
+            (Codec::ImaAdpcm, 2 * 4 + 999, 1_500),
+            (Codec::MuLaw, 2_000, 700),
+            (Codec::ALaw, 2_000, 700),
+        ] {
+            let encoded = EncodedFrame::encode(&frame, codec, 2);
+            assert_eq!(encoded.payload.len(), bytes, "{codec:?} payload size");
+            let decoded = encoded.decode().expect("decode failed");
+            let SampleBuffer::I16(decoded) = decoded.samples else {
+                panic!("{codec:?} should decode to 16-bit samples");
+            };
+            assert_eq!(decoded.len(), tone.len());
+            let worst = tone
+                .iter()
+                .zip(&decoded)
+                .map(|(&a, &b)| (a as i32 - b as i32).abs())
+                .max()
+                .unwrap_or(0);
+            assert!(worst < tolerance, "{codec:?} is off by {worst}");
+        }
+    }
+
+    #[test]
+    fn lossy_codecs_convert_to_16_bit() {
+        let frame = SamplesFrame {
+            sequence: 0,
+            position: 0,
+            samples: SampleBuffer::F32(vec![0.0, 0.5, -0.5, 1.0, -1.0]),
+        };
+        for codec in [Codec::MuLaw, Codec::ALaw] {
+            let encoded = EncodedFrame::encode(&frame, codec, 1);
+            assert_eq!(encoded.format, PcmFormat::I16);
+            let decoded = encoded.decode().expect("decode failed");
+            let SampleBuffer::I16(decoded) = decoded.samples else {
+                panic!("{codec:?} should decode to 16-bit samples");
+            };
+            let expected = [0, 16_384, -16_384, 32_767, -32_768];
+            for (&sample, &expected) in decoded.iter().zip(&expected) {
+                assert!(
+                    (sample as i32 - expected).abs() < 1_100,
+                    "{codec:?}: {sample}"
+                );
+            }
+        }
+        // Silence maps onto the standard code words
+        let silence = SamplesFrame {
+            sequence: 0,
+            position: 0,
+            samples: SampleBuffer::I16(vec![0]),
+        };
+        assert_eq!(
+            EncodedFrame::encode(&silence, Codec::MuLaw, 1).payload,
+            [0xFF]
+        );
+        assert_eq!(
+            EncodedFrame::encode(&silence, Codec::ALaw, 1).payload,
+            [0xD5]
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! IMA ADPCM: 4 bits per 16-bit sample. Every frame starts each channel afresh from a
+//! header holding its first sample and step index, so frames decode independently.
+
+use crate::audio::codec::CodecError;
+
+const STEP_SIZES: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+const INDEX_ADJUSTMENTS: [i32; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+/// predictor(2) + step_index(1) + reserved(1)
+const CHANNEL_HEADER_LEN: usize = 4;
+
+#[derive(Clone, Copy)]
+struct ChannelState {
+    predictor: i32,
+    index: usize,
+}
+
+impl ChannelState {
+    /// Updates the state with `nibble` and returns the new predicted sample.
+    fn decode(&mut self, nibble: u8) -> i16 {
+        let step = STEP_SIZES[self.index];
+        let mut delta = step >> 3;
+        if nibble & 4 != 0 {
+            delta += step;
+        }
+        if nibble & 2 != 0 {
+            delta += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            delta += step >> 2;
+        }
+        if nibble & 8 != 0 {
+            delta = -delta;
+        }
+        self.predictor = (self.predictor + delta).clamp(i16::MIN as i32, i16::MAX as i32);
+        let adjusted = self.index as i32 + INDEX_ADJUSTMENTS[(nibble & 7) as usize];
+        self.index = adjusted.clamp(0, STEP_SIZES.len() as i32 - 1) as usize;
+        self.predictor as i16
+    }
+
+    fn encode(&mut self, sample: i16) -> u8 {
+        let mut difference = sample as i32 - self.predictor;
+        let mut nibble = 0;
+        if difference < 0 {
+            nibble = 8;
+            difference = -difference;
+        }
+        let mut step = STEP_SIZES[self.index];
+        for bit in [4, 2, 1] {
+            if difference >= step {
+                nibble |= bit;
+                difference -= step;
+            }
+            step >>= 1;
+        }
+        // Decoding keeps the encoder in step with what the receiver reconstructs
+        self.decode(nibble);
+        nibble
+    }
+}
+
+/// Step index matching the average change between the first samples of a channel, so
+/// a frame doesn't start with a long run of tiny steps.
+fn initial_index(samples: impl Iterator<Item = i16>) -> usize {
+    let samples: Vec<i32> = samples.take(16).map(|s| s as i32).collect();
+    if samples.len() < 2 {
+        return 0;
+    }
+    let change = samples
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).abs())
+        .sum::<i32>()
+        / (samples.len() as i32 - 1);
+    STEP_SIZES
+        .iter()
+        .position(|&step| step >= change)
+        .unwrap_or(STEP_SIZES.len() - 1)
+}
+
+pub fn encode(samples: &[i16], channels: usize) -> Vec<u8> {
+    let frames = samples.len() / channels;
+    if frames == 0 {
+        return Vec::new();
+    }
+    let mut payload = Vec::with_capacity(channels * CHANNEL_HEADER_LEN + samples.len() / 2);
+    let mut states = Vec::with_capacity(channels);
+    for channel in 0..channels {
+        let first = samples[channel];
+        let index = initial_index(samples.iter().skip(channel).step_by(channels).copied());
+        payload.extend_from_slice(&first.to_le_bytes());
+        payload.push(index as u8);
+        payload.push(0);
+        states.push(ChannelState {
+            predictor: first as i32,
+            index,
+        });
+    }
+    let nibbles = samples[channels..]
+        .iter()
+        .enumerate()
+        .map(|(i, &sample)| states[i % channels].encode(sample));
+    pack_nibbles(nibbles, &mut payload);
+    payload
+}
+
+pub fn decode(
+    payload: &[u8],
+    channels: usize,
+    sample_count: usize,
+) -> Result<Vec<i16>, CodecError> {
+    if !sample_count.is_multiple_of(channels) {
+        return Err(CodecError::InvalidData);
+    }
+    if sample_count == 0 {
+        return Ok(Vec::new());
+    }
+    let nibble_count = sample_count - channels;
+    let header_len = channels * CHANNEL_HEADER_LEN;
+    if payload.len() < header_len + nibble_count.div_ceil(2) {
+        return Err(CodecError::Truncated);
+    }
+    let mut samples = Vec::with_capacity(sample_count);
+    let mut states = Vec::with_capacity(channels);
+    for header in payload[..header_len].chunks(CHANNEL_HEADER_LEN) {
+        let first = i16::from_le_bytes([header[0], header[1]]);
+        let index = header[2] as usize;
+        if index >= STEP_SIZES.len() {
+            return Err(CodecError::InvalidData);
+        }
+        samples.push(first);
+        states.push(ChannelState {
+            predictor: first as i32,
+            index,
+        });
+    }
+    for i in 0..nibble_count {
+        let byte = payload[header_len + i / 2];
+        let nibble = if i % 2 == 0 { byte & 0x0F } else { byte >> 4 };
+        samples.push(states[i % channels].decode(nibble));
+    }
+    Ok(samples)
+}
+
+/// Packs two nibbles per byte, the first one in the low bits.
+fn pack_nibbles(nibbles: impl Iterator<Item = u8>, out: &mut Vec<u8>) {
+    let mut pending = None;
+    for nibble in nibbles {
+        match pending.take() {
+            None => pending = Some(nibble),
+            Some(low) => out.push(low | (nibble << 4)),
+        }
+    }
+    if let Some(low) = pending {
+        out.push(low);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::audio::codec::CodecError;
+
+/// Packs values most significant bit first.
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits already used in the last byte, 0 when it is full.
+    used: u32,
+}
+
+impl BitWriter {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+    /// Writes the low `bits` bits of `value`, `bits` being at most 64.
+    pub(crate) fn write(&mut self, value: u64, bits: u32) {
+        let mut remaining = bits;
+        while remaining > 0 {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let free = 8 - self.used;
+            let count = free.min(remaining);
+            let chunk = ((value >> (remaining - count)) & ((1 << count) - 1)) as u8;
+            *self.bytes.last_mut().unwrap() |= chunk << (free - count);
+            self.used = (self.used + count) % 8;
+            remaining -= count;
+        }
+    }
+    /// Writes a signed value as `bits`-bit two's complement.
+    pub(crate) fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+    /// Writes `count` zeros followed by a one.
+    pub(crate) fn write_unary(&mut self, count: u64) {
+        let mut count = count;
+        while count >= 32 {
+            self.write(0, 32);
+            count -= 32;
+        }
+        self.write(1, count as u32 + 1);
+    }
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub(crate) struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// Position in bits.
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+    pub(crate) fn read(&mut self, bits: u32) -> Result<u64, CodecError> {
+        if self.position + bits as usize > self.bytes.len() * 8 {
+            return Err(CodecError::Truncated);
+        }
+        let mut value = 0u64;
+        for _ in 0..bits {
+            let byte = self.bytes[self.position / 8];
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+    /// Reads a `bits`-bit two's complement value.
+    pub(crate) fn read_signed(&mut self, bits: u32) -> Result<i64, CodecError> {
+        let value = self.read(bits)?;
+        if bits == 0 || bits == 64 {
+            return Ok(value as i64);
+        }
+        let shift = 64 - bits;
+        Ok(((value << shift) as i64) >> shift)
+    }
+    pub(crate) fn read_unary(&mut self) -> Result<u64, CodecError> {
+        let mut count = 0;
+        while self.read(1)? == 0 {
+            count += 1;
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,81 @@
+//! G.711 companding: every 16-bit sample is stored as one logarithmic byte.
+
+/// Added before looking up the mu-law segment, so small values land in the first one.
+const MU_LAW_BIAS: i32 = 0x84;
+const MU_LAW_CLIP: i32 = 32_635;
+/// Largest 13-bit magnitude of each A-law segment.
+const A_LAW_SEGMENT_ENDS: [i32; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+
+pub fn encode_mu_law(samples: &[i16]) -> Vec<u8> {
+    samples.iter().map(|&s| linear_to_mu_law(s)).collect()
+}
+
+pub fn decode_mu_law(payload: &[u8]) -> Vec<i16> {
+    payload.iter().map(|&b| mu_law_to_linear(b)).collect()
+}
+
+pub fn encode_a_law(samples: &[i16]) -> Vec<u8> {
+    samples.iter().map(|&s| linear_to_a_law(s)).collect()
+}
+
+pub fn decode_a_law(payload: &[u8]) -> Vec<i16> {
+    payload.iter().map(|&b| a_law_to_linear(b)).collect()
+}
+
+fn linear_to_mu_law(sample: i16) -> u8 {
+    let mut magnitude = sample as i32;
+    let sign = if magnitude < 0 {
+        magnitude = -magnitude;
+        0x80
+    } else {
+        0
+    };
+    magnitude = magnitude.min(MU_LAW_CLIP) + MU_LAW_BIAS;
+    let exponent = (0..8).rev().find(|&e| magnitude >= 0x80 << e).unwrap_or(0);
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) as u8 | mantissa as u8)
+}
+
+fn mu_law_to_linear(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0F) as i32;
+    let magnitude = (((mantissa << 3) + MU_LAW_BIAS) << exponent) - MU_LAW_BIAS;
+    if byte & 0x80 != 0 {
+        -magnitude as i16
+    } else {
+        magnitude as i16
+    }
+}
+
+fn linear_to_a_law(sample: i16) -> u8 {
+    let mut magnitude = (sample as i32) >> 3;
+    let mask = if magnitude >= 0 {
+        0xD5
+    } else {
+        magnitude = -magnitude - 1;
+        0x55
+    };
+    let Some(segment) = A_LAW_SEGMENT_ENDS.iter().position(|&end| magnitude <= end) else {
+        return 0x7F ^ mask;
+    };
+    let shift = if segment < 2 { 1 } else { segment };
+    let value = (segment << 4) as u8 | ((magnitude >> shift) & 0x0F) as u8;
+    value ^ mask
+}
+
+fn a_law_to_linear(byte: u8) -> i16 {
+    let byte = byte ^ 0x55;
+    let segment = (byte & 0x70) >> 4;
+    let mut magnitude = ((byte & 0x0F) as i32) << 4;
+    match segment {
+        0 => magnitude += 8,
+        1 => magnitude += 0x108,
+        _ => magnitude = (magnitude + 0x108) << (segment - 1),
+    }
+    if byte & 0x80 != 0 {
+        magnitude as i16
+    } else {
+        -magnitude as i16
+    }
+}
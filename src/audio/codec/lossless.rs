@@ -0,0 +1,429 @@
+//! FLAC-style lossless coding: every channel is predicted from its previous samples,
+//! either with a fixed polynomial or with quantized linear prediction coefficients, and
+//! the prediction residual is Rice coded in partitions with their own parameter.
+
+use crate::audio::codec::CodecError;
+use crate::audio::codec::bits::{BitReader, BitWriter};
+use crate::audio::message::PcmFormat;
+use crate::audio::samples::SampleBuffer;
+use crate::network::MAX_FRAME_SIZE;
+
+const CONSTANT: u64 = 0;
+const VERBATIM: u64 = 1;
+const FIXED: u64 = 2;
+const LPC: u64 = 3;
+
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_LPC_ORDER: usize = 12;
+/// Bits of each quantized LPC coefficient.
+const LPC_PRECISION: u32 = 14;
+const MAX_LPC_SHIFT: i32 = 31;
+/// Residual partitions hold `2^order` values.
+const MAX_PARTITION_ORDER: u32 = 8;
+const MAX_RICE_PARAMETER: u32 = 63;
+/// Most samples a frame may announce, as many as the largest frame of 8-bit PCM holds.
+/// The count comes from the wire, so it is checked before allocating anything.
+const MAX_SAMPLE_COUNT: usize = MAX_FRAME_SIZE;
+
+/// How a channel is predicted.
+enum Predictor {
+    Constant,
+    Verbatim,
+    Fixed(usize),
+    Lpc { coefficients: Vec<i64>, shift: u32 },
+}
+
+/// Rice parameters picked for a residual.
+struct RicePlan {
+    partition_order: u32,
+    parameters: Vec<u32>,
+    bits: u64,
+}
+
+pub fn encode(samples: &SampleBuffer, channels: usize) -> Vec<u8> {
+    let bits = sample_bits(samples.format());
+    let values = to_i32(samples);
+    let mut writer = BitWriter::new();
+    let mut channel = Vec::with_capacity(values.len() / channels);
+    for offset in 0..channels {
+        channel.clear();
+        channel.extend(
+            values
+                .iter()
+                .skip(offset)
+                .step_by(channels)
+                .map(|&v| v as i64),
+        );
+        encode_channel(&channel, bits, &mut writer);
+    }
+    writer.into_bytes()
+}
+
+pub fn decode(
+    payload: &[u8],
+    format: PcmFormat,
+    channels: usize,
+    sample_count: usize,
+) -> Result<SampleBuffer, CodecError> {
+    if sample_count > MAX_SAMPLE_COUNT || !sample_count.is_multiple_of(channels) {
+        return Err(CodecError::InvalidData);
+    }
+    let frames = sample_count / channels;
+    let bits = sample_bits(format);
+    let mut reader = BitReader::new(payload);
+    let mut values = vec![0i32; sample_count];
+    let mut channel = Vec::with_capacity(frames);
+    for offset in 0..channels {
+        decode_channel(&mut reader, bits, frames, &mut channel)?;
+        for (frame, &value) in channel.iter().enumerate() {
+            values[frame * channels + offset] = value as i32;
+        }
+    }
+    Ok(from_i32(format, values))
+}
+
+fn sample_bits(format: PcmFormat) -> u32 {
+    format.bytes_per_sample() as u32 * 8
+}
+
+/// Floats are coded through their bit pattern, which keeps them exact.
+fn to_i32(samples: &SampleBuffer) -> Vec<i32> {
+    match samples {
+        SampleBuffer::I8(samples) => samples.iter().map(|&s| s as i32).collect(),
+        SampleBuffer::I16(samples) => samples.iter().map(|&s| s as i32).collect(),
+        SampleBuffer::I24(samples) | SampleBuffer::I32(samples) => samples.clone(),
+        SampleBuffer::F32(samples) => samples.iter().map(|s| s.to_bits() as i32).collect(),
+    }
+}
+
+fn from_i32(format: PcmFormat, values: Vec<i32>) -> SampleBuffer {
+    match format {
+        PcmFormat::I8 => SampleBuffer::I8(values.into_iter().map(|v| v as i8).collect()),
+        PcmFormat::I16 => SampleBuffer::I16(values.into_iter().map(|v| v as i16).collect()),
+        PcmFormat::I24 => SampleBuffer::I24(values),
+        PcmFormat::I32 => SampleBuffer::I32(values),
+        PcmFormat::F32 => SampleBuffer::F32(
+            values
+                .into_iter()
+                .map(|v| f32::from_bits(v as u32))
+                .collect(),
+        ),
+    }
+}
+
+fn encode_channel(samples: &[i64], bits: u32, writer: &mut BitWriter) {
+    let mut best = (Predictor::Verbatim, 2 + samples.len() as u64 * bits as u64);
+    if samples.windows(2).all(|pair| pair[0] == pair[1]) {
+        best = (Predictor::Constant, 2 + bits as u64);
+    }
+    let mut residual = Vec::with_capacity(samples.len());
+    for order in 0..=MAX_FIXED_ORDER.min(samples.len()) {
+        residual.clear();
+        residual.extend(
+            (order..samples.len()).map(|n| samples[n] - fixed_prediction(samples, n, order)),
+        );
+        let cost = 2 + 3 + order as u64 * bits as u64 + plan_rice(&residual).bits;
+        if cost < best.1 {
+            best = (Predictor::Fixed(order), cost);
+        }
+    }
+    for (coefficients, shift) in lpc_candidates(samples) {
+        let order = coefficients.len();
+        residual.clear();
+        residual
+            .extend((order..samples.len()).map(|n| {
+                samples[n].wrapping_sub(lpc_prediction(samples, n, &coefficients, shift))
+            }));
+        let header = 2 + 5 + 5 + order as u64 * (LPC_PRECISION + bits) as u64;
+        let cost = header + plan_rice(&residual).bits;
+        if cost < best.1 {
+            best = (
+                Predictor::Lpc {
+                    coefficients,
+                    shift,
+                },
+                cost,
+            );
+        }
+    }
+
+    match best.0 {
+        Predictor::Constant => {
+            writer.write(CONSTANT, 2);
+            writer.write_signed(samples.first().copied().unwrap_or(0), bits);
+        }
+        Predictor::Verbatim => {
+            writer.write(VERBATIM, 2);
+            samples.iter().for_each(|&s| writer.write_signed(s, bits));
+        }
+        Predictor::Fixed(order) => {
+            writer.write(FIXED, 2);
+            writer.write(order as u64, 3);
+            samples[..order]
+                .iter()
+                .for_each(|&s| writer.write_signed(s, bits));
+            residual.clear();
+            residual.extend(
+                (order..samples.len()).map(|n| samples[n] - fixed_prediction(samples, n, order)),
+            );
+            write_residual(&residual, writer);
+        }
+        Predictor::Lpc {
+            coefficients,
+            shift,
+        } => {
+            let order = coefficients.len();
+            writer.write(LPC, 2);
+            writer.write(order as u64 - 1, 5);
+            writer.write(shift as u64, 5);
+            coefficients
+                .iter()
+                .for_each(|&c| writer.write_signed(c, LPC_PRECISION));
+            samples[..order]
+                .iter()
+                .for_each(|&s| writer.write_signed(s, bits));
+            residual.clear();
+            residual.extend((order..samples.len()).map(|n| {
+                samples[n].wrapping_sub(lpc_prediction(samples, n, &coefficients, shift))
+            }));
+            write_residual(&residual, writer);
+        }
+    }
+}
+
+fn decode_channel(
+    reader: &mut BitReader,
+    bits: u32,
+    frames: usize,
+    samples: &mut Vec<i64>,
+) -> Result<(), CodecError> {
+    samples.clear();
+    match reader.read(2)? {
+        CONSTANT => {
+            let value = reader.read_signed(bits)?;
+            samples.resize(frames, value);
+        }
+        VERBATIM => {
+            for _ in 0..frames {
+                samples.push(reader.read_signed(bits)?);
+            }
+        }
+        FIXED => {
+            let order = reader.read(3)? as usize;
+            if order > MAX_FIXED_ORDER || order > frames {
+                return Err(CodecError::InvalidData);
+            }
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits)?);
+            }
+            read_residual(reader, frames - order, |residual| {
+                let n = samples.len();
+                let value = residual.wrapping_add(fixed_prediction(samples, n, order));
+                samples.push(value as i32 as i64);
+            })?;
+        }
+        _ => {
+            let order = reader.read(5)? as usize + 1;
+            let shift = reader.read(5)? as u32;
+            if order > frames {
+                return Err(CodecError::InvalidData);
+            }
+            let mut coefficients = Vec::with_capacity(order);
+            for _ in 0..order {
+                coefficients.push(reader.read_signed(LPC_PRECISION)?);
+            }
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits)?);
+            }
+            read_residual(reader, frames - order, |residual| {
+                let n = samples.len();
+                let prediction = lpc_prediction(samples, n, &coefficients, shift);
+                samples.push(residual.wrapping_add(prediction) as i32 as i64);
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Prediction of `samples[n]` by the fixed polynomial of the given order.
+fn fixed_prediction(samples: &[i64], n: usize, order: usize) -> i64 {
+    match order {
+        0 => 0,
+        1 => samples[n - 1],
+        2 => 2 * samples[n - 1] - samples[n - 2],
+        3 => 3 * samples[n - 1] - 3 * samples[n - 2] + samples[n - 3],
+        _ => 4 * samples[n - 1] - 6 * samples[n - 2] + 4 * samples[n - 3] - samples[n - 4],
+    }
+}
+
+fn lpc_prediction(samples: &[i64], n: usize, coefficients: &[i64], shift: u32) -> i64 {
+    let sum = coefficients.iter().enumerate().fold(0i64, |sum, (j, &c)| {
+        sum.wrapping_add(c.wrapping_mul(samples[n - 1 - j]))
+    });
+    sum >> shift
+}
+
+/// Quantized coefficients for every LPC order worth trying, from the autocorrelation of
+/// the Welch-windowed signal solved with Levinson-Durbin.
+fn lpc_candidates(samples: &[i64]) -> Vec<(Vec<i64>, u32)> {
+    let max_order = MAX_LPC_ORDER.min(samples.len().saturating_sub(1));
+    if max_order == 0 {
+        return Vec::new();
+    }
+    let len = samples.len() as f64;
+    let windowed: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(n, &s)| {
+            let x = (n as f64 - (len - 1.0) / 2.0) / ((len + 1.0) / 2.0);
+            s as f64 * (1.0 - x * x)
+        })
+        .collect();
+    let autocorrelation: Vec<f64> = (0..=max_order)
+        .map(|lag| {
+            windowed[lag..]
+                .iter()
+                .zip(&windowed)
+                .map(|(a, b)| a * b)
+                .sum()
+        })
+        .collect();
+    if autocorrelation[0] <= 0.0 {
+        return Vec::new();
+    }
+
+    let mut candidates = Vec::with_capacity(max_order);
+    let mut predictor = vec![0.0; max_order + 1];
+    let mut error = autocorrelation[0];
+    for order in 1..=max_order {
+        let mut reflection = autocorrelation[order];
+        for j in 1..order {
+            reflection -= predictor[j] * autocorrelation[order - j];
+        }
+        reflection /= error;
+        let previous = predictor.clone();
+        predictor[order] = reflection;
+        for j in 1..order {
+            predictor[j] = previous[j] - reflection * previous[order - j];
+        }
+        error *= 1.0 - reflection * reflection;
+        if let Some(quantized) = quantize(&predictor[1..=order]) {
+            candidates.push(quantized);
+        }
+        if error <= 0.0 || !error.is_finite() {
+            break;
+        }
+    }
+    candidates
+}
+
+/// Scales the coefficients to `LPC_PRECISION`-bit integers, returning them and the shift.
+fn quantize(coefficients: &[f64]) -> Option<(Vec<i64>, u32)> {
+    let max = coefficients.iter().fold(0.0f64, |max, c| max.max(c.abs()));
+    if max <= 0.0 || !max.is_finite() {
+        return None;
+    }
+    let shift =
+        (LPC_PRECISION as i32 - 1 - (max.log2().floor() as i32 + 1)).clamp(0, MAX_LPC_SHIFT);
+    let scale = (1u64 << shift) as f64;
+    let limit = (1i64 << (LPC_PRECISION - 1)) - 1;
+    let quantized = coefficients
+        .iter()
+        .map(|c| ((c * scale).round() as i64).clamp(-limit - 1, limit))
+        .collect();
+    Some((quantized, shift as u32))
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+/// Bits needed to Rice code `values` with parameter `k`.
+fn rice_bits(values: &[i64], k: u32) -> u64 {
+    values
+        .iter()
+        .fold(values.len() as u64 * (k as u64 + 1), |bits, &v| {
+            bits.saturating_add(zigzag(v) >> k)
+        })
+}
+
+/// Picks the partition size and Rice parameters that code the residual in the fewest bits.
+fn plan_rice(residual: &[i64]) -> RicePlan {
+    let mut best: Option<RicePlan> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let size = 1usize << partition_order;
+        let mut bits = 4u64;
+        let mut parameters = Vec::new();
+        for partition in residual.chunks(size) {
+            let mean = partition
+                .iter()
+                .fold(0u64, |sum, &v| sum.saturating_add(zigzag(v)))
+                / partition.len() as u64;
+            let estimate = (64 - mean.leading_zeros()).min(MAX_RICE_PARAMETER);
+            let (k, partition_bits) = (estimate.saturating_sub(1)
+                ..=(estimate + 1).min(MAX_RICE_PARAMETER))
+                .map(|k| (k, rice_bits(partition, k)))
+                .min_by_key(|&(_, bits)| bits)
+                .unwrap_or((0, 0));
+            parameters.push(k);
+            bits = bits.saturating_add(6 + partition_bits);
+        }
+        if best.as_ref().is_none_or(|plan| bits < plan.bits) {
+            best = Some(RicePlan {
+                partition_order,
+                parameters,
+                bits,
+            });
+        }
+        if size >= residual.len() {
+            break;
+        }
+    }
+    best.unwrap_or(RicePlan {
+        partition_order: 0,
+        parameters: Vec::new(),
+        bits: 4,
+    })
+}
+
+fn write_residual(residual: &[i64], writer: &mut BitWriter) {
+    let plan = plan_rice(residual);
+    writer.write(plan.partition_order as u64, 4);
+    let partitions = residual.chunks(1 << plan.partition_order);
+    for (partition, &k) in partitions.zip(&plan.parameters) {
+        writer.write(k as u64, 6);
+        for &value in partition {
+            let value = zigzag(value);
+            writer.write_unary(value >> k);
+            writer.write(value, k);
+        }
+    }
+}
+
+/// Reads `count` residual values, handing each to `push` as soon as it is decoded.
+fn read_residual(
+    reader: &mut BitReader,
+    count: usize,
+    mut push: impl FnMut(i64),
+) -> Result<(), CodecError> {
+    let partition_order = reader.read(4)? as u32;
+    if partition_order > MAX_PARTITION_ORDER {
+        return Err(CodecError::InvalidData);
+    }
+    let size = 1usize << partition_order;
+    let mut remaining = count;
+    while remaining > 0 {
+        let k = reader.read(6)? as u32;
+        for _ in 0..size.min(remaining) {
+            let quotient = reader.read_unary()?;
+            let value = quotient.wrapping_shl(k) | reader.read(k)?;
+            push(unzigzag(value));
+        }
+        remaining = remaining.saturating_sub(size);
+    }
+    Ok(())
+}
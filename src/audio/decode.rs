@@ -0,0 +1,611 @@
+use crate::audio::input::{AudioSource, AudioSourceError, check_format};
+use crate::audio::message::PcmFormat;
+use crate::audio::samples::SampleBuffer;
+use hound::{SampleFormat, WavSpec};
+use log::warn;
+use std::fs::File;
+use symphonia::core::audio::GenericAudioBufferRef;
+use symphonia::core::codecs::CodecParameters;
+use symphonia::core::codecs::audio::{AudioCodecParameters, AudioDecoder, AudioDecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::probe::Hint;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, TrackType};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTag};
+use symphonia::core::units::Timestamp;
+
+/// Compressed audio file (FLAC, MP3, Ogg Vorbis) decoded into PCM.
+///
+/// Lossless codecs keep their integer bit depth, so a FLAC file streams bit-exactly;
+/// lossy codecs are decoded to 32-bit float.
+pub struct DecodedAudioInput {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn AudioDecoder>,
+    track_id: u32,
+    spec: WavSpec,
+    format: PcmFormat,
+    frames: Option<u64>,
+    /// Decoded samples not read yet.
+    pending: SampleBuffer,
+    /// Frames to drop from the next decoded packets, after a seek landed early.
+    skip_frames: u64,
+    decoded_int: Vec<i32>,
+    decoded_float: Vec<f32>,
+}
+
+impl DecodedAudioInput {
+    /// Opens `path`, detecting the container and codec from its content.
+    pub fn open(path: &str) -> Result<Self, AudioSourceError> {
+        let file = File::open(path).map_err(AudioSourceError::IoError)?;
+        let stream = MediaSourceStream::new(Box::new(file), Default::default());
+        let reader = symphonia::default::get_probe()
+            .probe(
+                &Hint::new(),
+                stream,
+                FormatOptions::default(),
+                MetadataOptions::default(),
+            )
+            .map_err(AudioSourceError::DecoderError)?;
+        let track = reader
+            .default_track(TrackType::Audio)
+            .ok_or(AudioSourceError::UnsupportedFormat)?;
+        let Some(CodecParameters::Audio(params)) = &track.codec_params else {
+            return Err(AudioSourceError::UnsupportedFormat);
+        };
+        let decoder = symphonia::default::get_codecs()
+            .make_audio_decoder(params, &AudioDecoderOptions::default())
+            .map_err(AudioSourceError::DecoderError)?;
+
+        let channels = params
+            .channels
+            .as_ref()
+            .map(|channels| channels.count())
+            .filter(|&count| count > 0)
+            .ok_or(AudioSourceError::UnsupportedFormat)?;
+        let sample_rate = params
+            .sample_rate
+            .ok_or(AudioSourceError::UnsupportedFormat)?;
+        let format = output_format(params);
+        let spec = WavSpec {
+            channels: channels as u16,
+            sample_rate,
+            bits_per_sample: format.bytes_per_sample() as u16 * 8,
+            sample_format: match format {
+                PcmFormat::F32 => SampleFormat::Float,
+                _ => SampleFormat::Int,
+            },
+        };
+        Ok(DecodedAudioInput {
+            track_id: track.id,
+            frames: track.num_frames,
+            reader,
+            decoder,
+            spec,
+            format,
+            pending: SampleBuffer::new(format),
+            skip_frames: 0,
+            decoded_int: Vec::new(),
+            decoded_float: Vec::new(),
+        })
+    }
+
+    /// Decodes packets until some samples are pending, returns false at the end of the file.
+    fn decode_next(&mut self) -> Result<bool, AudioSourceError> {
+        while self.pending.is_empty() {
+            let packet = match self.reader.next_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok(false),
+                Err(e) => return Err(AudioSourceError::DecoderError(e)),
+            };
+            if packet.track_id != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(Error::DecodeError(e)) => {
+                    warn!("Skipping undecodable packet: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(AudioSourceError::DecoderError(e)),
+            };
+            let channels = self.spec.channels as usize;
+            let skip = (self.skip_frames as usize).min(decoded.frames());
+            self.skip_frames -= skip as u64;
+            append_decoded(
+                &decoded,
+                skip * channels,
+                &mut self.pending,
+                &mut self.decoded_int,
+                &mut self.decoded_float,
+            );
+        }
+        Ok(true)
+    }
+}
+
+/// Lossless codecs report their bit depth, lossy ones don't and are decoded to float.
+fn output_format(params: &AudioCodecParameters) -> PcmFormat {
+    match params.bits_per_sample {
+        Some(1..=8) => PcmFormat::I8,
+        Some(9..=16) => PcmFormat::I16,
+        Some(17..=24) => PcmFormat::I24,
+        Some(_) => PcmFormat::I32,
+        None => PcmFormat::F32,
+    }
+}
+
+/// Converts a decoded packet to `pending`'s format and appends it, minus the first
+/// `skip` samples.
+fn append_decoded(
+    decoded: &GenericAudioBufferRef<'_>,
+    skip: usize,
+    pending: &mut SampleBuffer,
+    decoded_int: &mut Vec<i32>,
+    decoded_float: &mut Vec<f32>,
+) {
+    if let SampleBuffer::F32(samples) = pending {
+        decoded.copy_to_vec_interleaved(decoded_float);
+        samples.extend_from_slice(&decoded_float[skip..]);
+        return;
+    }
+    // Integer samples are converted at full 32-bit scale, then shifted down
+    decoded.copy_to_vec_interleaved(decoded_int);
+    let decoded_int = &decoded_int[skip..];
+    match pending {
+        SampleBuffer::I8(samples) => samples.extend(decoded_int.iter().map(|&s| (s >> 24) as i8)),
+        SampleBuffer::I16(samples) => samples.extend(decoded_int.iter().map(|&s| (s >> 16) as i16)),
+        SampleBuffer::I24(samples) => samples.extend(decoded_int.iter().map(|&s| s >> 8)),
+        SampleBuffer::I32(samples) => samples.extend_from_slice(decoded_int),
+        SampleBuffer::F32(_) => unreachable!("handled above"),
+    }
+}
+
+impl AudioSource for DecodedAudioInput {
+    fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    fn format(&self) -> Result<PcmFormat, AudioSourceError> {
+        Ok(self.format)
+    }
+
+    fn read_samples(
+        &mut self,
+        buf: &mut SampleBuffer,
+        max: usize,
+    ) -> Result<usize, AudioSourceError> {
+        check_format(self, buf)?;
+        let mut read = 0;
+        while read < max && self.decode_next()? {
+            read += self.pending.move_front(buf, max - read);
+        }
+        Ok(read)
+    }
+
+    fn duration(&self) -> Option<u64> {
+        self.frames
+    }
+
+    /// Files always end, even when their container doesn't tell their length.
+    fn get_all_samples(&mut self, buf: &mut SampleBuffer) -> Result<(), AudioSourceError> {
+        while self.read_samples(buf, usize::MAX)? > 0 {}
+        Ok(())
+    }
+
+    /// Made of the artist and title tags of the latest metadata, chained Ogg streams
+    /// can change them between tracks.
+    fn track_title(&mut self) -> Option<String> {
+        let track_id = self.track_id as u64;
+        let mut metadata = self.reader.metadata();
+        let revision = metadata.skip_to_latest()?;
+        let track_tags = revision
+            .per_track
+            .iter()
+            .filter(|track| track.track_id == track_id)
+            .flat_map(|track| &track.metadata.tags);
+        let (mut artist, mut title) = (None, None);
+        for tag in revision.media.tags.iter().chain(track_tags) {
+            match &tag.std {
+                Some(StandardTag::Artist(value)) => artist = Some(value.as_str()),
+                Some(StandardTag::TrackTitle(value)) => title = Some(value.as_str()),
+                _ => {}
+            }
+        }
+        match (artist, title) {
+            (Some(artist), Some(title)) => Some(format!("{artist} - {title}")),
+            (None, Some(title)) => Some(title.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Seeks to `frame`, assuming the track counts time in frames as audio formats do.
+    fn seek(&mut self, frame: u64) -> Result<(), AudioSourceError> {
+        let seeked = self
+            .reader
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Timestamp {
+                    ts: Timestamp::new(frame as i64),
+                    track_id: self.track_id,
+                },
+            )
+            .map_err(AudioSourceError::DecoderError)?;
+        self.decoder.reset();
+        self.pending.clear();
+        self.skip_frames = (seeked.required_ts.get() - seeked.actual_ts.get()).max(0) as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecodedAudioInput;
+    use crate::audio::input::{AudioSource, open_audio_file};
+    use crate::audio::message::PcmFormat;
+    use crate::audio::samples::SampleBuffer;
+
+    const BLOCK_SIZE: usize = 1_024;
+
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: u32,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u64, bits: u32) {
+            for bit in (0..bits).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let last = self.bytes.last_mut().unwrap();
+                *last |= (((value >> bit) & 1) as u8) << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        /// Like `write`, packing from the least significant bit as Vorbis does.
+        fn write_lsb(&mut self, value: u64, bits: u32) {
+            for bit in 0..bits {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let last = self.bytes.last_mut().unwrap();
+                *last |= (((value >> bit) & 1) as u8) << (self.bits % 8);
+                self.bits += 1;
+            }
+        }
+    }
+
+    fn crc(bytes: &[u8], polynomial: u16, width: u32) -> u16 {
+        let top = 1 << (width - 1);
+        let mask = ((1u32 << width) - 1) as u16;
+        let mut crc = 0u16;
+        for &byte in bytes {
+            crc ^= (byte as u16) << (width - 8);
+            for _ in 0..8 {
+                crc = if crc & top != 0 {
+                    (crc << 1) ^ polynomial
+                } else {
+                    crc << 1
+                };
+            }
+            crc &= mask;
+        }
+        crc
+    }
+
+    fn ogg_crc(bytes: &[u8]) -> u32 {
+        let mut crc = 0u32;
+        for &byte in bytes {
+            crc ^= (byte as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04C1_1DB7
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        crc
+    }
+
+    /// Appends an Ogg page holding `packets`, none of them longer than a page allows.
+    fn write_ogg_page(
+        out: &mut Vec<u8>,
+        flags: u8,
+        granule: u64,
+        sequence: u32,
+        packets: &[Vec<u8>],
+    ) {
+        let mut page = Vec::new();
+        page.extend_from_slice(b"OggS");
+        page.push(0);
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes());
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&0u32.to_le_bytes());
+        let lacing: Vec<u8> = packets
+            .iter()
+            .flat_map(|packet| {
+                std::iter::repeat_n(255, packet.len() / 255).chain([(packet.len() % 255) as u8])
+            })
+            .collect();
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        packets
+            .iter()
+            .for_each(|packet| page.extend_from_slice(packet));
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        out.extend_from_slice(&page);
+    }
+
+    /// Minimal Ogg Vorbis encoder: a setup with a single floor marked unused in every
+    /// audio packet, so `packets` short blocks of silence, and `comments` such as
+    /// "TITLE=Song".
+    fn write_vorbis(path: &str, sample_rate: u32, channels: u8, packets: usize, comments: &[&str]) {
+        let mut identification = vec![1];
+        identification.extend_from_slice(b"vorbis");
+        identification.extend_from_slice(&0u32.to_le_bytes());
+        identification.push(channels);
+        identification.extend_from_slice(&sample_rate.to_le_bytes());
+        identification.extend_from_slice(&[0; 12]);
+        // Blocks of 256 and 2048 samples
+        identification.push(8 | 11 << 4);
+        identification.push(1);
+
+        let mut comment = vec![3];
+        comment.extend_from_slice(b"vorbis");
+        comment.extend_from_slice(&0u32.to_le_bytes()); // Empty vendor string
+        comment.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for tag in comments {
+            comment.extend_from_slice(&(tag.len() as u32).to_le_bytes());
+            comment.extend_from_slice(tag.as_bytes());
+        }
+        comment.push(1);
+
+        let mut setup = BitWriter::default();
+        setup.bytes.push(5);
+        setup.bytes.extend_from_slice(b"vorbis");
+        setup.bits = 7 * 8;
+        // One codebook of two 1-bit entries, without lookup
+        setup.write_lsb(0, 8);
+        setup.write_lsb(0x56_4342, 24);
+        setup.write_lsb(1, 16);
+        setup.write_lsb(2, 24);
+        setup.write_lsb(0, 2);
+        setup.write_lsb(0, 5);
+        setup.write_lsb(0, 5);
+        setup.write_lsb(0, 4);
+        // One time domain transform, which must be 0
+        setup.write_lsb(0, 6);
+        setup.write_lsb(0, 16);
+        // One floor of type 1 without partitions, over 256 values
+        setup.write_lsb(0, 6);
+        setup.write_lsb(1, 16);
+        setup.write_lsb(0, 5);
+        setup.write_lsb(0, 2);
+        setup.write_lsb(8, 4);
+        // One empty residue of type 0 with a single classification
+        setup.write_lsb(0, 6);
+        setup.write_lsb(0, 16);
+        setup.write_lsb(0, 24);
+        setup.write_lsb(0, 24);
+        setup.write_lsb(0, 24);
+        setup.write_lsb(0, 6);
+        setup.write_lsb(0, 8);
+        setup.write_lsb(0, 3);
+        setup.write_lsb(0, 1);
+        // One mapping of every channel onto the floor and the residue, without coupling
+        setup.write_lsb(0, 6);
+        setup.write_lsb(0, 16);
+        setup.write_lsb(0, 1);
+        setup.write_lsb(0, 1);
+        setup.write_lsb(0, 2);
+        setup.write_lsb(0, 8);
+        setup.write_lsb(0, 8);
+        setup.write_lsb(0, 8);
+        // One mode of short blocks
+        setup.write_lsb(0, 6);
+        setup.write_lsb(0, 1);
+        setup.write_lsb(0, 16);
+        setup.write_lsb(0, 16);
+        setup.write_lsb(0, 8);
+        setup.write_lsb(1, 1);
+
+        let mut out = Vec::new();
+        write_ogg_page(&mut out, 0x02, 0, 0, &[identification]);
+        write_ogg_page(&mut out, 0, 0, 1, &[comment, setup.bytes]);
+        // An audio packet flag and an unused floor per channel, all zero bits. Every
+        // packet after the first completes 128 samples.
+        let audio = vec![vec![0u8; (1 + channels as usize).div_ceil(8)]; packets];
+        let granule = (packets as u64 - 1) * 128;
+        write_ogg_page(&mut out, 0x04, granule, 2, &audio);
+        std::fs::write(path, out).expect("write ogg");
+    }
+
+    /// Minimal MP3 encoder: `frames` frames of mono silence at 128 kb/s and 44.1 kHz,
+    /// each with zeroed side information and no main data.
+    fn write_mp3(path: &str, frames: usize) {
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0xC0];
+        frame.resize(144 * 128_000 / 44_100, 0);
+        std::fs::write(path, frame.repeat(frames)).expect("write mp3");
+    }
+
+    /// Minimal FLAC encoder storing every channel as a verbatim subframe, and
+    /// `comments` such as "TITLE=Song" in a Vorbis comment block.
+    fn write_flac(
+        path: &str,
+        sample_rate: u32,
+        channels: usize,
+        bits: u32,
+        samples: &[i32],
+        comments: &[&str],
+    ) {
+        let frames = samples.len() / channels;
+        let mut out = BitWriter::default();
+        out.bytes.extend_from_slice(b"fLaC");
+        out.bits = 32;
+        // STREAMINFO, 34 bytes, last metadata block unless there are comments
+        out.write(comments.is_empty() as u64, 1);
+        out.write(0, 7);
+        out.write(34, 24);
+        out.write(BLOCK_SIZE as u64, 16);
+        out.write(BLOCK_SIZE as u64, 16);
+        out.write(0, 24);
+        out.write(0, 24);
+        out.write(sample_rate as u64, 20);
+        out.write(channels as u64 - 1, 3);
+        out.write(bits as u64 - 1, 5);
+        out.write(frames as u64, 36);
+        out.write(0, 64);
+        out.write(0, 64);
+        if !comments.is_empty() {
+            let mut block = Vec::new();
+            block.extend_from_slice(&0u32.to_le_bytes()); // Empty vendor string
+            block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+            for comment in comments {
+                block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+                block.extend_from_slice(comment.as_bytes());
+            }
+            // Last metadata block, VORBIS_COMMENT
+            out.write(1, 1);
+            out.write(4, 7);
+            out.write(block.len() as u64, 24);
+            out.bytes.extend_from_slice(&block);
+            out.bits += block.len() as u32 * 8;
+        }
+
+        let sample_size_code = match bits {
+            8 => 0b001,
+            16 => 0b100,
+            _ => 0b110,
+        };
+        for (number, block) in samples.chunks(BLOCK_SIZE * channels).enumerate() {
+            let mut frame = BitWriter::default();
+            frame.write(0b11_1111_1111_1110, 14);
+            frame.write(0, 2);
+            frame.write(0b0111, 4);
+            frame.write(0, 4);
+            frame.write(channels as u64 - 1, 4);
+            frame.write(sample_size_code, 3);
+            frame.write(0, 1);
+            frame.write(number as u64, 8);
+            frame.write((block.len() / channels) as u64 - 1, 16);
+            let header_crc = crc(&frame.bytes, 0x07, 8);
+            frame.write(header_crc as u64, 8);
+            for channel in 0..channels {
+                frame.write(0b0000_0010, 8);
+                for sample in block.iter().skip(channel).step_by(channels) {
+                    frame.write(*sample as u64 & ((1 << bits) - 1), bits);
+                }
+            }
+            let frame_crc = crc(&frame.bytes, 0x8005, 16);
+            frame.write(frame_crc as u64, 16);
+            out.bytes.extend_from_slice(&frame.bytes);
+        }
+        std::fs::write(path, out.bytes).expect("write flac");
+    }
+
+    #[test]
+    fn flac_decodes_bit_exactly_and_seeks() {
+        let path = std::env::temp_dir().join("sonos-decode.flac");
+        let path = path.to_str().unwrap();
+        let frames = BLOCK_SIZE * 2 + 100;
+        let cases = [
+            (16, PcmFormat::I16, 2, -32_768..32_767),
+            (24, PcmFormat::I24, 1, -8_388_608..8_388_607),
+        ];
+        for (bits, format, channels, range) in cases {
+            let samples: Vec<i32> = (0..frames * channels)
+                .map(|n| range.start + (n as i32 * 7_919).rem_euclid(range.end - range.start))
+                .collect();
+            write_flac(path, 44_100, channels, bits, &samples, &[]);
+
+            // Detected from content, not from the extension
+            let mut input = open_audio_file(path).expect("open flac");
+            assert_eq!(input.format().expect("format"), format);
+            assert_eq!(input.spec().channels as usize, channels);
+            assert_eq!(input.spec().sample_rate, 44_100);
+            assert_eq!(input.duration(), Some(frames as u64));
+            let mut decoded = SampleBuffer::new(format);
+            while input.read_samples(&mut decoded, 1_000).expect("decode") > 0 {}
+            let mut expected = match format {
+                PcmFormat::I16 => SampleBuffer::I16(samples.iter().map(|&s| s as i16).collect()),
+                _ => SampleBuffer::I24(samples.clone()),
+            };
+            assert_eq!(decoded, expected);
+
+            let mut input = DecodedAudioInput::open(path).expect("open flac");
+            let seek_frame = BLOCK_SIZE + 37;
+            input.seek(seek_frame as u64).expect("seek");
+            let mut tail = SampleBuffer::new(format);
+            input.get_all_samples(&mut tail).expect("decode after seek");
+            let mut expected_tail = SampleBuffer::new(format);
+            // Drop everything before the seek point
+            expected.move_front(&mut SampleBuffer::new(format), seek_frame * channels);
+            expected.move_front(&mut expected_tail, usize::MAX);
+            assert_eq!(tail, expected_tail);
+        }
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn vorbis_is_probed_and_decoded() {
+        let path = std::env::temp_dir().join("sonos-decode.ogg");
+        let path = path.to_str().unwrap();
+        write_vorbis(path, 22_050, 2, 41, &["ARTIST=Band", "TITLE=Song"]);
+        let mut input = open_audio_file(path).expect("open vorbis");
+        assert_eq!(input.format().expect("format"), PcmFormat::F32);
+        assert_eq!(input.spec().channels, 2);
+        assert_eq!(input.spec().sample_rate, 22_050);
+        assert_eq!(input.track_title().as_deref(), Some("Band - Song"));
+        let mut decoded = SampleBuffer::new(PcmFormat::F32);
+        input.get_all_samples(&mut decoded).expect("decode vorbis");
+        let SampleBuffer::F32(decoded) = decoded else {
+            panic!("vorbis should decode to floats");
+        };
+        // Every packet but the first completes 128 frames
+        assert_eq!(input.duration(), Some(40 * 128));
+        assert_eq!(decoded.len(), 40 * 128 * 2);
+        assert!(decoded.iter().all(|&sample| sample == 0.0));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn mp3_is_probed_and_decoded() {
+        let path = std::env::temp_dir().join("sonos-decode.mp3");
+        let path = path.to_str().unwrap();
+        write_mp3(path, 20);
+        let mut input = open_audio_file(path).expect("open mp3");
+        assert_eq!(input.format().expect("format"), PcmFormat::F32);
+        assert_eq!(input.spec().channels, 1);
+        assert_eq!(input.spec().sample_rate, 44_100);
+        let mut decoded = SampleBuffer::new(PcmFormat::F32);
+        input.get_all_samples(&mut decoded).expect("decode mp3");
+        let SampleBuffer::F32(decoded) = decoded else {
+            panic!("mp3 should decode to floats");
+        };
+        assert_eq!(input.duration(), Some(20 * 1_152));
+        assert_eq!(decoded.len(), 20 * 1_152);
+        assert!(decoded.iter().all(|&sample| sample == 0.0));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn flac_tags_give_the_track_title() {
+        let path = std::env::temp_dir().join("sonos-decode-tags.flac");
+        let path = path.to_str().unwrap();
+        let samples = vec![0; 200];
+        write_flac(path, 8_000, 1, 16, &samples, &["TITLE=Song", "ARTIST=Band"]);
+        let mut input = DecodedAudioInput::open(path).expect("open flac");
+        assert_eq!(input.track_title().as_deref(), Some("Band - Song"));
+
+        write_flac(path, 8_000, 1, 16, &samples, &[]);
+        let mut input = DecodedAudioInput::open(path).expect("open flac");
+        assert_eq!(input.track_title(), None);
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -1,5 +1,122 @@
-use hound::{Error, WavSpec};
+use crate::audio::decode::DecodedAudioInput;
+use crate::audio::message::PcmFormat;
+use crate::audio::samples::SampleBuffer;
+use hound::{Error, SampleFormat, WavSpec};
+use std::f64::consts::PI;
+use std::fmt;
 use std::fs::File;
+use std::io;
+use std::io::Read;
+
+#[derive(Debug)]
+pub enum AudioSourceError {
+    HoundError(hound::Error),
+    IoError(io::Error),
+    DecoderError(symphonia::core::errors::Error),
+    /// Samples were requested in another format than the source produces.
+    FormatMismatch {
+        expected: PcmFormat,
+        requested: PcmFormat,
+    },
+    UnsupportedFormat,
+    SeekUnsupported,
+    /// The whole stream was requested from a source that may never end.
+    UnknownLength,
+}
+impl fmt::Display for AudioSourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioSourceError::HoundError(e) => write!(f, "Hound error: {}", e),
+            AudioSourceError::IoError(e) => write!(f, "IO error: {}", e),
+            AudioSourceError::DecoderError(e) => write!(f, "Decoder error: {}", e),
+            AudioSourceError::FormatMismatch {
+                expected,
+                requested,
+            } => write!(
+                f,
+                "Source produces {:?} samples, {:?} were requested",
+                expected, requested
+            ),
+            AudioSourceError::UnsupportedFormat => write!(f, "Unsupported sample format"),
+            AudioSourceError::SeekUnsupported => write!(f, "Source can't seek"),
+            AudioSourceError::UnknownLength => write!(f, "Source has no known length"),
+        }
+    }
+}
+
+/// Anything the server can stream from: files, decoders, generated signals or pipes.
+pub trait AudioSource {
+    fn spec(&self) -> WavSpec;
+
+    /// Format of the samples returned by [`AudioSource::read_samples`].
+    fn format(&self) -> Result<PcmFormat, AudioSourceError> {
+        PcmFormat::from_spec(&self.spec()).ok_or(AudioSourceError::UnsupportedFormat)
+    }
+
+    /// Appends up to `max` samples to `buf`, which must match the source format.
+    /// Returns the number of samples read, 0 once the end of the stream is reached.
+    fn read_samples(
+        &mut self,
+        buf: &mut SampleBuffer,
+        max: usize,
+    ) -> Result<usize, AudioSourceError>;
+
+    /// Length of the stream in frames, if known.
+    fn duration(&self) -> Option<u64> {
+        None
+    }
+
+    /// Title of the track being read, e.g. "Artist - Title" from the tags of the file.
+    /// It may change along the stream.
+    fn track_title(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Moves the next read to the given frame.
+    fn seek(&mut self, _frame: u64) -> Result<(), AudioSourceError> {
+        Err(AudioSourceError::SeekUnsupported)
+    }
+
+    /// Appends the rest of the stream to `buf`. Fails for sources of unknown length, such
+    /// as an endless `SineWave`, rather than reading forever.
+    fn get_all_samples(&mut self, buf: &mut SampleBuffer) -> Result<(), AudioSourceError> {
+        if self.duration().is_none() {
+            return Err(AudioSourceError::UnknownLength);
+        }
+        while self.read_samples(buf, usize::MAX)? > 0 {}
+        Ok(())
+    }
+}
+
+/// Checks `buf` can hold samples of `source`, as `read_samples` implementations require.
+pub(crate) fn check_format(
+    source: &dyn AudioSource,
+    buf: &SampleBuffer,
+) -> Result<(), AudioSourceError> {
+    let expected = source.format()?;
+    if buf.format() != expected {
+        return Err(AudioSourceError::FormatMismatch {
+            expected,
+            requested: buf.format(),
+        });
+    }
+    Ok(())
+}
+
+/// Opens an audio file for streaming, picking the reader from the file content rather
+/// than its extension: WAV files are read as is, anything else goes through the decoder.
+pub fn open_audio_file(path: &str) -> Result<Box<dyn AudioSource>, AudioSourceError> {
+    let mut header = Vec::with_capacity(12);
+    File::open(path)
+        .and_then(|file| file.take(12).read_to_end(&mut header))
+        .map_err(AudioSourceError::IoError)?;
+    if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WAVE") {
+        let input = WavAudioInput::init(path).map_err(AudioSourceError::HoundError)?;
+        Ok(Box::new(input))
+    } else {
+        Ok(Box::new(DecodedAudioInput::open(path)?))
+    }
+}
 
 pub struct WavAudioInput {
     reader: hound::WavReader<std::io::BufReader<File>>,
@@ -10,17 +127,187 @@ impl WavAudioInput {
         let reader = hound::WavReader::open(filepath)?;
         Ok(Self { reader })
     }
-    pub fn iter_samples(&mut self) -> impl Iterator<Item = Result<i16, Error>> + '_ {
-        self.reader.samples::<i16>()
-    }
     pub fn get_spec(&self) -> WavSpec {
         self.reader.spec()
     }
+    pub fn get_format(&self) -> Result<PcmFormat, Error> {
+        PcmFormat::from_spec(&self.reader.spec()).ok_or(Error::Unsupported)
+    }
+}
+
+impl AudioSource for WavAudioInput {
+    fn spec(&self) -> WavSpec {
+        self.get_spec()
+    }
+
+    fn read_samples(
+        &mut self,
+        buf: &mut SampleBuffer,
+        max: usize,
+    ) -> Result<usize, AudioSourceError> {
+        check_format(self, buf)?;
+        let before = buf.len();
+        match buf {
+            SampleBuffer::I8(samples) => {
+                for sample in self.reader.samples::<i8>().take(max) {
+                    samples.push(sample.map_err(AudioSourceError::HoundError)?);
+                }
+            }
+            SampleBuffer::I16(samples) => {
+                for sample in self.reader.samples::<i16>().take(max) {
+                    samples.push(sample.map_err(AudioSourceError::HoundError)?);
+                }
+            }
+            SampleBuffer::I24(samples) | SampleBuffer::I32(samples) => {
+                for sample in self.reader.samples::<i32>().take(max) {
+                    samples.push(sample.map_err(AudioSourceError::HoundError)?);
+                }
+            }
+            SampleBuffer::F32(samples) => {
+                for sample in self.reader.samples::<f32>().take(max) {
+                    samples.push(sample.map_err(AudioSourceError::HoundError)?);
+                }
+            }
+        }
+        Ok(buf.len() - before)
+    }
+
+    fn duration(&self) -> Option<u64> {
+        Some(self.reader.duration() as u64)
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), AudioSourceError> {
+        let frame = u32::try_from(frame).unwrap_or(u32::MAX);
+        self.reader.seek(frame).map_err(AudioSourceError::IoError)
+    }
+}
+
+/// Generated sine tone in 32-bit float, the same on every channel.
+pub struct SineWave {
+    frequency: f64,
+    amplitude: f32,
+    spec: WavSpec,
+    /// Length in frames, `None` for an endless tone.
+    frames: Option<u64>,
+    next_frame: u64,
+}
+
+impl SineWave {
+    pub fn new(frequency: f64, sample_rate: u32, channels: u16) -> Self {
+        SineWave {
+            frequency,
+            amplitude: 0.5,
+            spec: WavSpec {
+                channels: channels.max(1),
+                sample_rate,
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+            },
+            frames: None,
+            next_frame: 0,
+        }
+    }
+    pub fn with_amplitude(mut self, amplitude: f32) -> Self {
+        self.amplitude = amplitude;
+        self
+    }
+    pub fn with_frames(mut self, frames: u64) -> Self {
+        self.frames = Some(frames);
+        self
+    }
+}
+
+impl AudioSource for SineWave {
+    fn spec(&self) -> WavSpec {
+        self.spec
+    }
 
-    pub fn get_all_samples(&mut self, buf: &mut Vec<i16>) -> Result<(), Error> {
-        for sample in self.reader.samples::<i16>() {
-            buf.push(sample?);
+    fn read_samples(
+        &mut self,
+        buf: &mut SampleBuffer,
+        max: usize,
+    ) -> Result<usize, AudioSourceError> {
+        check_format(self, buf)?;
+        let SampleBuffer::F32(samples) = buf else {
+            unreachable!("format checked above");
+        };
+        let channels = self.spec.channels as usize;
+        let mut frames = (max / channels) as u64;
+        if let Some(total) = self.frames {
+            frames = frames.min(total.saturating_sub(self.next_frame));
+        }
+        for frame in self.next_frame..self.next_frame + frames {
+            let phase = 2.0 * PI * self.frequency * frame as f64 / self.spec.sample_rate as f64;
+            let sample = phase.sin() as f32 * self.amplitude;
+            samples.extend(std::iter::repeat_n(sample, channels));
         }
+        self.next_frame += frames;
+        Ok(frames as usize * channels)
+    }
+
+    fn duration(&self) -> Option<u64> {
+        self.frames
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), AudioSourceError> {
+        self.next_frame = frame;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AudioSource, AudioSourceError, SineWave, WavAudioInput};
+    use crate::audio::message::PcmFormat;
+    use crate::audio::samples::SampleBuffer;
+
+    fn read_all(source: &mut dyn AudioSource) -> SampleBuffer {
+        let mut buf = SampleBuffer::new(source.format().expect("supported format"));
+        source.get_all_samples(&mut buf).expect("read source");
+        buf
+    }
+
+    #[test]
+    fn sources_seek_and_report_duration() {
+        let mut tone = SineWave::new(440.0, 8_000, 2).with_frames(800);
+        assert_eq!(tone.duration(), Some(800));
+        let samples = read_all(&mut tone);
+        assert_eq!(samples.len(), 1_600);
+        let SampleBuffer::F32(values) = &samples else {
+            panic!("tone should be float");
+        };
+        assert!(values.chunks(2).all(|frame| frame[0] == frame[1]));
+        assert!(values.iter().any(|&s| s > 0.49));
+
+        tone.seek(700).expect("seek tone");
+        assert_eq!(read_all(&mut tone).len(), 200);
+        assert!(matches!(
+            SineWave::new(440.0, 8_000, 2).get_all_samples(&mut SampleBuffer::new(PcmFormat::F32)),
+            Err(AudioSourceError::UnknownLength)
+        ));
+        assert!(matches!(
+            tone.read_samples(&mut SampleBuffer::new(PcmFormat::I16), 10),
+            Err(AudioSourceError::FormatMismatch { .. })
+        ));
+
+        let path = std::env::temp_dir().join("sonos-source-seek.wav");
+        let path = path.to_str().expect("utf-8 temp path");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).expect("create wav");
+        for sample in 0..100i16 {
+            writer.write_sample(sample).expect("write sample");
+        }
+        writer.finalize().expect("finalize wav");
+
+        let mut wav = WavAudioInput::init(path).expect("open wav");
+        assert_eq!(wav.duration(), Some(100));
+        wav.seek(90).expect("seek wav");
+        assert_eq!(read_all(&mut wav), SampleBuffer::I16((90..100).collect()));
+        std::fs::remove_file(path).ok();
+    }
+}
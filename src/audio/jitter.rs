@@ -0,0 +1,228 @@
+use crate::audio::message::SamplesFrame;
+use crate::audio::samples::SampleBuffer;
+use std::collections::BTreeMap;
+
+/// Most frames concealed in a row, about a second of audio with the frames of the server.
+/// Longer gaps are skipped rather than filled with silence.
+const MAX_CONCEALED_FRAMES: u64 = 100;
+
+/// How the network treated the frames that went through a `JitterBuffer`.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct JitterStats {
+    /// Frames received, duplicates and late ones included.
+    pub received: u64,
+    /// Frames that arrived after a frame with a higher sequence number.
+    pub reordered: u64,
+    pub duplicates: u64,
+    /// Frames that arrived once their slot was already played or concealed.
+    pub late: u64,
+    /// Frames never received in time.
+    pub lost: u64,
+    /// Samples of silence played in place of lost frames.
+    pub concealed_samples: u64,
+    /// Gaps too long to conceal, after which the stream went on right away.
+    pub resyncs: u64,
+}
+
+/// Puts frames received over an unreliable transport back in order, and replaces each
+/// lost frame with silence so the output keeps its timing.
+///
+/// Up to `depth` frames are held back while waiting for a missing one. Once more arrive
+/// the missing frame is given up on. Gaps of more than `MAX_CONCEALED_FRAMES` aren't
+/// concealed, whether packets went missing for that long or a frame claims to be that
+/// far ahead.
+#[derive(Debug)]
+pub struct JitterBuffer {
+    depth: usize,
+    pending: BTreeMap<u64, SamplesFrame>,
+    /// Sequence number and position of the next frame to release, once one was.
+    next: Option<(u64, u64)>,
+    highest_sequence: Option<u64>,
+    /// Samples in the longest frame received, the length of every frame but the last.
+    frame_samples: u64,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(depth: usize) -> Self {
+        JitterBuffer {
+            depth,
+            pending: BTreeMap::new(),
+            next: None,
+            highest_sequence: None,
+            frame_samples: 1,
+            stats: JitterStats::default(),
+        }
+    }
+
+    /// Adds a received frame, returns the frames ready to be played in order.
+    pub fn push(&mut self, frame: SamplesFrame) -> Vec<SamplesFrame> {
+        self.stats.received += 1;
+        if let Some((next_sequence, _)) = self.next
+            && frame.sequence < next_sequence
+        {
+            self.stats.late += 1;
+            return Vec::new();
+        }
+        if self.pending.contains_key(&frame.sequence) {
+            self.stats.duplicates += 1;
+            return Vec::new();
+        }
+        match self.highest_sequence {
+            Some(highest) if frame.sequence < highest => self.stats.reordered += 1,
+            _ => self.highest_sequence = Some(frame.sequence),
+        }
+        self.frame_samples = self.frame_samples.max(frame.samples.len() as u64);
+        self.pending.insert(frame.sequence, frame);
+
+        let mut ready = Vec::new();
+        while self.pending.len() > self.depth {
+            self.release_first(&mut ready);
+        }
+        while let (Some((next_sequence, _)), Some((&sequence, _))) =
+            (self.next, self.pending.first_key_value())
+            && sequence == next_sequence
+        {
+            self.release_first(&mut ready);
+        }
+        ready
+    }
+
+    /// Releases every frame still held, at the end of the stream.
+    pub fn drain(&mut self) -> Vec<SamplesFrame> {
+        let mut ready = Vec::new();
+        while !self.pending.is_empty() {
+            self.release_first(&mut ready);
+        }
+        ready
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    /// Releases the oldest frame held, preceded by silence for the frames missing
+    /// before it.
+    fn release_first(&mut self, ready: &mut Vec<SamplesFrame>) {
+        let Some((sequence, frame)) = self.pending.pop_first() else {
+            return;
+        };
+        if let Some((next_sequence, next_position)) = self.next
+            && sequence > next_sequence
+        {
+            let missing_samples = frame.position.saturating_sub(next_position);
+            // Frames all have the same length but the last one, which can't be missing here
+            let length = self.frame_samples;
+            if sequence - next_sequence > MAX_CONCEALED_FRAMES
+                || missing_samples > MAX_CONCEALED_FRAMES * length
+            {
+                self.stats.resyncs += 1;
+                self.next = Some((
+                    sequence.saturating_add(1),
+                    frame.position.saturating_add(frame.samples.len() as u64),
+                ));
+                ready.push(frame);
+                return;
+            }
+            self.stats.lost += sequence - next_sequence;
+            self.stats.concealed_samples += missing_samples;
+            // One frame of silence per missing frame, so the stream looks unbroken
+            let mut position = next_position;
+            for missing in next_sequence..sequence {
+                let samples = if missing + 1 == sequence {
+                    frame.position.saturating_sub(position)
+                } else {
+                    length.min(frame.position.saturating_sub(position))
+                };
+                ready.push(SamplesFrame {
+                    sequence: missing,
+                    position,
+                    samples: SampleBuffer::silence(frame.samples.format(), samples as usize),
+                });
+                position += samples;
+            }
+        }
+        self.next = Some((
+            sequence.saturating_add(1),
+            frame.position.saturating_add(frame.samples.len() as u64),
+        ));
+        ready.push(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JitterBuffer, JitterStats};
+    use crate::audio::message::SamplesFrame;
+    use crate::audio::samples::SampleBuffer;
+
+    fn frame(sequence: u64) -> SamplesFrame {
+        SamplesFrame {
+            sequence,
+            position: sequence * 4,
+            samples: SampleBuffer::I16(vec![sequence as i16 + 1; 4]),
+        }
+    }
+
+    fn sequences(frames: &[SamplesFrame]) -> Vec<u64> {
+        frames.iter().map(|frame| frame.sequence).collect()
+    }
+
+    #[test]
+    fn reorders_frames_within_its_depth() {
+        let mut jitter = JitterBuffer::new(2);
+        assert!(jitter.push(frame(1)).is_empty());
+        assert!(jitter.push(frame(0)).is_empty());
+        assert_eq!(sequences(&jitter.push(frame(3))), vec![0, 1]);
+        assert_eq!(sequences(&jitter.push(frame(2))), vec![2, 3]);
+        assert_eq!(sequences(&jitter.push(frame(4))), vec![4]);
+        assert!(jitter.push(frame(2)).is_empty());
+        assert_eq!(
+            jitter.stats(),
+            JitterStats {
+                received: 6,
+                reordered: 2,
+                late: 1,
+                ..JitterStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn conceals_lost_frames_with_silence() {
+        let mut jitter = JitterBuffer::new(1);
+        assert!(jitter.push(frame(0)).is_empty());
+        assert_eq!(sequences(&jitter.push(frame(3))), vec![0]);
+        assert!(jitter.push(frame(3)).is_empty());
+        let released = jitter.push(frame(4));
+        // Frames 1 and 2 never came, silence takes their place
+        assert_eq!(sequences(&released), vec![1, 2, 3, 4]);
+        assert_eq!(released[1].position, 8);
+        assert_eq!(released[1].samples, SampleBuffer::I16(vec![0; 4]));
+        assert!(jitter.push(frame(6)).is_empty());
+        assert_eq!(sequences(&jitter.drain()), vec![5, 6]);
+
+        let stats = jitter.stats();
+        assert_eq!(stats.lost, 3);
+        assert_eq!(stats.concealed_samples, 12);
+        assert_eq!(stats.duplicates, 1);
+    }
+
+    #[test]
+    fn resyncs_on_gaps_too_long_to_conceal() {
+        let mut jitter = JitterBuffer::new(1);
+        assert!(jitter.push(frame(0)).is_empty());
+        assert_eq!(sequences(&jitter.push(frame(1_000))), vec![0]);
+        // Whether from an outage or a forged sequence number, nothing is concealed
+        assert_eq!(sequences(&jitter.push(frame(1_002))), vec![1_000]);
+        let mut ahead = frame(1_004);
+        ahead.position = u64::MAX / 2;
+        assert_eq!(sequences(&jitter.push(ahead)), vec![1_001, 1_002]);
+        // Nor when a frame claims to start too far ahead
+        assert_eq!(sequences(&jitter.drain()), vec![1_004]);
+
+        let stats = jitter.stats();
+        assert_eq!(stats.resyncs, 2);
+        assert_eq!((stats.lost, stats.concealed_samples), (1, 4));
+    }
+}
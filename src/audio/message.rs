@@ -1,6 +1,12 @@
 use crate::audio::DeserializationError::{DataLengthMismatch, UnknownWaveSpecSampleFormat};
+use crate::audio::codec::EncodedFrame;
 use crate::audio::message::LengthError::TooLong;
+use crate::audio::samples::SampleBuffer;
 use hound::{SampleFormat, WavSpec};
+use std::time::Duration;
+
+/// Version of the wire format. Bump it whenever a message layout changes.
+pub const PROTOCOL_VERSION: u16 = 5;
 
 #[derive(Debug)]
 pub enum LengthError {
@@ -17,22 +23,261 @@ pub enum DeserializationError {
         current_length: usize,
     },
     UnknownWaveSpecSampleFormat,
+    UnsupportedProtocolVersion {
+        supported: u16,
+        received: u16,
+    },
+    UnknownPcmFormat {
+        tag: u8,
+    },
+    UnknownCodec {
+        tag: u8,
+    },
 }
 pub trait Serializable: Sized {
     fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), LengthError>;
     fn deserialize(bytes: &[u8]) -> Result<Self, DeserializationError>;
 }
 
+/// Sample encodings a peer is able to handle.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PcmFormat {
+    I8,
+    I16,
+    I24,
+    I32,
+    F32,
+}
+
+impl PcmFormat {
+    pub fn from_spec(spec: &WavSpec) -> Option<Self> {
+        match (spec.sample_format, spec.bits_per_sample) {
+            (SampleFormat::Int, 8) => Some(PcmFormat::I8),
+            (SampleFormat::Int, 16) => Some(PcmFormat::I16),
+            (SampleFormat::Int, 24) => Some(PcmFormat::I24),
+            (SampleFormat::Int, 32) => Some(PcmFormat::I32),
+            (SampleFormat::Float, 32) => Some(PcmFormat::F32),
+            _ => None,
+        }
+    }
+}
+
+impl From<PcmFormat> for u8 {
+    fn from(format: PcmFormat) -> Self {
+        match format {
+            PcmFormat::I8 => 1,
+            PcmFormat::I16 => 2,
+            PcmFormat::I24 => 3,
+            PcmFormat::I32 => 4,
+            PcmFormat::F32 => 5,
+        }
+    }
+}
+
+impl TryFrom<u8> for PcmFormat {
+    type Error = DeserializationError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(PcmFormat::I8),
+            2 => Ok(PcmFormat::I16),
+            3 => Ok(PcmFormat::I24),
+            4 => Ok(PcmFormat::I32),
+            5 => Ok(PcmFormat::F32),
+            _ => Err(DeserializationError::UnknownPcmFormat { tag: value }),
+        }
+    }
+}
+
+/// Encoding applied to the samples carried on the wire.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Codec {
+    Pcm,
+    /// Linear prediction with Rice coded residuals, decoding to the exact samples sent.
+    Lossless,
+    /// 4-bit IMA ADPCM of 16-bit samples.
+    ImaAdpcm,
+    /// G.711 mu-law, 8 bits per 16-bit sample.
+    MuLaw,
+    /// G.711 A-law, 8 bits per 16-bit sample.
+    ALaw,
+}
+
+impl Codec {
+    /// Whether decoding gives back the exact samples that were encoded.
+    pub fn is_lossless(self) -> bool {
+        matches!(self, Codec::Pcm | Codec::Lossless)
+    }
+    /// Format of the decoded samples of a stream in `format`.
+    pub fn decoded_format(self, format: PcmFormat) -> PcmFormat {
+        if self.is_lossless() {
+            format
+        } else {
+            PcmFormat::I16
+        }
+    }
+    /// Spec of the decoded samples of a stream described by `spec`.
+    pub fn decoded_spec(self, spec: &WavSpec) -> WavSpec {
+        if self.is_lossless() {
+            return *spec;
+        }
+        WavSpec {
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+            ..*spec
+        }
+    }
+}
+
+impl From<Codec> for u8 {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::Pcm => 1,
+            Codec::Lossless => 2,
+            Codec::ImaAdpcm => 3,
+            Codec::MuLaw => 4,
+            Codec::ALaw => 5,
+        }
+    }
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = DeserializationError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Codec::Pcm),
+            2 => Ok(Codec::Lossless),
+            3 => Ok(Codec::ImaAdpcm),
+            4 => Ok(Codec::MuLaw),
+            5 => Ok(Codec::ALaw),
+            _ => Err(DeserializationError::UnknownCodec { tag: value }),
+        }
+    }
+}
+
+/// Bit set of optional protocol features.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// Peer understands `EndOfStream` and acknowledges it before disconnecting.
+    pub const END_OF_STREAM: Features = Features(1);
+    /// Server keeps recent frames to replay to clients reconnecting mid-stream.
+    pub const RESUME: Features = Features(2);
+
+    pub fn from_bits(bits: u32) -> Self {
+        Features(bits)
+    }
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+    pub const fn union(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+/// First message of a connection, sent by the client to announce what it supports.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hello {
+    pub version: u16,
+    pub sample_formats: Vec<PcmFormat>,
+    pub codecs: Vec<Codec>,
+    pub features: Features,
+    /// Set by a client reconnecting mid-stream: position right after the last sample it
+    /// received, where it wants the stream to resume.
+    pub resume_position: Option<u64>,
+}
+
+impl Hello {
+    pub fn new(sample_formats: Vec<PcmFormat>, codecs: Vec<Codec>, features: Features) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            sample_formats,
+            codecs,
+            features,
+            resume_position: None,
+        }
+    }
+}
+
+/// Server answer to a [`Hello`], carrying what was negotiated for the connection.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Welcome {
+    pub version: u16,
+    pub codec: Codec,
+    pub features: Features,
+}
+
+impl Welcome {
+    pub fn new(codec: Codec, features: Features) -> Self {
+        Welcome {
+            version: PROTOCOL_VERSION,
+            codec,
+            features,
+        }
+    }
+}
+
+/// A chunk of the stream, tagged with its place in it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SamplesFrame {
+    /// Incremented by one for every frame the server sends.
+    pub sequence: u64,
+    /// Index of the first sample of the frame in the stream, counting every channel.
+    pub position: u64,
+    pub samples: SampleBuffer,
+}
+
+impl SamplesFrame {
+    /// Time at which the first sample of the frame should be played, relative to the
+    /// start of the stream.
+    pub fn presentation_time(&self, spec: &WavSpec) -> Duration {
+        let samples_per_second = spec.sample_rate as u64 * spec.channels.max(1) as u64;
+        if samples_per_second == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(self.position as f64 / samples_per_second as f64)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum AudioMessage {
-    Spec(WavSpec),
-    Samples(Vec<i16>),
+    /// Describes the stream as the server reads it. Samples of a lossy `codec` decode
+    /// to [`Codec::decoded_spec`] instead.
+    Spec {
+        spec: WavSpec,
+        codec: Codec,
+    },
+    Samples(SamplesFrame),
+    Hello(Hello),
+    Welcome(Welcome),
+    /// Sent by the server once the whole stream went out.
+    EndOfStream {
+        total_samples: u64,
+    },
+    /// Sent back by the client once its output is finalized or drained.
+    EndOfStreamAck {
+        received_samples: u64,
+    },
+    /// Samples compressed with the codec negotiated for the connection.
+    EncodedSamples(EncodedFrame),
 }
 
 #[repr(u8)]
 enum AudioMessageType {
     Spec = 1,
     Samples = 2,
+    Hello = 3,
+    Welcome = 4,
+    EndOfStream = 5,
+    EndOfStreamAck = 6,
+    EncodedSamples = 7,
 }
 
 impl TryFrom<u8> for AudioMessageType {
@@ -41,20 +286,109 @@ impl TryFrom<u8> for AudioMessageType {
         match value {
             1 => Ok(AudioMessageType::Spec),
             2 => Ok(AudioMessageType::Samples),
+            3 => Ok(AudioMessageType::Hello),
+            4 => Ok(AudioMessageType::Welcome),
+            5 => Ok(AudioMessageType::EndOfStream),
+            6 => Ok(AudioMessageType::EndOfStreamAck),
+            7 => Ok(AudioMessageType::EncodedSamples),
             _ => Err(DeserializationError::IncorrectAudioMessageType { kind: value }),
         }
     }
 }
 
-const SPEC_MSG_LEN: usize = 1 + 2 + 4 + 2 + 1;
+const SPEC_MSG_LEN: usize = 1 + 2 + 4 + 2 + 1 + 1;
 // message_type(1) + channels(2) + sample_rate(4) + bits_per_sample(2) + sample_format(1)
-const SAMPLES_HEADER_LEN: usize = 1 + 4;
-const SAMPLE_SIZE: usize = 2;
+// + codec(1)
+const SAMPLES_HEADER_LEN: usize = 1 + 8 + 8 + 1 + 4;
+// message_type(1) + sequence(8) + position(8) + pcm_format(1) + length(4)
+const ENCODED_SAMPLES_HEADER_LEN: usize = 1 + 8 + 8 + 1 + 1 + 2 + 4 + 4;
+// message_type(1) + sequence(8) + position(8) + codec(1) + pcm_format(1) + channels(2)
+// + sample_count(4) + payload_length(4)
+
+/// Cursor over a received message, reporting truncated input as a length mismatch.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Starts right after the message type tag.
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 1 }
+    }
+    fn take(&mut self, count: usize) -> Result<&'a [u8], DeserializationError> {
+        let end = self.position + count;
+        if end > self.bytes.len() {
+            return Err(DataLengthMismatch {
+                expected_length: end,
+                current_length: self.bytes.len(),
+            });
+        }
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+    fn u8(&mut self) -> Result<u8, DeserializationError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, DeserializationError> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+    fn u32(&mut self) -> Result<u32, DeserializationError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+    fn u64(&mut self) -> Result<u64, DeserializationError> {
+        let low = self.u32()? as u64;
+        let high = self.u32()? as u64;
+        Ok(low | (high << 32))
+    }
+    /// Reads the protocol version and rejects anything this build cannot decode.
+    fn version(&mut self) -> Result<u16, DeserializationError> {
+        let version = self.u16()?;
+        if version != PROTOCOL_VERSION {
+            return Err(DeserializationError::UnsupportedProtocolVersion {
+                supported: PROTOCOL_VERSION,
+                received: version,
+            });
+        }
+        Ok(version)
+    }
+    fn finish(&self) -> Result<(), DeserializationError> {
+        if self.position != self.bytes.len() {
+            return Err(DataLengthMismatch {
+                expected_length: self.position,
+                current_length: self.bytes.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn push_list<T: Copy + Into<u8>>(buf: &mut Vec<u8>, items: &[T]) -> Result<(), LengthError> {
+    if items.len() > u8::MAX as usize {
+        return Err(TooLong { len: items.len() });
+    }
+    buf.push(items.len() as u8);
+    buf.extend(items.iter().map(|&item| item.into()));
+    Ok(())
+}
+
+fn read_list<T: TryFrom<u8, Error = DeserializationError>>(
+    reader: &mut Reader,
+) -> Result<Vec<T>, DeserializationError> {
+    let count = reader.u8()? as usize;
+    reader
+        .take(count)?
+        .iter()
+        .map(|&tag| T::try_from(tag))
+        .collect()
+}
 
 impl Serializable for AudioMessage {
     fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), LengthError> {
         match self {
-            AudioMessage::Spec(spec) => {
+            AudioMessage::Spec { spec, codec } => {
                 buf.reserve(SPEC_MSG_LEN);
                 buf.push(AudioMessageType::Spec as u8);
                 buf.extend_from_slice(&spec.channels.to_le_bytes());
@@ -66,17 +400,68 @@ impl Serializable for AudioMessage {
                     SampleFormat::Int => 2,
                 };
                 buf.push(format_tag);
+                buf.push((*codec).into());
             }
-            AudioMessage::Samples(samples) => {
-                if samples.len() > (u32::MAX / 2) as usize {
+            AudioMessage::Samples(frame) => {
+                let samples = &frame.samples;
+                let format = samples.format();
+                if samples.len() > (u32::MAX as usize) / format.bytes_per_sample() {
                     // Prevent overflow when calculating length in bytes
                     return Err(TooLong { len: samples.len() });
                 }
-                buf.reserve(SAMPLES_HEADER_LEN + samples.len() * SAMPLE_SIZE);
+                buf.reserve(SAMPLES_HEADER_LEN + samples.len() * format.bytes_per_sample());
                 buf.push(AudioMessageType::Samples as u8);
+                buf.extend_from_slice(&frame.sequence.to_le_bytes());
+                buf.extend_from_slice(&frame.position.to_le_bytes());
+                buf.push(format.into());
                 let len = (samples.len() as u32).to_le_bytes();
                 buf.extend_from_slice(&len);
-                buf.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+                samples.write_le(buf);
+            }
+            AudioMessage::Hello(hello) => {
+                buf.push(AudioMessageType::Hello as u8);
+                buf.extend_from_slice(&hello.version.to_le_bytes());
+                push_list(buf, &hello.sample_formats)?;
+                push_list(buf, &hello.codecs)?;
+                buf.extend_from_slice(&hello.features.bits().to_le_bytes());
+                match hello.resume_position {
+                    Some(position) => {
+                        buf.push(1);
+                        buf.extend_from_slice(&position.to_le_bytes());
+                    }
+                    None => buf.push(0),
+                }
+            }
+            AudioMessage::Welcome(welcome) => {
+                buf.push(AudioMessageType::Welcome as u8);
+                buf.extend_from_slice(&welcome.version.to_le_bytes());
+                buf.push(welcome.codec.into());
+                buf.extend_from_slice(&welcome.features.bits().to_le_bytes());
+            }
+            AudioMessage::EndOfStream { total_samples } => {
+                buf.push(AudioMessageType::EndOfStream as u8);
+                buf.extend_from_slice(&total_samples.to_le_bytes());
+            }
+            AudioMessage::EndOfStreamAck { received_samples } => {
+                buf.push(AudioMessageType::EndOfStreamAck as u8);
+                buf.extend_from_slice(&received_samples.to_le_bytes());
+            }
+            AudioMessage::EncodedSamples(frame) => {
+                if frame.payload.len() > u32::MAX as usize {
+                    return Err(TooLong {
+                        len: frame.payload.len(),
+                    });
+                }
+                buf.reserve(ENCODED_SAMPLES_HEADER_LEN + frame.payload.len());
+                buf.push(AudioMessageType::EncodedSamples as u8);
+                buf.extend_from_slice(&frame.sequence.to_le_bytes());
+                buf.extend_from_slice(&frame.position.to_le_bytes());
+                buf.push(frame.codec.into());
+                buf.push(frame.format.into());
+                buf.extend_from_slice(&frame.channels.to_le_bytes());
+                buf.extend_from_slice(&frame.sample_count.to_le_bytes());
+                buf.extend_from_slice(&(frame.payload.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&frame.payload);
             }
         }
         Ok(())
@@ -119,40 +504,104 @@ impl Serializable for AudioMessage {
                     2 => SampleFormat::Int,
                     _ => return Err(UnknownWaveSpecSampleFormat),
                 };
+                let codec = Codec::try_from(bytes[10])?;
 
-                Ok(AudioMessage::Spec(WavSpec {
-                    channels,
-                    sample_rate,
-                    bits_per_sample,
-                    sample_format,
-                }))
+                Ok(AudioMessage::Spec {
+                    spec: WavSpec {
+                        channels,
+                        sample_rate,
+                        bits_per_sample,
+                        sample_format,
+                    },
+                    codec,
+                })
             }
             Ok(AudioMessageType::Samples) => {
-                let data_length_mismatch_error = DataLengthMismatch {
-                    current_length: bytes.len(),
-                    expected_length: 5,
-                };
-                if bytes.len() < SAMPLES_HEADER_LEN {
-                    return Err(data_length_mismatch_error);
-                }
-                let length = u32::from_le_bytes(
-                    bytes[1..5]
-                        .try_into()
-                        .map_err(|_| data_length_mismatch_error)?,
-                );
-                let expected_length = SAMPLES_HEADER_LEN + (length as usize) * SAMPLE_SIZE;
+                let mut reader = Reader::new(bytes);
+                let sequence = reader.u64()?;
+                let position = reader.u64()?;
+                let format = PcmFormat::try_from(reader.u8()?)?;
+                let length = reader.u32()?;
+                let expected_length =
+                    SAMPLES_HEADER_LEN + (length as usize) * format.bytes_per_sample();
                 if bytes.len() != expected_length {
                     return Err(DataLengthMismatch {
                         current_length: bytes.len(),
                         expected_length,
                     });
                 }
-                let samples: Vec<i16> = bytes[5..]
-                    .chunks_exact(2)
-                    .map(|chunk| i16::from_le_bytes([chunk[0], chunk[1]]))
-                    .collect();
+                let samples = SampleBuffer::read_le(format, &bytes[SAMPLES_HEADER_LEN..]);
 
-                Ok(AudioMessage::Samples(samples))
+                Ok(AudioMessage::Samples(SamplesFrame {
+                    sequence,
+                    position,
+                    samples,
+                }))
+            }
+            Ok(AudioMessageType::Hello) => {
+                let mut reader = Reader::new(bytes);
+                let version = reader.version()?;
+                let sample_formats = read_list(&mut reader)?;
+                let codecs = read_list(&mut reader)?;
+                let features = Features::from_bits(reader.u32()?);
+                let resume_position = if reader.u8()? != 0 {
+                    Some(reader.u64()?)
+                } else {
+                    None
+                };
+                reader.finish()?;
+                Ok(AudioMessage::Hello(Hello {
+                    version,
+                    sample_formats,
+                    codecs,
+                    features,
+                    resume_position,
+                }))
+            }
+            Ok(AudioMessageType::Welcome) => {
+                let mut reader = Reader::new(bytes);
+                let version = reader.version()?;
+                let codec = Codec::try_from(reader.u8()?)?;
+                let features = Features::from_bits(reader.u32()?);
+                reader.finish()?;
+                Ok(AudioMessage::Welcome(Welcome {
+                    version,
+                    codec,
+                    features,
+                }))
+            }
+            Ok(AudioMessageType::EndOfStream) => {
+                let mut reader = Reader::new(bytes);
+                let total_samples = reader.u64()?;
+                reader.finish()?;
+                Ok(AudioMessage::EndOfStream { total_samples })
+            }
+            Ok(AudioMessageType::EndOfStreamAck) => {
+                let mut reader = Reader::new(bytes);
+                let received_samples = reader.u64()?;
+                reader.finish()?;
+                Ok(AudioMessage::EndOfStreamAck { received_samples })
+            }
+            Ok(AudioMessageType::EncodedSamples) => {
+                let mut reader = Reader::new(bytes);
+                let sequence = reader.u64()?;
+                let position = reader.u64()?;
+                let codec = Codec::try_from(reader.u8()?)?;
+                let format = PcmFormat::try_from(reader.u8()?)?;
+                let channels = reader.u16()?;
+                let sample_count = reader.u32()?;
+                let length = reader.u32()? as usize;
+                let payload = reader.take(length)?.to_vec();
+                reader.finish()?;
+                Ok(AudioMessage::EncodedSamples(EncodedFrame {
+                    sequence,
+                    position,
+                    codec,
+                    format,
+                    channels,
+                    sample_count,
+                    payload,
+                }))
             }
             Err(e) => Err(e),
         }
@@ -161,9 +610,14 @@ impl Serializable for AudioMessage {
 
 #[cfg(test)]
 mod tests {
-    use crate::audio::{AudioMessage, DeserializationError, Serializable};
+    use crate::audio::codec::{CodecError, EncodedFrame};
+    use crate::audio::message::{
+        Codec, Features, Hello, PROTOCOL_VERSION, PcmFormat, SamplesFrame, Welcome,
+    };
+    use crate::audio::{AudioMessage, DeserializationError, SampleBuffer, Serializable};
     use hound::{SampleFormat, WavSpec};
     use log::{LevelFilter, debug};
+    use std::time::Duration;
 
     fn round_trip(msg: AudioMessage) {
         let mut buf = Vec::new();
@@ -179,21 +633,81 @@ mod tests {
             .filter_level(LevelFilter::Trace)
             .init();
         let messages = vec![
-            AudioMessage::Spec(WavSpec {
-                channels: 1,
-                sample_rate: 44_100,
-                bits_per_sample: 16,
-                sample_format: SampleFormat::Int,
+            AudioMessage::Spec {
+                spec: WavSpec {
+                    channels: 1,
+                    sample_rate: 44_100,
+                    bits_per_sample: 16,
+                    sample_format: SampleFormat::Int,
+                },
+                codec: Codec::Pcm,
+            },
+            AudioMessage::Spec {
+                spec: WavSpec {
+                    channels: 2,
+                    sample_rate: 48_000,
+                    bits_per_sample: 32,
+                    sample_format: SampleFormat::Float,
+                },
+                codec: Codec::ImaAdpcm,
+            },
+            AudioMessage::Samples(SamplesFrame {
+                sequence: 0,
+                position: 0,
+                samples: SampleBuffer::I16(vec![]),
+            }),
+            AudioMessage::Samples(SamplesFrame {
+                sequence: 1,
+                position: 0,
+                samples: SampleBuffer::I16(vec![0]),
+            }),
+            AudioMessage::Samples(SamplesFrame {
+                sequence: u64::MAX,
+                position: u64::MAX - 5,
+                samples: SampleBuffer::I16(vec![i16::MIN, -1, 0, 1, i16::MAX]),
+            }),
+            AudioMessage::Samples(SamplesFrame {
+                sequence: 2,
+                position: 5,
+                samples: SampleBuffer::I8(vec![i8::MIN, -1, 0, 1, i8::MAX]),
+            }),
+            AudioMessage::Samples(SamplesFrame {
+                sequence: 3,
+                position: 10,
+                samples: SampleBuffer::I24(vec![-8_388_608, -1, 0, 1, 8_388_607]),
+            }),
+            AudioMessage::Samples(SamplesFrame {
+                sequence: 4,
+                position: 15,
+                samples: SampleBuffer::I32(vec![i32::MIN, -1, 0, 1, i32::MAX]),
             }),
-            AudioMessage::Spec(WavSpec {
-                channels: 2,
-                sample_rate: 48_000,
-                bits_per_sample: 32,
-                sample_format: SampleFormat::Float,
+            AudioMessage::Samples(SamplesFrame {
+                sequence: 5,
+                position: 20,
+                samples: SampleBuffer::F32(vec![-1.0, -0.5, 0.0, f32::MIN_POSITIVE, 1.0]),
             }),
-            AudioMessage::Samples(vec![]),
-            AudioMessage::Samples(vec![0]),
-            AudioMessage::Samples(vec![i16::MIN, -1, 0, 1, i16::MAX]),
+            AudioMessage::Hello(Hello::new(vec![], vec![], Features::NONE)),
+            AudioMessage::Hello(Hello::new(
+                vec![PcmFormat::I16, PcmFormat::F32],
+                vec![Codec::Pcm],
+                Features::from_bits(0b101),
+            )),
+            AudioMessage::Hello(Hello {
+                resume_position: Some(441_000),
+                ..Hello::new(vec![PcmFormat::I16], vec![Codec::Pcm], Features::RESUME)
+            }),
+            AudioMessage::Welcome(Welcome::new(Codec::Pcm, Features::NONE)),
+            AudioMessage::Welcome(Welcome::new(Codec::Pcm, Features::END_OF_STREAM)),
+            AudioMessage::Welcome(Welcome::new(Codec::Lossless, Features::NONE)),
+            AudioMessage::Welcome(Welcome::new(Codec::MuLaw, Features::NONE)),
+            AudioMessage::Welcome(Welcome::new(Codec::ALaw, Features::NONE)),
+            AudioMessage::EndOfStream { total_samples: 0 },
+            AudioMessage::EndOfStream {
+                total_samples: u64::MAX,
+            },
+            AudioMessage::EndOfStreamAck {
+                received_samples: 44_100 * 60 * 60,
+            },
         ];
 
         for msg in messages {
@@ -202,6 +716,126 @@ mod tests {
         }
     }
 
+    /// Test signals covering silence, tones, noise and full scale jumps.
+    fn test_signals(min: i64, max: i64) -> Vec<Vec<i64>> {
+        let scale = (max as f64) * 0.8;
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut noise = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            min + (state % (max - min + 1) as u64) as i64
+        };
+        vec![
+            vec![],
+            vec![max],
+            vec![0; 1_000],
+            (0..1_000)
+                .map(|n| ((n as f64 * 0.05).sin() * scale) as i64)
+                .collect(),
+            (0..1_000)
+                .map(|n| {
+                    ((n as f64 * 0.3).sin() * scale * 0.5 + (n as f64 * 0.011).cos() * scale * 0.4)
+                        as i64
+                })
+                .collect(),
+            (0..999).map(|_| noise()).collect(),
+            (0..1_000)
+                .map(|n| if n % 2 == 0 { min } else { max })
+                .collect(),
+        ]
+    }
+
+    fn to_buffer(format: PcmFormat, values: &[i64]) -> SampleBuffer {
+        match format {
+            PcmFormat::I8 => SampleBuffer::I8(values.iter().map(|&v| v as i8).collect()),
+            PcmFormat::I16 => SampleBuffer::I16(values.iter().map(|&v| v as i16).collect()),
+            PcmFormat::I24 => SampleBuffer::I24(values.iter().map(|&v| v as i32).collect()),
+            PcmFormat::I32 => SampleBuffer::I32(values.iter().map(|&v| v as i32).collect()),
+            PcmFormat::F32 => {
+                SampleBuffer::F32(values.iter().map(|&v| v as f32 / i32::MAX as f32).collect())
+            }
+        }
+    }
+
+    #[test]
+    fn encoded_samples_round_trip_bit_exactly() {
+        let formats = [
+            (PcmFormat::I8, i8::MIN as i64, i8::MAX as i64),
+            (PcmFormat::I16, i16::MIN as i64, i16::MAX as i64),
+            (PcmFormat::I24, -8_388_608, 8_388_607),
+            (PcmFormat::I32, i32::MIN as i64, i32::MAX as i64),
+            (PcmFormat::F32, i32::MIN as i64, i32::MAX as i64),
+        ];
+        for (format, min, max) in formats {
+            for values in test_signals(min, max) {
+                for channels in [1, 2, 3] {
+                    let frame = SamplesFrame {
+                        sequence: 7,
+                        position: 1_234,
+                        samples: to_buffer(format, &values),
+                    };
+                    for codec in [Codec::Pcm, Codec::Lossless] {
+                        let encoded = EncodedFrame::encode(&frame, codec, channels);
+                        let mut bytes = Vec::new();
+                        AudioMessage::EncodedSamples(encoded.clone())
+                            .serialize(&mut bytes)
+                            .expect("serialize failed");
+                        assert_eq!(
+                            AudioMessage::deserialize(&bytes),
+                            Ok(AudioMessage::EncodedSamples(encoded.clone()))
+                        );
+                        assert_eq!(
+                            encoded.decode().expect("decode failed"),
+                            frame,
+                            "{format:?} over {channels} channels with {codec:?}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lossless_codec_shrinks_smooth_signals() {
+        let values: Vec<i64> = (0..2_048)
+            .map(|n| ((n as f64 * 0.02).sin() * 20_000.0) as i64)
+            .collect();
+        let frame = SamplesFrame {
+            sequence: 0,
+            position: 0,
+            samples: to_buffer(PcmFormat::I16, &values),
+        };
+        let encoded = EncodedFrame::encode(&frame, Codec::Lossless, 2);
+        // A smooth tone should take at most a quarter of its 16-bit PCM size
+        let pcm_bytes = values.len() * 2;
+        assert!(
+            encoded.payload.len() * 4 <= pcm_bytes,
+            "{} bytes for {} bytes of PCM",
+            encoded.payload.len(),
+            pcm_bytes
+        );
+
+        let mut truncated = encoded.clone();
+        truncated.payload.truncate(truncated.payload.len() / 2);
+        assert!(truncated.decode().is_err());
+    }
+
+    #[test]
+    fn lossless_codec_rejects_huge_sample_counts() {
+        // A constant subframe would fill any count from these 3 bytes
+        let forged = EncodedFrame {
+            sequence: 0,
+            position: 0,
+            codec: Codec::Lossless,
+            format: PcmFormat::I16,
+            channels: 1,
+            sample_count: u32::MAX,
+            payload: vec![0, 0, 0],
+        };
+        assert_eq!(forged.decode(), Err(CodecError::InvalidData));
+    }
+
     #[test]
     fn unknown_sample_format_tag_yields_correct_error() {
         let mut bytes = Vec::new();
@@ -210,8 +844,57 @@ mod tests {
         bytes.extend_from_slice(&44_100u32.to_le_bytes());
         bytes.extend_from_slice(&16u16.to_le_bytes());
         bytes.push(99);
+        bytes.push(1);
 
         let err = AudioMessage::deserialize(&bytes).unwrap_err();
         assert_eq!(err, DeserializationError::UnknownWaveSpecSampleFormat);
     }
+
+    #[test]
+    fn mismatched_protocol_version_is_rejected() {
+        let future_version = PROTOCOL_VERSION + 1;
+        let messages = [
+            AudioMessage::Hello(Hello {
+                version: future_version,
+                sample_formats: vec![PcmFormat::I16],
+                codecs: vec![Codec::Pcm],
+                features: Features::NONE,
+                resume_position: None,
+            }),
+            AudioMessage::Welcome(Welcome {
+                version: future_version,
+                codec: Codec::Pcm,
+                features: Features::NONE,
+            }),
+        ];
+
+        for msg in messages {
+            let mut bytes = Vec::new();
+            msg.serialize(&mut bytes).expect("serialize failed");
+            let err = AudioMessage::deserialize(&bytes).unwrap_err();
+            assert_eq!(
+                err,
+                DeserializationError::UnsupportedProtocolVersion {
+                    supported: PROTOCOL_VERSION,
+                    received: future_version,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn presentation_time_follows_position() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48_000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let frame = SamplesFrame {
+            sequence: 3,
+            position: 144_000,
+            samples: SampleBuffer::I16(vec![]),
+        };
+        assert_eq!(frame.presentation_time(&spec), Duration::from_millis(1_500));
+    }
 }
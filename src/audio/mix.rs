@@ -0,0 +1,237 @@
+/// Gain applied to the center channel when folding it into left and right.
+const CENTER_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Speaker a channel feeds, in the order of the WAV channel mask bits.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Speaker {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    Lfe,
+    BackLeft,
+    BackRight,
+    FrontLeftCenter,
+    FrontRightCenter,
+    BackCenter,
+    SideLeft,
+    SideRight,
+}
+
+use Speaker::*;
+
+/// Every speaker a channel mask can name, in WAV channel order.
+const WAV_ORDER: [Speaker; 11] = [
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    Lfe,
+    BackLeft,
+    BackRight,
+    FrontLeftCenter,
+    FrontRightCenter,
+    BackCenter,
+    SideLeft,
+    SideRight,
+];
+
+/// Speakers of the usual layout with `channels` channels, e.g. quad or 5.1, in WAV
+/// channel order. Channels past the last speaker WAV names have no role.
+fn layout(channels: usize) -> Vec<Option<Speaker>> {
+    let speakers: &[Speaker] = match channels {
+        1 => &[FrontCenter],
+        2 => &[FrontLeft, FrontRight],
+        3 => &[FrontLeft, FrontRight, FrontCenter],
+        4 => &[FrontLeft, FrontRight, BackLeft, BackRight],
+        5 => &[FrontLeft, FrontRight, FrontCenter, BackLeft, BackRight],
+        6 => &[FrontLeft, FrontRight, FrontCenter, Lfe, BackLeft, BackRight],
+        7 => &[
+            FrontLeft,
+            FrontRight,
+            FrontCenter,
+            Lfe,
+            BackCenter,
+            SideLeft,
+            SideRight,
+        ],
+        8 => &[
+            FrontLeft,
+            FrontRight,
+            FrontCenter,
+            Lfe,
+            BackLeft,
+            BackRight,
+            SideLeft,
+            SideRight,
+        ],
+        _ => &WAV_ORDER,
+    };
+    (0..channels)
+        .map(|channel| speakers.get(channel).copied())
+        .collect()
+}
+
+/// Where a speaker missing from the device goes instead, by order of preference. A
+/// pair of speakers shares the signal.
+fn fallbacks(speaker: Speaker) -> &'static [&'static [Speaker]] {
+    match speaker {
+        FrontLeft => &[&[FrontLeft], &[FrontCenter]],
+        FrontRight => &[&[FrontRight], &[FrontCenter]],
+        FrontCenter => &[&[FrontCenter], &[FrontLeft, FrontRight]],
+        // Too low to be heard without a subwoofer, and likely to clip the others
+        Lfe => &[&[Lfe]],
+        BackLeft => &[&[BackLeft], &[SideLeft], &[FrontLeft], &[FrontCenter]],
+        BackRight => &[&[BackRight], &[SideRight], &[FrontRight], &[FrontCenter]],
+        FrontLeftCenter => &[&[FrontLeftCenter], &[FrontLeft], &[FrontCenter]],
+        FrontRightCenter => &[&[FrontRightCenter], &[FrontRight], &[FrontCenter]],
+        BackCenter => &[
+            &[BackCenter],
+            &[BackLeft, BackRight],
+            &[SideLeft, SideRight],
+            &[FrontLeft, FrontRight],
+            &[FrontCenter],
+        ],
+        SideLeft => &[&[SideLeft], &[BackLeft], &[FrontLeft], &[FrontCenter]],
+        SideRight => &[&[SideRight], &[BackRight], &[FrontRight], &[FrontCenter]],
+    }
+}
+
+/// Maps interleaved frames from the stream channel count onto the device channel count.
+///
+/// Channels are matched by speaker, assuming the usual layout for their count. Mono is
+/// copied to every output. A speaker the device lacks is folded into the nearest ones
+/// it has: the center into left and right, surrounds into the rear or the front. The
+/// LFE only plays on a device with one, and outputs no stream channel maps to are left
+/// silent.
+#[derive(Debug, Clone)]
+pub struct ChannelMixer {
+    source_channels: usize,
+    output_channels: usize,
+    /// `output_channels` rows of `source_channels` gains.
+    gains: Vec<f32>,
+}
+
+impl ChannelMixer {
+    pub fn new(source_channels: usize, output_channels: usize) -> Self {
+        let source_channels = source_channels.max(1);
+        let output_channels = output_channels.max(1);
+        let mut gains = vec![0.0; source_channels * output_channels];
+        let outputs = layout(output_channels);
+        for (input, speaker) in layout(source_channels).into_iter().enumerate() {
+            let targets: Vec<usize> = match speaker {
+                _ if source_channels == 1 => (0..output_channels).collect(),
+                Some(speaker) => fallbacks(speaker)
+                    .iter()
+                    .map(|group| {
+                        group
+                            .iter()
+                            .filter_map(|wanted| outputs.iter().position(|s| *s == Some(*wanted)))
+                            .collect::<Vec<_>>()
+                    })
+                    .find(|found| !found.is_empty())
+                    .unwrap_or_default(),
+                // Without a role, a channel can only go to the output of the same index
+                None => (input < output_channels)
+                    .then_some(input)
+                    .into_iter()
+                    .collect(),
+            };
+            let gain = if targets.len() == 2 && source_channels > 1 {
+                CENTER_GAIN
+            } else {
+                1.0
+            };
+            for output in targets {
+                gains[output * source_channels + input] = gain;
+            }
+        }
+        // Keep the folded signal within range
+        for row in gains.chunks_exact_mut(source_channels) {
+            let total: f32 = row.iter().sum();
+            if total > 1.0 {
+                row.iter_mut().for_each(|gain| *gain /= total);
+            }
+        }
+        ChannelMixer {
+            source_channels,
+            output_channels,
+            gains,
+        }
+    }
+
+    pub fn source_channels(&self) -> usize {
+        self.source_channels
+    }
+    pub fn output_channels(&self) -> usize {
+        self.output_channels
+    }
+
+    /// Mixes one source frame into one output frame.
+    pub fn mix_frame(&self, source: &[f32], output: &mut [f32]) {
+        for (sample, row) in output
+            .iter_mut()
+            .zip(self.gains.chunks_exact(self.source_channels))
+        {
+            *sample = row.iter().zip(source).map(|(gain, s)| gain * s).sum();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChannelMixer;
+
+    fn mix(source_channels: usize, output_channels: usize, frame: &[f32]) -> Vec<f32> {
+        let mixer = ChannelMixer::new(source_channels, output_channels);
+        let mut output = vec![f32::NAN; output_channels];
+        mixer.mix_frame(frame, &mut output);
+        output
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn maps_channels_onto_device() {
+        // Same layout is a copy
+        assert_close(&mix(2, 2, &[0.25, -0.5]), &[0.25, -0.5]);
+        // Mono goes to every speaker
+        assert_close(&mix(1, 2, &[0.5]), &[0.5, 0.5]);
+        assert_close(&mix(1, 6, &[0.5]), &[0.5; 6]);
+        // Stereo to mono averages
+        assert_close(&mix(2, 1, &[0.5, -0.25]), &[0.125]);
+        // Stereo on a surround device keeps left and right, leaves the rest silent
+        assert_close(&mix(2, 4, &[0.5, -0.5]), &[0.5, -0.5, 0.0, 0.0]);
+        // Surround folds down to stereo without the LFE
+        let total = 2.0 + std::f32::consts::FRAC_1_SQRT_2;
+        let left = (2.0 + 0.5 * std::f32::consts::FRAC_1_SQRT_2) / total;
+        assert_close(
+            &mix(6, 2, &[1.0, 0.0, 0.5, 1.0, 1.0, 0.0]),
+            &[left, 0.5 * std::f32::consts::FRAC_1_SQRT_2 / total],
+        );
+        // Surround to mono ignores the LFE
+        assert_close(&mix(6, 1, &[0.5, 0.5, 0.5, 1.0, 0.5, 0.5]), &[0.5]);
+        // 5.1 on a quad device folds the center into the front pair, keeps the surrounds
+        // on the rear pair and drops the LFE
+        let front = 1.0 + std::f32::consts::FRAC_1_SQRT_2;
+        assert_close(
+            &mix(6, 4, &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]),
+            &[
+                (0.1 + 0.3 * std::f32::consts::FRAC_1_SQRT_2) / front,
+                (0.2 + 0.3 * std::f32::consts::FRAC_1_SQRT_2) / front,
+                0.5,
+                0.6,
+            ],
+        );
+        // 7.1 on a 5.1 device shares the rear pair between back and side speakers
+        assert_close(
+            &mix(8, 6, &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8]),
+            &[0.1, 0.2, 0.3, 0.4, 0.6, 0.7],
+        );
+        // Stereo on a 5.1 device stays in front, nothing comes out of the LFE
+        assert_close(&mix(2, 6, &[0.5, -0.5]), &[0.5, -0.5, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
@@ -1,15 +1,20 @@
+use crate::audio::mix::ChannelMixer;
+use crate::audio::resample::Resampler;
+use crate::audio::samples::SampleBuffer;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{
     BuildStreamError, DefaultStreamConfigError, DeviceNameError, Host, OutputCallbackInfo,
     PauseStreamError, PlayStreamError, Sample, SampleFormat, Stream, StreamConfig,
 };
 use hound::WavSpec;
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 use ringbuf::traits::{Consumer, Observer, Producer, Split};
 use ringbuf::{HeapCons, HeapProd, HeapRb};
 use std::fmt;
 use std::fs::File;
 use std::io::BufWriter;
+use std::thread::sleep;
+use std::time::Duration;
 
 pub struct WavAudioOutput {
     writer: hound::WavWriter<BufWriter<File>>,
@@ -48,12 +53,20 @@ impl WavAudioOutput {
             Err(e) => Err(WavOutputError::HoundError(e)),
         }
     }
-    pub fn write_samples(&mut self, samples: &[i16]) -> Result<(), WavOutputError> {
-        samples.iter().try_for_each(|&s| {
-            self.writer
-                .write_sample(s)
-                .map_err(WavOutputError::HoundError)
-        })
+    pub fn write_samples(&mut self, samples: &SampleBuffer) -> Result<(), WavOutputError> {
+        let writer = &mut self.writer;
+        match samples {
+            SampleBuffer::I8(samples) => samples.iter().try_for_each(|&s| writer.write_sample(s)),
+            SampleBuffer::I16(samples) => samples.iter().try_for_each(|&s| writer.write_sample(s)),
+            SampleBuffer::I24(samples) | SampleBuffer::I32(samples) => {
+                samples.iter().try_for_each(|&s| writer.write_sample(s))
+            }
+            SampleBuffer::F32(samples) => samples.iter().try_for_each(|&s| writer.write_sample(s)),
+        }
+        .map_err(WavOutputError::HoundError)
+    }
+    pub fn flush(&mut self) -> Result<(), WavOutputError> {
+        self.writer.flush().map_err(WavOutputError::HoundError)
     }
     pub fn finalize(self) -> Result<(), WavOutputError> {
         self.writer.finalize().map_err(WavOutputError::HoundError)
@@ -99,7 +112,8 @@ impl SpeakerOutputBuilder {
         self.device_name = Some(name.to_string());
         self
     }
-    pub fn build(&mut self) -> Result<SpeakerOutput, SpeakerOutputError> {
+    /// Opens the device for a stream described by `spec`.
+    pub fn build(&mut self, spec: &WavSpec) -> Result<SpeakerOutput, SpeakerOutputError> {
         let device = match self.device_name.as_deref() {
             None => self
                 .host
@@ -129,42 +143,63 @@ impl SpeakerOutputBuilder {
         let config: StreamConfig = supported_config.into();
         debug!("Default output config: {:?}", config);
 
-        // 10-second buffer at 44.1k mono
-        let rb = HeapRb::<i16>::new(44100 * 2 * 10);
+        let mixer = ChannelMixer::new(spec.channels as usize, config.channels as usize);
+        debug!(
+            "Mapping {} stream channels onto {} device channels",
+            mixer.source_channels(),
+            mixer.output_channels()
+        );
+
+        // 10-second buffer of interleaved stream samples
+        let rb = HeapRb::<f32>::new(spec.sample_rate as usize * mixer.source_channels() * 10);
         let (producer, consumer) = rb.split();
+        let resampler = if spec.sample_rate != config.sample_rate.0 {
+            debug!(
+                "Resampling stream from {} Hz to {} Hz",
+                spec.sample_rate, config.sample_rate.0
+            );
+            Some(Resampler::new(
+                spec.sample_rate,
+                config.sample_rate.0,
+                mixer.source_channels(),
+            ))
+        } else {
+            None
+        };
+        let playback = Playback::new(consumer, resampler, mixer);
 
         let err_fn = |err| error!("Stream error: {}", err);
 
         // Build stream using a single fill function
         let stream = match sample_format {
             SampleFormat::F32 => {
-                let mut cons = consumer;
+                let mut playback = playback;
                 device
                     .build_output_stream(
                         &config,
-                        move |out: &mut [f32], info| fill_from_consumer(&mut cons, out, info),
+                        move |out: &mut [f32], info| playback.fill(out, info),
                         err_fn,
                         None,
                     )
                     .map_err(SpeakerOutputError::StreamBuildFailed)?
             }
             SampleFormat::I16 => {
-                let mut cons = consumer;
+                let mut playback = playback;
                 device
                     .build_output_stream(
                         &config,
-                        move |out: &mut [i16], info| fill_from_consumer(&mut cons, out, info),
+                        move |out: &mut [i16], info| playback.fill(out, info),
                         err_fn,
                         None,
                     )
                     .map_err(SpeakerOutputError::StreamBuildFailed)?
             }
             SampleFormat::U16 => {
-                let mut cons = consumer;
+                let mut playback = playback;
                 device
                     .build_output_stream(
                         &config,
-                        move |out: &mut [u16], info| fill_from_consumer(&mut cons, out, info),
+                        move |out: &mut [u16], info| playback.fill(out, info),
                         err_fn,
                         None,
                     )
@@ -176,16 +211,29 @@ impl SpeakerOutputBuilder {
             .play()
             .map_err(SpeakerOutputError::StreamPlayFailed)?;
 
-        Ok(SpeakerOutput { stream, producer })
+        Ok(SpeakerOutput {
+            stream,
+            producer,
+            conversion_buffer: Vec::new(),
+        })
     }
 }
 pub struct SpeakerOutput {
     stream: Stream,
-    producer: HeapProd<i16>,
+    producer: HeapProd<f32>,
+    conversion_buffer: Vec<f32>,
 }
 
 impl SpeakerOutput {
-    pub fn play_samples(&mut self, samples: &[i16]) -> usize {
+    pub fn play_samples(&mut self, samples: &SampleBuffer) -> usize {
+        let mut converted = std::mem::take(&mut self.conversion_buffer);
+        converted.clear();
+        samples.extend_f32(&mut converted);
+        let pushed_count = self.play_f32_samples(&converted);
+        self.conversion_buffer = converted;
+        pushed_count
+    }
+    fn play_f32_samples(&mut self, samples: &[f32]) -> usize {
         while self.producer.vacant_len() < samples.len() {}
         let pushed_count = self.producer.push_slice(samples);
         if pushed_count < samples.len() {
@@ -196,13 +244,19 @@ impl SpeakerOutput {
         }
         pushed_count
     }
-    pub fn play_sample(&mut self, sample: i16) -> Result<(), i16> {
+    pub fn play_sample(&mut self, sample: f32) -> Result<(), f32> {
         while self.producer.is_full() {}
         match self.producer.try_push(sample) {
             Ok(_) => Ok(()),
             Err(s) => Err(s),
         }
     }
+    /// Blocks until the audio callback consumed every queued sample.
+    pub fn drain(&self) {
+        while !self.producer.is_empty() {
+            sleep(Duration::from_millis(10));
+        }
+    }
     pub fn pause(&self) -> Result<(), SpeakerOutputError> {
         self.stream
             .pause()
@@ -215,15 +269,355 @@ impl SpeakerOutput {
     }
 }
 
-/// Single shared callback implementation
-fn fill_from_consumer<T>(consumer: &mut HeapCons<i16>, out: &mut [T], _info: &OutputCallbackInfo)
-where
-    T: Sample + cpal::FromSample<i16>,
-{
-    for frame in out.chunks_mut(2) {
-        let s = consumer.try_pop().unwrap_or(Sample::EQUILIBRIUM);
-        for sample in frame {
-            *sample = Sample::from_sample::<i16>(s);
+/// State owned by the audio callback.
+struct Playback {
+    consumer: HeapCons<f32>,
+    /// Set when the stream and the device run at different rates.
+    resampler: Option<Resampler>,
+    mixer: ChannelMixer,
+    source_frame: Vec<f32>,
+    resampled_frame: Vec<f32>,
+    output_frame: Vec<f32>,
+}
+
+impl Playback {
+    fn new(consumer: HeapCons<f32>, resampler: Option<Resampler>, mixer: ChannelMixer) -> Self {
+        Playback {
+            consumer,
+            resampler,
+            source_frame: vec![0.0; mixer.source_channels()],
+            resampled_frame: vec![0.0; mixer.source_channels()],
+            output_frame: vec![0.0; mixer.output_channels()],
+            mixer,
+        }
+    }
+
+    /// Pops the next stream frame into `source_frame`, or silence on underrun.
+    fn pop_source_frame(&mut self) {
+        // Only pop whole frames so channels never get shifted
+        if self.consumer.occupied_len() >= self.source_frame.len() {
+            self.consumer.pop_slice(&mut self.source_frame);
+        } else {
+            self.source_frame.fill(0.0);
+        }
+    }
+
+    /// Single shared callback implementation
+    fn fill<T>(&mut self, out: &mut [T], _info: &OutputCallbackInfo)
+    where
+        T: Sample + cpal::FromSample<f32>,
+    {
+        for frame in out.chunks_mut(self.mixer.output_channels()) {
+            let stream_frame = match self.resampler.take() {
+                Some(mut resampler) => {
+                    while resampler.input_frames_needed() > 0 {
+                        self.pop_source_frame();
+                        resampler.push_frame(&self.source_frame);
+                    }
+                    resampler.next_frame(&mut self.resampled_frame);
+                    self.resampler = Some(resampler);
+                    &self.resampled_frame
+                }
+                None => {
+                    self.pop_source_frame();
+                    &self.source_frame
+                }
+            };
+            self.mixer.mix_frame(stream_frame, &mut self.output_frame);
+            for (sample, &value) in frame.iter_mut().zip(&self.output_frame) {
+                *sample = Sample::from_sample::<f32>(value);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AudioSinkError {
+    WavOutput(WavOutputError),
+    SpeakerOutput(SpeakerOutputError),
+}
+impl fmt::Display for AudioSinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioSinkError::WavOutput(e) => write!(f, "WAV output error: {}", e),
+            AudioSinkError::SpeakerOutput(e) => write!(f, "Speaker output error: {:?}", e),
+        }
+    }
+}
+
+/// Destination for a received stream: a file, a speaker, or nothing at all.
+pub trait AudioSink {
+    /// Prepares the sink for a stream described by `spec`. Called again if the
+    /// spec is announced again, a sink may keep its state when it didn't change.
+    fn configure(&mut self, spec: &WavSpec) -> Result<(), AudioSinkError>;
+
+    /// Writes samples in the format of the configured spec. Samples received before
+    /// any spec are dropped.
+    fn write_samples(&mut self, samples: &SampleBuffer) -> Result<(), AudioSinkError>;
+
+    /// Blocks until everything written so far has been output.
+    fn flush(&mut self) -> Result<(), AudioSinkError> {
+        Ok(())
+    }
+
+    /// Closes the current stream, the sink needs a new spec before more samples.
+    fn finalize(&mut self) -> Result<(), AudioSinkError>;
+}
+
+/// Writes the stream to a WAV file, created once the spec is known.
+pub struct WavFileSink {
+    path: String,
+    spec: Option<WavSpec>,
+    output: Option<WavAudioOutput>,
+}
+
+impl WavFileSink {
+    pub fn new(path: &str) -> Self {
+        WavFileSink {
+            path: path.to_string(),
+            spec: None,
+            output: None,
+        }
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn configure(&mut self, spec: &WavSpec) -> Result<(), AudioSinkError> {
+        if self.output.is_some() && self.spec == Some(*spec) {
+            return Ok(());
+        }
+        self.finalize()?;
+        let output = WavAudioOutput::new(&self.path, *spec).map_err(AudioSinkError::WavOutput)?;
+        self.output = Some(output);
+        self.spec = Some(*spec);
+        Ok(())
+    }
+    fn write_samples(&mut self, samples: &SampleBuffer) -> Result<(), AudioSinkError> {
+        match self.output.as_mut() {
+            Some(output) => output
+                .write_samples(samples)
+                .map_err(AudioSinkError::WavOutput),
+            None => Ok(()),
+        }
+    }
+    fn flush(&mut self) -> Result<(), AudioSinkError> {
+        match self.output.as_mut() {
+            Some(output) => output.flush().map_err(AudioSinkError::WavOutput),
+            None => Ok(()),
         }
     }
+    fn finalize(&mut self) -> Result<(), AudioSinkError> {
+        match self.output.take() {
+            Some(output) => {
+                output.finalize().map_err(AudioSinkError::WavOutput)?;
+                info!("WAV file {} finalized", self.path);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Plays the stream on a speaker, opened once the spec is known.
+pub struct SpeakerSink {
+    builder: SpeakerOutputBuilder,
+    spec: Option<WavSpec>,
+    output: Option<SpeakerOutput>,
+}
+
+impl SpeakerSink {
+    pub fn new(builder: SpeakerOutputBuilder) -> Self {
+        SpeakerSink {
+            builder,
+            spec: None,
+            output: None,
+        }
+    }
+}
+
+impl AudioSink for SpeakerSink {
+    fn configure(&mut self, spec: &WavSpec) -> Result<(), AudioSinkError> {
+        if self.output.is_some() && self.spec == Some(*spec) {
+            return Ok(());
+        }
+        self.finalize()?;
+        let output = self
+            .builder
+            .build(spec)
+            .map_err(AudioSinkError::SpeakerOutput)?;
+        self.output = Some(output);
+        self.spec = Some(*spec);
+        Ok(())
+    }
+    fn write_samples(&mut self, samples: &SampleBuffer) -> Result<(), AudioSinkError> {
+        if let Some(output) = self.output.as_mut() {
+            output.play_samples(samples);
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), AudioSinkError> {
+        if let Some(output) = self.output.as_ref() {
+            output.drain();
+        }
+        Ok(())
+    }
+    fn finalize(&mut self) -> Result<(), AudioSinkError> {
+        match self.output.take() {
+            Some(output) => output.pause().map_err(AudioSinkError::SpeakerOutput),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Discards the stream, keeping count of what went through it.
+#[derive(Debug, Default)]
+pub struct NullSink {
+    spec: Option<WavSpec>,
+    samples_written: u64,
+}
+
+impl NullSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn spec(&self) -> Option<WavSpec> {
+        self.spec
+    }
+    pub fn samples_written(&self) -> u64 {
+        self.samples_written
+    }
+}
+
+impl AudioSink for NullSink {
+    fn configure(&mut self, spec: &WavSpec) -> Result<(), AudioSinkError> {
+        self.spec = Some(*spec);
+        Ok(())
+    }
+    fn write_samples(&mut self, samples: &SampleBuffer) -> Result<(), AudioSinkError> {
+        if self.spec.is_some() {
+            self.samples_written += samples.len() as u64;
+        }
+        Ok(())
+    }
+    fn finalize(&mut self) -> Result<(), AudioSinkError> {
+        self.spec = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AudioSink, NullSink, WavAudioOutput, WavFileSink};
+    use crate::audio::{
+        AudioMessage, AudioSource, PcmFormat, SampleBuffer, SamplesFrame, Serializable,
+        WavAudioInput,
+    };
+    use hound::{SampleFormat, WavSpec};
+
+    fn spec(bits_per_sample: u16, sample_format: SampleFormat) -> WavSpec {
+        WavSpec {
+            channels: 2,
+            sample_rate: 22_050,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+
+    #[test]
+    fn wav_streams_bit_exactly_for_every_format() {
+        let cases = [
+            (
+                spec(8, SampleFormat::Int),
+                SampleBuffer::I8(vec![i8::MIN, -1, 0, 1, 64, i8::MAX]),
+            ),
+            (
+                spec(16, SampleFormat::Int),
+                SampleBuffer::I16(vec![i16::MIN, -1, 0, 1, 1_000, i16::MAX]),
+            ),
+            (
+                spec(24, SampleFormat::Int),
+                SampleBuffer::I24(vec![-8_388_608, -1, 0, 1, 100_000, 8_388_607]),
+            ),
+            (
+                spec(32, SampleFormat::Int),
+                SampleBuffer::I32(vec![i32::MIN, -1, 0, 1, 1 << 20, i32::MAX]),
+            ),
+            (
+                spec(32, SampleFormat::Float),
+                SampleBuffer::F32(vec![-1.0, -0.25, 0.0, 1e-7, 0.5, 1.0]),
+            ),
+        ];
+        let dir = std::env::temp_dir();
+        for (spec, samples) in cases {
+            let format = PcmFormat::from_spec(&spec).expect("unsupported spec");
+            let input_path = dir.join(format!("sonos-input-{:?}.wav", format));
+            let output_path = dir.join(format!("sonos-output-{:?}.wav", format));
+            let input_path = input_path.to_str().unwrap();
+            let output_path = output_path.to_str().unwrap();
+
+            let mut source = WavAudioOutput::new(input_path, spec).expect("create input");
+            source.write_samples(&samples).expect("write input");
+            source.finalize().expect("finalize input");
+
+            let mut input = WavAudioInput::init(input_path).expect("open input");
+            let mut read = SampleBuffer::new(format);
+            input.get_all_samples(&mut read).expect("read input");
+
+            let mut bytes = Vec::new();
+            AudioMessage::Samples(SamplesFrame {
+                sequence: 0,
+                position: 0,
+                samples: read,
+            })
+            .serialize(&mut bytes)
+            .expect("serialize failed");
+            let Ok(AudioMessage::Samples(frame)) = AudioMessage::deserialize(&bytes) else {
+                panic!("deserialize failed");
+            };
+
+            let mut output = WavAudioOutput::new(output_path, spec).expect("create output");
+            output.write_samples(&frame.samples).expect("write output");
+            output.finalize().expect("finalize output");
+
+            let mut written = WavAudioInput::init(output_path).expect("open output");
+            assert_eq!(written.get_spec(), spec);
+            let mut result = SampleBuffer::new(format);
+            written.get_all_samples(&mut result).expect("read output");
+            assert_eq!(result, samples);
+        }
+    }
+
+    #[test]
+    fn sinks_keep_their_stream_across_repeated_specs() {
+        let spec = spec(16, SampleFormat::Int);
+        let path = std::env::temp_dir().join("sonos-sink.wav");
+        let path = path.to_str().unwrap();
+
+        let mut wav = WavFileSink::new(path);
+        // Samples before the spec have nowhere to go
+        wav.write_samples(&SampleBuffer::I16(vec![9; 4]))
+            .expect("write before spec");
+        wav.configure(&spec).expect("configure");
+        wav.write_samples(&SampleBuffer::I16(vec![1, 2]))
+            .expect("write samples");
+        wav.configure(&spec).expect("configure again");
+        wav.write_samples(&SampleBuffer::I16(vec![3, 4]))
+            .expect("write samples");
+        wav.flush().expect("flush");
+        wav.finalize().expect("finalize");
+
+        let mut written = WavAudioInput::init(path).expect("open output");
+        let mut result = SampleBuffer::new(PcmFormat::I16);
+        written.get_all_samples(&mut result).expect("read output");
+        assert_eq!(result, SampleBuffer::I16(vec![1, 2, 3, 4]));
+
+        let mut null = NullSink::new();
+        null.write_samples(&SampleBuffer::I16(vec![0; 4]))
+            .expect("write before spec");
+        null.configure(&spec).expect("configure");
+        null.write_samples(&SampleBuffer::I16(vec![0; 6]))
+            .expect("write samples");
+        assert_eq!(null.spec(), Some(spec));
+        assert_eq!(null.samples_written(), 6);
+    }
 }
@@ -0,0 +1,95 @@
+use crate::audio::message::SamplesFrame;
+use std::collections::VecDeque;
+
+/// The most recent frames of a stream, kept so that a client which lost its connection
+/// can pick up where it left off.
+#[derive(Debug)]
+pub struct ReplayWindow {
+    frames: VecDeque<SamplesFrame>,
+    /// Samples kept at most, counting every channel.
+    capacity: usize,
+    buffered: usize,
+    /// Length of the stream in samples, once it ended.
+    total_samples: Option<u64>,
+}
+
+impl ReplayWindow {
+    /// Window of `capacity` samples, counting every channel.
+    pub fn new(capacity: usize) -> Self {
+        ReplayWindow {
+            frames: VecDeque::new(),
+            capacity,
+            buffered: 0,
+            total_samples: None,
+        }
+    }
+
+    /// Keeps `frame`, forgetting the oldest frames that no longer fit.
+    pub fn push(&mut self, frame: SamplesFrame) {
+        self.buffered += frame.samples.len();
+        self.frames.push_back(frame);
+        while self.buffered > self.capacity
+            && let Some(oldest) = self.frames.pop_front()
+        {
+            self.buffered -= oldest.samples.len();
+        }
+    }
+
+    /// Marks the end of the stream, to be replayed after the last frame.
+    pub fn end(&mut self, total_samples: u64) {
+        self.total_samples = Some(total_samples);
+    }
+
+    /// Length of the stream, `None` until it ended.
+    pub fn total_samples(&self) -> Option<u64> {
+        self.total_samples
+    }
+
+    /// Position right after the newest frame, 0 before the first one.
+    pub fn end_position(&self) -> u64 {
+        self.frames
+            .back()
+            .map_or(0, |frame| frame.position + frame.samples.len() as u64)
+    }
+
+    /// Frames starting at or after `position`. They start later than that when the
+    /// frames in between were already forgotten.
+    pub fn since(&self, position: u64) -> impl Iterator<Item = &SamplesFrame> {
+        let first = self
+            .frames
+            .partition_point(|frame| frame.position < position);
+        self.frames.range(first..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplayWindow;
+    use crate::audio::message::SamplesFrame;
+    use crate::audio::samples::SampleBuffer;
+
+    fn positions<'a>(frames: impl Iterator<Item = &'a SamplesFrame>) -> Vec<u64> {
+        frames.map(|frame| frame.position).collect()
+    }
+
+    #[test]
+    fn keeps_the_latest_frames() {
+        let mut window = ReplayWindow::new(250);
+        assert_eq!(window.end_position(), 0);
+        for sequence in 0..5 {
+            window.push(SamplesFrame {
+                sequence,
+                position: sequence * 100,
+                samples: SampleBuffer::I16(vec![0; 100]),
+            });
+        }
+        assert_eq!(positions(window.since(0)), [300, 400]);
+        assert_eq!(window.end_position(), 500);
+        assert_eq!(positions(window.since(400)), [400]);
+        assert_eq!(positions(window.since(350)), [400]);
+        assert_eq!(positions(window.since(500)), [] as [u64; 0]);
+        assert_eq!(window.total_samples(), None);
+        window.end(500);
+        assert_eq!(window.total_samples(), Some(500));
+    }
+}
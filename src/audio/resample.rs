@@ -0,0 +1,196 @@
+use std::f64::consts::PI;
+
+/// Number of input frames on each side of the interpolated point.
+const HALF_TAPS: usize = 32;
+const TAPS: usize = HALF_TAPS * 2;
+/// Number of fractional positions the filter is precomputed for.
+const PHASES: usize = 256;
+/// Fraction of the Nyquist frequency kept, the rest is the filter transition band.
+const PASSBAND: f64 = 0.95;
+/// Index of the window frame the output is interpolated from.
+const CENTER: usize = HALF_TAPS - 1;
+
+/// Streaming sample-rate converter using a polyphase windowed-sinc filter.
+///
+/// Frames are interleaved `f32` samples. Input is pushed one frame at a time, as
+/// requested by [`Resampler::input_frames_needed`], which lets an audio callback pull
+/// exactly what it needs from its buffer.
+pub struct Resampler {
+    channels: usize,
+    /// Input frames advanced for every output frame.
+    step: f64,
+    /// Position of the next output frame between `CENTER` and `CENTER + 1`.
+    fraction: f64,
+    pending_frames: usize,
+    /// Last `TAPS` input frames, interleaved.
+    window: Vec<f32>,
+    /// `PHASES + 1` rows of `TAPS` coefficients.
+    filter: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32, channels: usize) -> Self {
+        let channels = channels.max(1);
+        let step = input_rate as f64 / output_rate as f64;
+        // Below the lowest of both Nyquist frequencies, relative to the input rate
+        let cutoff = PASSBAND * (output_rate as f64 / input_rate as f64).min(1.0);
+        let mut filter = Vec::with_capacity((PHASES + 1) * TAPS);
+        for phase in 0..=PHASES {
+            let fraction = phase as f64 / PHASES as f64;
+            let row: Vec<f64> = (0..TAPS)
+                .map(|tap| kernel(tap as f64 - CENTER as f64 - fraction, cutoff))
+                .collect();
+            // Unity gain at DC for every phase
+            let sum: f64 = row.iter().sum();
+            filter.extend(row.iter().map(|c| (c / sum) as f32));
+        }
+        Resampler {
+            channels,
+            step,
+            fraction: 0.0,
+            // The first input frame must reach the center before the first output frame
+            pending_frames: TAPS - CENTER,
+            window: vec![0.0; TAPS * channels],
+            filter,
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Input frames to push before the next output frame is available.
+    pub fn input_frames_needed(&self) -> usize {
+        self.pending_frames
+    }
+
+    pub fn push_frame(&mut self, frame: &[f32]) {
+        self.window.copy_within(self.channels.., 0);
+        let start = self.window.len() - self.channels;
+        self.window[start..].copy_from_slice(&frame[..self.channels]);
+        self.pending_frames = self.pending_frames.saturating_sub(1);
+    }
+
+    /// Computes the next output frame, once [`Resampler::input_frames_needed`] is 0.
+    pub fn next_frame(&mut self, out: &mut [f32]) {
+        let position = self.fraction * PHASES as f64;
+        let phase = (position as usize).min(PHASES - 1);
+        let weight = (position - phase as f64) as f32;
+        let low = &self.filter[phase * TAPS..(phase + 1) * TAPS];
+        let high = &self.filter[(phase + 1) * TAPS..(phase + 2) * TAPS];
+        for (channel, sample) in out.iter_mut().enumerate().take(self.channels) {
+            let mut low_sum = 0.0;
+            let mut high_sum = 0.0;
+            for tap in 0..TAPS {
+                let input = self.window[tap * self.channels + channel];
+                low_sum += low[tap] * input;
+                high_sum += high[tap] * input;
+            }
+            *sample = low_sum + (high_sum - low_sum) * weight;
+        }
+        self.fraction += self.step;
+        let advance = self.fraction.floor();
+        self.fraction -= advance;
+        self.pending_frames += advance as usize;
+    }
+
+    /// Resamples a whole interleaved buffer, appending every frame that can be
+    /// produced so far to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let mut frame = vec![0.0; self.channels];
+        let mut frames = input.chunks_exact(self.channels);
+        loop {
+            while self.pending_frames > 0 {
+                match frames.next() {
+                    Some(input_frame) => self.push_frame(input_frame),
+                    None => return,
+                }
+            }
+            self.next_frame(&mut frame);
+            output.extend_from_slice(&frame);
+        }
+    }
+}
+
+/// Low-pass sinc for a `cutoff` relative to the input Nyquist frequency,
+/// shaped by a Blackman window spanning `HALF_TAPS` frames on each side.
+fn kernel(x: f64, cutoff: f64) -> f64 {
+    if x.abs() >= HALF_TAPS as f64 {
+        return 0.0;
+    }
+    let sinc = if x == 0.0 {
+        1.0
+    } else {
+        (PI * cutoff * x).sin() / (PI * cutoff * x)
+    };
+    let t = PI * x / HALF_TAPS as f64;
+    let window = 0.42 + 0.5 * t.cos() + 0.08 * (2.0 * t).cos();
+    cutoff * sinc * window
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HALF_TAPS, Resampler};
+    use std::f64::consts::PI;
+
+    fn sine(frequency: f64, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|n| (2.0 * PI * frequency * n as f64 / rate as f64).sin() as f32 * 0.5)
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f64 {
+        let sum: f64 = samples.iter().map(|&s| (s as f64) * (s as f64)).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn upsampled_sine_matches_reference() {
+        let (input_rate, output_rate) = (22_050, 48_000);
+        let input = sine(1_000.0, input_rate, input_rate as usize);
+        let mut resampler = Resampler::new(input_rate, output_rate, 1);
+        let mut output = Vec::new();
+        resampler.process(&input, &mut output);
+
+        let expected_frames = input.len() * output_rate as usize / input_rate as usize;
+        assert!(output.len().abs_diff(expected_frames) <= HALF_TAPS * 3);
+        let reference = sine(1_000.0, output_rate, output.len());
+        // Skip the edges, where the filter sees the zeros outside the signal
+        let edge = HALF_TAPS * 3;
+        let max_error = output[edge..output.len() - edge]
+            .iter()
+            .zip(&reference[edge..])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(max_error < 1e-3, "max error {}", max_error);
+    }
+
+    #[test]
+    fn downsampling_removes_frequencies_above_nyquist() {
+        let (input_rate, output_rate) = (48_000, 22_050);
+        let mut resampler = Resampler::new(input_rate, output_rate, 1);
+        let mut passed = Vec::new();
+        resampler.process(&sine(1_000.0, input_rate, 48_000), &mut passed);
+        let mut resampler = Resampler::new(input_rate, output_rate, 1);
+        let mut rejected = Vec::new();
+        resampler.process(&sine(15_000.0, input_rate, 48_000), &mut rejected);
+
+        let edge = HALF_TAPS * 2;
+        let passed_rms = rms(&passed[edge..passed.len() - edge]);
+        let rejected_rms = rms(&rejected[edge..rejected.len() - edge]);
+        assert!((passed_rms - 0.5 / 2f64.sqrt()).abs() < 1e-3);
+        assert!(rejected_rms < 1e-3, "aliasing rms {}", rejected_rms);
+    }
+
+    #[test]
+    fn channels_stay_separate() {
+        let left = sine(440.0, 44_100, 4_410);
+        let input: Vec<f32> = left.iter().flat_map(|&s| [s, 0.0]).collect();
+        let mut resampler = Resampler::new(44_100, 48_000, 2);
+        let mut output = Vec::new();
+        resampler.process(&input, &mut output);
+
+        assert!(rms(&output.iter().step_by(2).copied().collect::<Vec<_>>()) > 0.3);
+        assert!(output.iter().skip(1).step_by(2).all(|&s| s == 0.0));
+    }
+}
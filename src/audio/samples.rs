@@ -0,0 +1,164 @@
+use crate::audio::message::PcmFormat;
+
+/// Interleaved samples, typed by their PCM format.
+///
+/// 24-bit samples are stored sign-extended in an `i32` and must fit in 24 bits.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SampleBuffer {
+    I8(Vec<i8>),
+    I16(Vec<i16>),
+    I24(Vec<i32>),
+    I32(Vec<i32>),
+    F32(Vec<f32>),
+}
+
+impl PcmFormat {
+    /// Size of one sample on the wire.
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            PcmFormat::I8 => 1,
+            PcmFormat::I16 => 2,
+            PcmFormat::I24 => 3,
+            PcmFormat::I32 | PcmFormat::F32 => 4,
+        }
+    }
+}
+
+impl SampleBuffer {
+    pub fn new(format: PcmFormat) -> Self {
+        Self::with_capacity(format, 0)
+    }
+    pub fn with_capacity(format: PcmFormat, capacity: usize) -> Self {
+        match format {
+            PcmFormat::I8 => SampleBuffer::I8(Vec::with_capacity(capacity)),
+            PcmFormat::I16 => SampleBuffer::I16(Vec::with_capacity(capacity)),
+            PcmFormat::I24 => SampleBuffer::I24(Vec::with_capacity(capacity)),
+            PcmFormat::I32 => SampleBuffer::I32(Vec::with_capacity(capacity)),
+            PcmFormat::F32 => SampleBuffer::F32(Vec::with_capacity(capacity)),
+        }
+    }
+    /// `len` samples of silence.
+    pub fn silence(format: PcmFormat, len: usize) -> Self {
+        match format {
+            PcmFormat::I8 => SampleBuffer::I8(vec![0; len]),
+            PcmFormat::I16 => SampleBuffer::I16(vec![0; len]),
+            PcmFormat::I24 => SampleBuffer::I24(vec![0; len]),
+            PcmFormat::I32 => SampleBuffer::I32(vec![0; len]),
+            PcmFormat::F32 => SampleBuffer::F32(vec![0.0; len]),
+        }
+    }
+    pub fn format(&self) -> PcmFormat {
+        match self {
+            SampleBuffer::I8(_) => PcmFormat::I8,
+            SampleBuffer::I16(_) => PcmFormat::I16,
+            SampleBuffer::I24(_) => PcmFormat::I24,
+            SampleBuffer::I32(_) => PcmFormat::I32,
+            SampleBuffer::F32(_) => PcmFormat::F32,
+        }
+    }
+    pub fn len(&self) -> usize {
+        match self {
+            SampleBuffer::I8(samples) => samples.len(),
+            SampleBuffer::I16(samples) => samples.len(),
+            SampleBuffer::I24(samples) | SampleBuffer::I32(samples) => samples.len(),
+            SampleBuffer::F32(samples) => samples.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn clear(&mut self) {
+        match self {
+            SampleBuffer::I8(samples) => samples.clear(),
+            SampleBuffer::I16(samples) => samples.clear(),
+            SampleBuffer::I24(samples) | SampleBuffer::I32(samples) => samples.clear(),
+            SampleBuffer::F32(samples) => samples.clear(),
+        }
+    }
+
+    /// Moves up to `max` samples from the front of this buffer to the end of `out`.
+    /// Returns how many were moved, 0 if the formats differ.
+    pub(crate) fn move_front(&mut self, out: &mut SampleBuffer, max: usize) -> usize {
+        let count = self.len().min(max);
+        match (self, out) {
+            (SampleBuffer::I8(from), SampleBuffer::I8(to)) => to.extend(from.drain(..count)),
+            (SampleBuffer::I16(from), SampleBuffer::I16(to)) => to.extend(from.drain(..count)),
+            (SampleBuffer::I24(from), SampleBuffer::I24(to))
+            | (SampleBuffer::I32(from), SampleBuffer::I32(to)) => to.extend(from.drain(..count)),
+            (SampleBuffer::F32(from), SampleBuffer::F32(to)) => to.extend(from.drain(..count)),
+            _ => return 0,
+        }
+        count
+    }
+
+    /// Appends every sample, scaled to `[-1.0, 1.0]`, to `out`.
+    pub fn extend_f32(&self, out: &mut Vec<f32>) {
+        match self {
+            SampleBuffer::I8(samples) => out.extend(samples.iter().map(|&s| s as f32 / 128.0)),
+            SampleBuffer::I16(samples) => out.extend(samples.iter().map(|&s| s as f32 / 32_768.0)),
+            SampleBuffer::I24(samples) => {
+                out.extend(samples.iter().map(|&s| s as f32 / 8_388_608.0))
+            }
+            SampleBuffer::I32(samples) => {
+                out.extend(samples.iter().map(|&s| s as f32 / 2_147_483_648.0))
+            }
+            SampleBuffer::F32(samples) => out.extend_from_slice(samples),
+        }
+    }
+
+    /// Appends every sample, scaled to 16 bits, to `out`.
+    pub fn extend_i16(&self, out: &mut Vec<i16>) {
+        match self {
+            SampleBuffer::I8(samples) => out.extend(samples.iter().map(|&s| (s as i16) << 8)),
+            SampleBuffer::I16(samples) => out.extend_from_slice(samples),
+            SampleBuffer::I24(samples) => out.extend(samples.iter().map(|&s| (s >> 8) as i16)),
+            SampleBuffer::I32(samples) => out.extend(samples.iter().map(|&s| (s >> 16) as i16)),
+            SampleBuffer::F32(samples) => out.extend(
+                samples
+                    .iter()
+                    .map(|&s| (s * 32_768.0).round().clamp(-32_768.0, 32_767.0) as i16),
+            ),
+        }
+    }
+
+    /// Appends the little-endian encoding of every sample to `buf`.
+    pub(crate) fn write_le(&self, buf: &mut Vec<u8>) {
+        match self {
+            SampleBuffer::I8(samples) => buf.extend(samples.iter().map(|&s| s as u8)),
+            SampleBuffer::I16(samples) => buf.extend(samples.iter().flat_map(|s| s.to_le_bytes())),
+            SampleBuffer::I24(samples) => buf.extend(samples.iter().flat_map(|s| {
+                let [b0, b1, b2, _] = s.to_le_bytes();
+                [b0, b1, b2]
+            })),
+            SampleBuffer::I32(samples) => buf.extend(samples.iter().flat_map(|s| s.to_le_bytes())),
+            SampleBuffer::F32(samples) => buf.extend(samples.iter().flat_map(|s| s.to_le_bytes())),
+        }
+    }
+
+    /// Decodes little-endian samples, `bytes` must hold a whole number of samples.
+    pub(crate) fn read_le(format: PcmFormat, bytes: &[u8]) -> Self {
+        let chunks = bytes.chunks_exact(format.bytes_per_sample());
+        match format {
+            PcmFormat::I8 => SampleBuffer::I8(chunks.map(|c| c[0] as i8).collect()),
+            PcmFormat::I16 => {
+                SampleBuffer::I16(chunks.map(|c| i16::from_le_bytes([c[0], c[1]])).collect())
+            }
+            // Shifting the bytes to the top of the i32 and back sign-extends the value
+            PcmFormat::I24 => SampleBuffer::I24(
+                chunks
+                    .map(|c| i32::from_le_bytes([0, c[0], c[1], c[2]]) >> 8)
+                    .collect(),
+            ),
+            PcmFormat::I32 => SampleBuffer::I32(
+                chunks
+                    .map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect(),
+            ),
+            PcmFormat::F32 => SampleBuffer::F32(
+                chunks
+                    .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect(),
+            ),
+        }
+    }
+}
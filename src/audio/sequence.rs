@@ -0,0 +1,106 @@
+use crate::audio::message::SamplesFrame;
+
+/// Where a received frame falls relative to the ones seen before it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FrameOrder {
+    /// First frame of the connection, `position` tells where in the stream we joined.
+    First {
+        position: u64,
+    },
+    InOrder,
+    /// Frames were lost between the previous frame and this one.
+    Gap {
+        missing_frames: u64,
+        missing_samples: u64,
+    },
+    /// Duplicate or late frame, older than the last one accepted.
+    Stale,
+}
+
+/// Follows the sequence numbers of incoming frames to detect lost or repeated data.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    next_sequence: Option<u64>,
+    next_position: u64,
+    lost_frames: u64,
+    lost_samples: u64,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, frame: &SamplesFrame) -> FrameOrder {
+        let order = match self.next_sequence {
+            None => FrameOrder::First {
+                position: frame.position,
+            },
+            Some(next) if frame.sequence < next => return FrameOrder::Stale,
+            Some(next) if frame.sequence == next => FrameOrder::InOrder,
+            Some(next) => {
+                let missing_frames = frame.sequence - next;
+                let missing_samples = frame.position.saturating_sub(self.next_position);
+                self.lost_frames += missing_frames;
+                self.lost_samples += missing_samples;
+                FrameOrder::Gap {
+                    missing_frames,
+                    missing_samples,
+                }
+            }
+        };
+        self.next_sequence = Some(frame.sequence + 1);
+        self.next_position = frame.position + frame.samples.len() as u64;
+        order
+    }
+
+    /// Position right after the last accepted frame.
+    pub fn next_position(&self) -> u64 {
+        self.next_position
+    }
+    pub fn lost_frames(&self) -> u64 {
+        self.lost_frames
+    }
+    pub fn lost_samples(&self) -> u64 {
+        self.lost_samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameOrder, SequenceTracker};
+    use crate::audio::message::SamplesFrame;
+    use crate::audio::samples::SampleBuffer;
+
+    fn frame(sequence: u64, position: u64, len: usize) -> SamplesFrame {
+        SamplesFrame {
+            sequence,
+            position,
+            samples: SampleBuffer::I16(vec![0; len]),
+        }
+    }
+
+    #[test]
+    fn detects_gaps_and_stale_frames() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(
+            tracker.observe(&frame(10, 5_000, 500)),
+            FrameOrder::First { position: 5_000 }
+        );
+        assert_eq!(tracker.observe(&frame(11, 5_500, 500)), FrameOrder::InOrder);
+        assert_eq!(
+            tracker.observe(&frame(14, 7_000, 500)),
+            FrameOrder::Gap {
+                missing_frames: 2,
+                missing_samples: 1_000,
+            }
+        );
+        assert_eq!(tracker.observe(&frame(12, 6_000, 500)), FrameOrder::Stale);
+        assert_eq!(tracker.observe(&frame(14, 7_000, 500)), FrameOrder::Stale);
+        assert_eq!(tracker.observe(&frame(15, 7_500, 500)), FrameOrder::InOrder);
+
+        assert_eq!(tracker.lost_frames(), 2);
+        assert_eq!(tracker.lost_samples(), 1_000);
+        assert_eq!(tracker.next_position(), 8_000);
+    }
+}
@@ -0,0 +1,108 @@
+//! WAV encoding of a stream whose length isn't known up front, e.g. one served over
+//! HTTP: a header declaring a WAV file as large as can be, then the samples.
+
+use crate::audio::samples::SampleBuffer;
+use hound::{SampleFormat, WavSpec};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+/// Size of the header up to the data chunk, counted in the size of the RIFF chunk.
+const RIFF_HEADER_SIZE: u32 = 36;
+
+/// Header of a WAV stream of samples in `spec`, followed by `write_wav_samples`.
+pub fn streaming_wav_header(spec: &WavSpec) -> Vec<u8> {
+    let format_tag = match spec.sample_format {
+        SampleFormat::Int => WAVE_FORMAT_PCM,
+        SampleFormat::Float => WAVE_FORMAT_IEEE_FLOAT,
+    };
+    let bytes_per_sample = spec.bits_per_sample.div_ceil(8);
+    let block_align = spec.channels * bytes_per_sample;
+    let byte_rate = spec.sample_rate * block_align as u32;
+    // The largest data chunk holding whole frames, players read until the stream ends
+    let block_align_bytes = block_align.max(1) as u32;
+    let data_size = (u32::MAX - RIFF_HEADER_SIZE) / block_align_bytes * block_align_bytes;
+
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(data_size + RIFF_HEADER_SIZE).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&format_tag.to_le_bytes());
+    header.extend_from_slice(&spec.channels.to_le_bytes());
+    header.extend_from_slice(&spec.sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&spec.bits_per_sample.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+/// Appends `samples` to `out` as they are laid out in the data chunk of a WAV file.
+pub fn write_wav_samples(samples: &SampleBuffer, out: &mut Vec<u8>) {
+    match samples {
+        // 8-bit WAV samples are unsigned
+        SampleBuffer::I8(samples) => out.extend(samples.iter().map(|&s| (s as u8) ^ 0x80)),
+        samples => samples.write_le(out),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{streaming_wav_header, write_wav_samples};
+    use crate::audio::samples::SampleBuffer;
+    use hound::{SampleFormat, WavReader, WavSpec};
+    use std::io::Cursor;
+
+    fn read_back<T: hound::Sample>(spec: WavSpec, samples: &SampleBuffer) -> (WavSpec, Vec<T>) {
+        let mut stream = streaming_wav_header(&spec);
+        write_wav_samples(samples, &mut stream);
+        let mut reader = WavReader::new(Cursor::new(stream)).expect("Invalid WAV header");
+        let read = reader
+            .samples::<T>()
+            .take(samples.len())
+            .collect::<Result<_, _>>()
+            .expect("Invalid samples");
+        (reader.spec(), read)
+    }
+
+    #[test]
+    fn players_read_the_stream() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44_100,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let samples = vec![1i16, -2, i16::MAX, i16::MIN];
+        assert_eq!(
+            read_back::<i16>(spec, &SampleBuffer::I16(samples.clone())),
+            (spec, samples)
+        );
+
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8_000,
+            bits_per_sample: 8,
+            sample_format: SampleFormat::Int,
+        };
+        let samples = vec![-128i8, -1, 0, 127];
+        assert_eq!(
+            read_back::<i8>(spec, &SampleBuffer::I8(samples.clone())),
+            (spec, samples)
+        );
+
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 48_000,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let samples = vec![0.5f32, -1.0];
+        assert_eq!(
+            read_back::<f32>(spec, &SampleBuffer::F32(samples.clone())),
+            (spec, samples)
+        );
+    }
+}
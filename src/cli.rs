@@ -3,5 +3,5 @@ mod parsers;
 mod server;
 
 pub use client::{ClientCli, ClientCliSubCommand};
-pub use parsers::{SpeakerDevice, WavFile};
+pub use parsers::{AudioFile, LagPolicy, SpeakerDevice, WavFile};
 pub use server::ServerCli;
@@ -1,23 +1,58 @@
+use crate::cli::parsers::{parse_multicast_group, parse_sdp_file};
 use crate::cli::{SpeakerDevice, WavFile};
+use crate::network::rtp::SdpDescription;
 use clap::Parser;
 use clap::{ArgGroup, Subcommand};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, subcommand_negates_reqs = true)]
-#[clap(group(ArgGroup::new("output").required(true).args(&["file", "speaker", "default_speaker"])))]
+#[clap(group(ArgGroup::new("output").required(true).args(&["file", "speaker", "default_speaker", "discard"])))]
 pub struct ClientCli {
     #[clap(subcommand)]
     pub command: Option<ClientCliSubCommand>,
 
     /// Port of the server
-    #[arg(short, long, required = true)]
+    #[arg(short, long, required_unless_present_any = ["unix", "rtp", "multicast"])]
     pub port: Option<u16>,
 
     /// IP address of the server
-    #[arg(long, value_parser = clap::value_parser!(IpAddr), required = true)]
+    #[arg(long, value_parser = clap::value_parser!(IpAddr), required_unless_present_any = ["unix", "rtp", "multicast"])]
     pub ip: Option<IpAddr>,
 
+    /// Unix domain socket of a server on the same host, instead of --ip and --port
+    #[arg(long, conflicts_with_all = ["ip", "port"])]
+    pub unix: Option<PathBuf>,
+
+    /// Receive the stream over UDP instead of TCP. Lost datagrams are concealed
+    #[arg(long, conflicts_with = "unix")]
+    pub udp: bool,
+
+    /// Receive an RTP stream described by this SDP file instead of connecting to a
+    /// server
+    #[arg(long, value_parser = parse_sdp_file, conflicts_with_all = ["ip", "port", "unix", "udp"])]
+    pub rtp: Option<SdpDescription>,
+
+    /// Join the stream a server sends to this multicast GROUP:PORT instead of connecting
+    /// to it
+    #[arg(long, value_parser = parse_multicast_group, conflicts_with_all = ["ip", "port", "unix", "udp", "rtp"])]
+    pub multicast: Option<SocketAddrV4>,
+
+    /// Address of the network interface to join the multicast group on, the routing
+    /// table picks one by default
+    #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED, requires = "multicast")]
+    pub multicast_interface: Ipv4Addr,
+
+    /// Frames held back over UDP, RTP or multicast to put late packets back in order
+    #[arg(long, default_value_t = 8)]
+    pub jitter_buffer: usize,
+
+    /// Seconds spent reconnecting to the server after losing it mid-stream, resuming
+    /// where the connection dropped. 0 gives up right away
+    #[arg(long, default_value_t = 10.0)]
+    pub reconnect_timeout: f64,
+
     #[clap(long, value_parser = clap::value_parser!(WavFile))]
     pub file: Option<WavFile>,
 
@@ -26,6 +61,10 @@ pub struct ClientCli {
 
     #[clap(short, long)]
     pub default_speaker: bool,
+
+    /// Receive the stream without saving or playing it
+    #[clap(long)]
+    pub discard: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -1,6 +1,9 @@
-use crate::audio::SpeakerOutputBuilder;
+use crate::audio::{Codec, SpeakerOutputBuilder, open_audio_file};
+use crate::network::QueuePolicy;
+use crate::network::rtp::SdpDescription;
 use std::fs;
 use std::io;
+use std::net::{SocketAddr, SocketAddrV4};
 use std::path::PathBuf;
 use std::str::FromStr;
 
@@ -74,12 +77,118 @@ impl FromStr for SpeakerDevice {
     }
 }
 
-pub fn parse_existing_wav(s: &str) -> Result<WavFile, String> {
-    let wav: WavFile = s.parse().map_err(|e: io::Error| e.to_string())?;
+/// Audio file to stream, in any format the server can read.
+#[derive(Debug, Clone)]
+pub struct AudioFile {
+    pub path: PathBuf,
+}
+
+/// Accepts any existing file whose content is a WAV or a format the decoder knows,
+/// regardless of its extension.
+pub fn parse_existing_audio(s: &str) -> Result<AudioFile, String> {
+    let path = PathBuf::from(s);
+    if !path.is_file() {
+        return Err(format!("File '{}' does not exist", path.display()));
+    }
+    if let Err(e) = open_audio_file(s) {
+        return Err(format!(
+            "File '{}' is not a supported audio file: {}",
+            path.display(),
+            e
+        ));
+    }
+    Ok(AudioFile { path })
+}
 
-    if !wav.path.exists() {
-        return Err(format!("File '{}' does not exist", wav.path.display()));
+/// Parses a codec name as given on the command line.
+pub fn parse_codec(s: &str) -> Result<Codec, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "pcm" => Ok(Codec::Pcm),
+        "lossless" => Ok(Codec::Lossless),
+        "ima-adpcm" | "adpcm" => Ok(Codec::ImaAdpcm),
+        "mu-law" | "ulaw" => Ok(Codec::MuLaw),
+        "a-law" | "alaw" => Ok(Codec::ALaw),
+        _ => Err(format!(
+            "Unknown codec '{}', expected one of pcm, lossless, ima-adpcm, mu-law, a-law",
+            s
+        )),
     }
+}
+
+/// Parses what to do with clients whose outgoing queue is full.
+pub fn parse_queue_policy(s: &str) -> Result<QueuePolicy, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "drop-oldest" => Ok(QueuePolicy::DropOldest),
+        "drop-client" => Ok(QueuePolicy::DropClient),
+        "block" => Ok(QueuePolicy::Block),
+        _ => Err(format!(
+            "Unknown queue policy '{}', expected one of drop-oldest, drop-client, block",
+            s
+        )),
+    }
+}
+
+/// What the server does with a client that falls too far behind.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LagPolicy {
+    /// Disconnect the client.
+    Evict,
+    /// Switch the client to a lower bandwidth codec if it supports one, else evict it.
+    Downgrade,
+}
+
+pub fn parse_lag_policy(s: &str) -> Result<LagPolicy, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "evict" => Ok(LagPolicy::Evict),
+        "downgrade" => Ok(LagPolicy::Downgrade),
+        _ => Err(format!(
+            "Unknown lag policy '{}', expected evict or downgrade",
+            s
+        )),
+    }
+}
+
+/// Parses where to send RTP packets. The port must be even, RTCP goes to the next one.
+pub fn parse_rtp_destination(s: &str) -> Result<SocketAddr, String> {
+    let address: SocketAddr = s
+        .parse()
+        .map_err(|_| format!("Invalid address '{}', expected IP:PORT", s))?;
+    if !address.port().is_multiple_of(2) {
+        return Err(format!(
+            "RTP port {} must be even, RTCP uses the next one",
+            address.port()
+        ));
+    }
+    Ok(address)
+}
+
+/// Parses the GROUP:PORT of an IPv4 multicast stream.
+pub fn parse_multicast_group(s: &str) -> Result<SocketAddrV4, String> {
+    let group: SocketAddrV4 = s
+        .parse()
+        .map_err(|_| format!("Invalid address '{}', expected GROUP:PORT", s))?;
+    if !group.ip().is_multicast() {
+        return Err(format!(
+            "{} is not an IPv4 multicast address (224.0.0.0/4)",
+            group.ip()
+        ));
+    }
+    Ok(group)
+}
+
+/// Parses the path of an Icecast mount point, e.g. `/radio`.
+pub fn parse_mount_point(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') || s.contains(char::is_whitespace) {
+        return Err(format!(
+            "Invalid mount point '{}', expected a path like /radio",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
 
-    Ok(wav)
+/// Reads the SDP file describing an RTP stream.
+pub fn parse_sdp_file(s: &str) -> Result<SdpDescription, String> {
+    let sdp = fs::read_to_string(s).map_err(|e| format!("Couldn't read '{}': {}", s, e))?;
+    SdpDescription::parse(&sdp).map_err(|e| format!("Invalid SDP file '{}': {}", s, e))
 }
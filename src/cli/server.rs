@@ -1,6 +1,14 @@
-use crate::cli::WavFile;
-use crate::cli::parsers::parse_existing_wav;
+use crate::audio::Codec;
+use crate::cli::AudioFile;
+use crate::cli::LagPolicy;
+use crate::cli::parsers::{
+    parse_codec, parse_existing_audio, parse_lag_policy, parse_mount_point, parse_multicast_group,
+    parse_queue_policy, parse_rtp_destination,
+};
+use crate::network::QueuePolicy;
 use clap::Parser;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -9,7 +17,80 @@ pub struct ServerCli {
     #[arg(short, long, default_value_t = 8080)]
     pub port: u16,
 
-    /// Audio WAV file to play
-    #[arg(short, long, value_parser=parse_existing_wav)]
-    pub wav: WavFile,
+    /// Also accept clients on a Unix domain socket at this path
+    #[arg(long)]
+    pub unix: Option<PathBuf>,
+
+    /// Also accept clients over UDP, on the same port number as TCP
+    #[arg(long)]
+    pub udp: bool,
+
+    /// Fraction of the datagrams sent to UDP clients to drop on purpose, to test how
+    /// they cope with a lossy network
+    #[arg(long, default_value_t = 0.0, requires = "udp")]
+    pub udp_loss: f64,
+
+    /// Also serve the stream as a never-ending WAV file over HTTP on this port, for
+    /// browsers, curl or VLC
+    #[arg(long)]
+    pub http: Option<u16>,
+
+    /// Also serve the stream over HTTP at this path the way an Icecast mount point
+    /// does, telling internet radio players the title of the current track
+    #[arg(long, value_parser = parse_mount_point, requires = "http")]
+    pub icecast_mount: Option<String>,
+
+    /// Name of the station announced to the players of the Icecast mount point
+    #[arg(long, default_value = "sonos-challenge", requires = "icecast_mount")]
+    pub icy_name: String,
+
+    /// Also send the stream as RTP (L16) to this IP:PORT, unicast or multicast, for
+    /// standard players. RTCP goes to the next port
+    #[arg(long, value_parser = parse_rtp_destination)]
+    pub rtp: Option<SocketAddr>,
+
+    /// Where to write the SDP description players open to receive the RTP stream
+    #[arg(long, default_value = "stream.sdp", requires = "rtp")]
+    pub sdp: PathBuf,
+
+    /// Also send the stream once to this multicast GROUP:PORT, for any number of clients
+    /// joining the group
+    #[arg(long, value_parser = parse_multicast_group)]
+    pub multicast: Option<SocketAddrV4>,
+
+    /// Address of the network interface to send multicast datagrams from, the routing
+    /// table picks one by default
+    #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED, requires = "multicast")]
+    pub multicast_interface: Ipv4Addr,
+
+    /// Audio file to play: WAV, FLAC, MP3 or Ogg Vorbis, detected from its content
+    #[arg(short, long, visible_alias = "wav", short_alias = 'w', value_parser=parse_existing_audio)]
+    pub input: AudioFile,
+
+    /// Codec to send samples with: pcm, lossless, ima-adpcm, mu-law or a-law.
+    /// Clients that don't support it get plain PCM
+    #[arg(short, long, default_value = "pcm", value_parser = parse_codec)]
+    pub codec: Codec,
+
+    /// What to do when a client falls so far behind that its outgoing queue is full:
+    /// drop-oldest, drop-client or block
+    #[arg(long, default_value = "drop-oldest", value_parser = parse_queue_policy)]
+    pub queue_policy: QueuePolicy,
+
+    /// Seconds of audio a client may have queued before the lag policy applies
+    #[arg(long, default_value_t = 10.0)]
+    pub max_lag: f64,
+
+    /// Seconds of the latest audio kept for clients resuming the stream after losing
+    /// their connection
+    #[arg(long, default_value_t = 5.0)]
+    pub replay_window: f64,
+
+    /// Seconds clients get to acknowledge the end of the stream before being disconnected
+    #[arg(long, default_value_t = 30.0)]
+    pub end_of_stream_timeout: f64,
+
+    /// What to do with a client lagging more than --max-lag: evict or downgrade
+    #[arg(long, default_value = "evict", value_parser = parse_lag_policy)]
+    pub lag_policy: LagPolicy,
 }
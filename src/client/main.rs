@@ -1,20 +1,52 @@
 use clap::Parser;
-use log::{LevelFilter, debug, error, info};
+use log::{LevelFilter, debug, error, info, warn};
+use sonos_challenge::audio::FrameOrder;
 use sonos_challenge::audio::{
-    AudioMessage, Serializable, SpeakerOutput, SpeakerOutputBuilder, WavAudioOutput,
+    AudioMessage, AudioSink, Codec, DeserializationError, Features, Hello, JitterBuffer, NullSink,
+    PROTOCOL_VERSION, PcmFormat, SamplesFrame, SequenceTracker, Serializable, SpeakerOutputBuilder,
+    SpeakerSink, WavFileSink,
 };
 use sonos_challenge::cli;
 use sonos_challenge::cli::{ClientCli, WavFile};
-use sonos_challenge::network::TcpClientError;
+use sonos_challenge::network::multicast::MulticastReceiver;
+use sonos_challenge::network::rtp::RtpReceiver;
 use sonos_challenge::network::tcp::TcpClient;
+use sonos_challenge::network::udp::UdpClient;
+use sonos_challenge::network::{FrameReceiver, FrameSender, TransportError};
 use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::Ordering::SeqCst;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Optional protocol features this client implements.
+const CLIENT_FEATURES: Features = Features::END_OF_STREAM.union(Features::RESUME);
+/// Wait before the first attempt to reconnect, doubled after every failed one.
+const RECONNECT_FIRST_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Where to connect again when the connection to the server drops.
+#[derive(Debug, Clone)]
+struct Reconnect {
+    address: String,
+    unix: Option<PathBuf>,
+    udp: bool,
+    /// How long to keep trying before giving up.
+    timeout: Duration,
+}
 
 struct Application {
-    tcp_client: TcpClient,
+    /// `None` for receive-only streams such as RTP or multicast, which have no handshake
+    /// and aren't acknowledged.
+    sender: Option<Box<dyn FrameSender>>,
+    receiver: Box<dyn FrameReceiver>,
+    tracker: SequenceTracker,
+    /// Puts frames back in order when the transport may lose or reorder them.
+    jitter: Option<JitterBuffer>,
+    /// `None` when a dropped connection ends the stream.
+    reconnect: Option<Reconnect>,
     stop: Arc<std::sync::atomic::AtomicBool>,
 }
 
@@ -24,111 +56,344 @@ enum ReceiveOutcome {
 }
 #[derive(Debug)]
 enum ApplicationError {
-    TcpClientError,
-    WavAudioOutputError,
+    Transport,
+    AudioSinkError,
+    Serialization,
+    HandshakeFailed,
 }
 impl Application {
+    /// Greets the server, asking it to resume the stream at `resume` if set.
+    fn handshake(&mut self, resume: Option<u64>) -> Result<(), ApplicationError> {
+        // Receive-only streams start right away
+        let Some(sender) = &mut self.sender else {
+            return Ok(());
+        };
+        let sample_formats = vec![
+            PcmFormat::I8,
+            PcmFormat::I16,
+            PcmFormat::I24,
+            PcmFormat::I32,
+            PcmFormat::F32,
+        ];
+        let hello = Hello {
+            resume_position: resume,
+            ..Hello::new(
+                sample_formats,
+                vec![
+                    Codec::Pcm,
+                    Codec::Lossless,
+                    Codec::ImaAdpcm,
+                    Codec::MuLaw,
+                    Codec::ALaw,
+                ],
+                CLIENT_FEATURES,
+            )
+        };
+        let mut buffer = Vec::new();
+        if AudioMessage::Hello(hello).serialize(&mut buffer).is_err() {
+            error!("Couldn't serialize Hello message");
+            return Err(ApplicationError::Serialization);
+        }
+        if let Err(error) = sender.send(&buffer) {
+            error!("Error sending Hello to server: {:?}", error);
+            return Err(ApplicationError::Transport);
+        }
+        buffer.clear();
+        if let ReceiveOutcome::ServerDisconnected = self.receive(&mut buffer)? {
+            error!("Server closed the connection during the handshake");
+            return Err(ApplicationError::HandshakeFailed);
+        }
+        match AudioMessage::deserialize(&buffer) {
+            Ok(AudioMessage::Welcome(welcome)) => {
+                info!(
+                    "Connected with protocol version {}, codec {:?}, features {:?}",
+                    welcome.version, welcome.codec, welcome.features
+                );
+                if resume.is_some() && !welcome.features.contains(Features::RESUME) {
+                    warn!("Server can't resume the stream, samples were missed");
+                }
+                Ok(())
+            }
+            Ok(AudioMessage::Spec { .. }) => {
+                error!(
+                    "Server skipped the handshake, it predates protocol version {}",
+                    PROTOCOL_VERSION
+                );
+                Err(ApplicationError::HandshakeFailed)
+            }
+            Ok(other) => {
+                error!("Expected a Welcome from server, got {:?}", other);
+                Err(ApplicationError::HandshakeFailed)
+            }
+            Err(DeserializationError::UnsupportedProtocolVersion {
+                supported,
+                received,
+            }) => {
+                error!(
+                    "Server speaks protocol version {}, this client supports {}",
+                    received, supported
+                );
+                Err(ApplicationError::HandshakeFailed)
+            }
+            Err(e) => {
+                error!("Error deserializing Welcome message: {:?}", e);
+                Err(ApplicationError::HandshakeFailed)
+            }
+        }
+    }
+
     fn receive(&mut self, buffer: &mut Vec<u8>) -> Result<ReceiveOutcome, ApplicationError> {
-        match self.tcp_client.receive(buffer) {
+        match self.receiver.receive(buffer) {
             Ok(_) => Ok(ReceiveOutcome::Data),
-            Err(TcpClientError::ServerDisconnected(_)) => {
+            Err(TransportError::Disconnected(_)) => {
                 info!("Server disconnected");
                 Ok(ReceiveOutcome::ServerDisconnected)
             }
             Err(error) => {
                 error!("Error receiving data from server: {:?}", error);
-                Err(ApplicationError::TcpClientError)
+                Err(ApplicationError::Transport)
             }
         }
     }
-    fn write_audio_to_file(&mut self, filename: &str) -> Result<(), ApplicationError> {
-        let mut buffer = Vec::new();
-        let mut output: Option<WavAudioOutput> = None;
-        loop {
-            buffer.clear();
-            if self.stop.load(SeqCst) {
-                if let Some(output) = output {
-                    match output.finalize() {
-                        Ok(_) => info!("WAV file finalized successfully"),
-                        Err(e) => error!("Error finalizing WAV file: {}", e),
-                    };
+    /// Checks a frame against the previous ones, returns false if it must be skipped.
+    fn track_frame(&mut self, frame: &SamplesFrame) -> bool {
+        match self.tracker.observe(frame) {
+            FrameOrder::First { position } => {
+                if position > 0 {
+                    info!("Joined the stream at sample {}", position);
                 }
-                info!("Stopping client");
-                return Ok(());
+                true
             }
-            if let ReceiveOutcome::ServerDisconnected = self.receive(&mut buffer)? {
-                return Ok(());
+            FrameOrder::InOrder => true,
+            FrameOrder::Gap {
+                missing_frames,
+                missing_samples,
+            } => {
+                warn!(
+                    "Missed {} frames ({} samples) before frame {}",
+                    missing_frames, missing_samples, frame.sequence
+                );
+                true
             }
-            let audio_message = AudioMessage::deserialize(&buffer);
-            match audio_message {
-                Ok(AudioMessage::Spec(spec)) => {
-                    debug!("Received audio spec: {:?}", spec);
-                    if let Some(output) = output {
-                        match output.finalize() {
-                            Ok(_) => info!("Previous WAV file finalized successfully"),
-                            Err(e) => error!("Error finalizing previous WAV file: {}", e),
-                        }
-                    }
-                    output = match WavAudioOutput::new(filename, spec) {
-                        Ok(result) => Some(result),
-                        Err(e) => {
-                            error!("Failed to create WAV output: {}", e);
-                            return Err(ApplicationError::WavAudioOutputError);
-                        }
+            FrameOrder::Stale => {
+                warn!("Skipping stale frame {}", frame.sequence);
+                false
+            }
+        }
+    }
+    /// Logs what the network did to the frames that went through the jitter buffer.
+    fn report_losses(&self) {
+        let Some(jitter) = &self.jitter else {
+            return;
+        };
+        let stats = jitter.stats();
+        info!(
+            "Received {} frames: {} lost ({} samples concealed), {} gaps skipped, {} reordered, {} late, {} duplicates",
+            stats.received,
+            stats.lost,
+            stats.concealed_samples,
+            stats.resyncs,
+            stats.reordered,
+            stats.late,
+            stats.duplicates
+        );
+    }
+    /// Writes a frame to `sink`, through the jitter buffer if there is one. Returns how
+    /// many of the samples written were received, rather than concealed.
+    fn play_frame(
+        &mut self,
+        sink: &mut dyn AudioSink,
+        frame: SamplesFrame,
+    ) -> Result<u64, ApplicationError> {
+        let concealed = self.concealed_samples();
+        let frames = match &mut self.jitter {
+            Some(jitter) => jitter.push(frame),
+            None => vec![frame],
+        };
+        let written = self.write_frames(sink, frames)?;
+        Ok(written.saturating_sub(self.concealed_samples() - concealed))
+    }
+    /// Writes the frames the jitter buffer still holds, like `play_frame`.
+    fn drain_jitter_buffer(&mut self, sink: &mut dyn AudioSink) -> Result<u64, ApplicationError> {
+        let concealed = self.concealed_samples();
+        let Some(jitter) = &mut self.jitter else {
+            return Ok(0);
+        };
+        let frames = jitter.drain();
+        let written = self.write_frames(sink, frames)?;
+        Ok(written.saturating_sub(self.concealed_samples() - concealed))
+    }
+    fn write_frames(
+        &mut self,
+        sink: &mut dyn AudioSink,
+        frames: Vec<SamplesFrame>,
+    ) -> Result<u64, ApplicationError> {
+        let mut written = 0;
+        for frame in frames {
+            if !self.track_frame(&frame) {
+                continue;
+            }
+            written += frame.samples.len() as u64;
+            if let Err(e) = sink.write_samples(&frame.samples) {
+                error!("Failed to write samples: {}", e);
+                return Err(ApplicationError::AudioSinkError);
+            }
+        }
+        Ok(written)
+    }
+    fn concealed_samples(&self) -> u64 {
+        self.jitter
+            .as_ref()
+            .map_or(0, |jitter| jitter.stats().concealed_samples)
+    }
+    fn acknowledge_end_of_stream(
+        &mut self,
+        total_samples: u64,
+        received_samples: u64,
+    ) -> Result<(), ApplicationError> {
+        self.report_losses();
+        if self.tracker.lost_frames() > 0 {
+            warn!(
+                "Lost {} frames ({} samples) during the stream",
+                self.tracker.lost_frames(),
+                self.tracker.lost_samples()
+            );
+        }
+        if self.tracker.next_position() != total_samples {
+            warn!(
+                "Stream ended at sample {} but the last frame received ended at sample {}",
+                total_samples,
+                self.tracker.next_position()
+            );
+        }
+        let mut buffer = Vec::new();
+        let ack = AudioMessage::EndOfStreamAck { received_samples };
+        if ack.serialize(&mut buffer).is_err() {
+            error!("Couldn't serialize end of stream acknowledgement");
+            return Err(ApplicationError::Serialization);
+        }
+        if let Some(sender) = &mut self.sender
+            && let Err(error) = sender.send(&buffer)
+        {
+            error!("Error acknowledging end of stream: {:?}", error);
+            return Err(ApplicationError::Transport);
+        }
+        info!("End of stream reached after {} samples", received_samples);
+        Ok(())
+    }
+    /// Connects to the server again after losing it mid-stream, resuming right after
+    /// the last frame received. Returns false if it gave up, or reconnecting is disabled.
+    fn reconnect(&mut self) -> bool {
+        let Some(reconnect) = self.reconnect.clone() else {
+            return false;
+        };
+        // Without a frame yet, there is nothing to resume from
+        let resume = (self.tracker.next_position() > 0).then(|| self.tracker.next_position());
+        let deadline = Instant::now() + reconnect.timeout;
+        let mut delay = RECONNECT_FIRST_DELAY;
+        loop {
+            let now = Instant::now();
+            if self.stop.load(SeqCst) || now >= deadline {
+                warn!("Giving up reconnecting to server at {}", reconnect.address);
+                return false;
+            }
+            sleep(delay.min(deadline - now));
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            let (sender, receiver) =
+                match connect(&reconnect.address, reconnect.unix.as_deref(), reconnect.udp) {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        info!(
+                            "Couldn't reconnect to server at {}: {}. Retrying after {:?}...",
+                            reconnect.address, e, delay
+                        );
+                        continue;
                     }
-                }
-                Ok(AudioMessage::Samples(samples)) => {
-                    debug!("Received {} samples", samples.len());
-                    if let Some(output) = output.as_mut() {
-                        if let Err(error) = output.write_samples(&samples) {
-                            error!("Failed to write samples to WAV file: {}", error);
-                            return Err(ApplicationError::WavAudioOutputError);
-                        }
+                };
+            self.sender = Some(sender);
+            self.receiver = receiver;
+            match self.handshake(resume) {
+                Ok(()) => {
+                    if let Some(position) = resume {
+                        info!("Reconnected, resuming the stream at sample {}", position);
                     }
+                    return true;
                 }
-                Err(e) => {
-                    error!("Error deserializing audio message: {:?}", e);
-                }
+                Err(error) => warn!("Handshake failed while reconnecting: {:?}", error),
             }
         }
     }
-    fn play_audio(&mut self, speaker: Option<String>) -> Result<(), ApplicationError> {
+    /// Receives the stream into `sink` until the server ends it, or disconnects and
+    /// can't be reconnected to.
+    fn run(&mut self, sink: &mut dyn AudioSink) -> Result<(), ApplicationError> {
         let mut buffer = Vec::new();
-        let mut speaker_output: Option<SpeakerOutput> = None;
+        let mut received_samples: u64 = 0;
         loop {
             buffer.clear();
             if self.stop.load(SeqCst) {
-                if let Some(output) = speaker_output
-                    && let Err(e) = output.pause()
-                {
-                    error!("Error pausing speaker output: {:?}", e);
+                if let Err(e) = sink.finalize() {
+                    error!("Error closing audio output: {}", e);
                 }
+                self.report_losses();
                 info!("Stopping client");
                 return Ok(());
             }
-            if let ReceiveOutcome::ServerDisconnected = self.receive(&mut buffer)? {
+            let disconnected = match self.receive(&mut buffer) {
+                Ok(ReceiveOutcome::Data) => false,
+                Ok(ReceiveOutcome::ServerDisconnected) => true,
+                // A broken connection is as good as a closed one when reconnecting
+                Err(_) if self.reconnect.is_some() => true,
+                Err(error) => return Err(error),
+            };
+            if disconnected {
+                if self.reconnect() {
+                    continue;
+                }
+                self.report_losses();
+                if let Err(e) = sink.finalize() {
+                    error!("Error closing audio output: {}", e);
+                }
                 return Ok(());
             }
-            let audio_message = AudioMessage::deserialize(&buffer);
-            match audio_message {
-                Ok(AudioMessage::Spec(spec)) => {
-                    debug!("Received audio spec: {:?}", spec);
-                    let mut speaker_builder = SpeakerOutputBuilder::new();
-                    if let Some(device_name) = &speaker {
-                        speaker_builder.with_output_device(device_name);
+            // Compressed frames are decoded here so the rest of the loop only sees samples
+            let audio_message = match AudioMessage::deserialize(&buffer) {
+                Ok(AudioMessage::EncodedSamples(encoded)) => match encoded.decode() {
+                    Ok(frame) => Ok(AudioMessage::Samples(frame)),
+                    Err(e) => {
+                        warn!("Couldn't decode frame {}: {}", encoded.sequence, e);
+                        continue;
                     }
-                    match speaker_builder.build() {
-                        Ok(so) => speaker_output = Some(so),
-                        Err(e) => {
-                            error!("Error initializing speaker output: {:?}", e);
-                            continue;
-                        }
+                },
+                other => other,
+            };
+            match audio_message {
+                Ok(AudioMessage::Spec { spec, codec }) => {
+                    debug!("Received audio spec: {:?} with {:?} codec", spec, codec);
+                    // The output gets the samples as decoded, which may differ from the source
+                    let spec = codec.decoded_spec(&spec);
+                    if let Err(e) = sink.configure(&spec) {
+                        error!("Failed to open audio output: {}", e);
+                        return Err(ApplicationError::AudioSinkError);
                     }
                 }
-                Ok(AudioMessage::Samples(samples)) => {
-                    if let Some(output) = speaker_output.as_mut() {
-                        output.play_samples(&samples);
+                Ok(AudioMessage::Samples(frame)) => {
+                    debug!(
+                        "Received frame {} with {} samples",
+                        frame.sequence,
+                        frame.samples.len()
+                    );
+                    received_samples += self.play_frame(sink, frame)?;
+                }
+                Ok(AudioMessage::EndOfStream { total_samples }) => {
+                    received_samples += self.drain_jitter_buffer(sink)?;
+                    if let Err(e) = sink.flush().and_then(|_| sink.finalize()) {
+                        error!("Error closing audio output: {}", e);
+                        return Err(ApplicationError::AudioSinkError);
                     }
+                    return self.acknowledge_end_of_stream(total_samples, received_samples);
+                }
+                Ok(other) => {
+                    debug!("Ignoring unexpected message: {:?}", other);
                 }
                 Err(e) => {
                     error!("Error deserializing audio message: {:?}", e);
@@ -145,6 +410,25 @@ impl Application {
     }
 }
 
+/// Connects to the server over its Unix domain socket if given a `unix` path, over UDP
+/// if `udp` is set, over TCP otherwise.
+fn connect(
+    address: &str,
+    unix: Option<&Path>,
+    udp: bool,
+) -> io::Result<(Box<dyn FrameSender>, Box<dyn FrameReceiver>)> {
+    if let Some(path) = unix {
+        let stream = UnixStream::connect(path)?;
+        return Ok((Box::new(stream.try_clone()?), Box::new(stream)));
+    }
+    if udp {
+        let udp = UdpClient::connect(address)?;
+        return Ok((Box::new(udp.try_clone()?), Box::new(udp)));
+    }
+    let tcp = TcpClient::connect(address)?;
+    Ok((Box::new(tcp.try_clone()?), Box::new(tcp)))
+}
+
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let cli = ClientCli::parse();
@@ -156,21 +440,59 @@ fn main() {
         None => {}
     }
 
-    let address = format!("{}:{}", cli.ip.unwrap(), cli.port.unwrap());
-    let mut tcp: io::Result<TcpClient>;
-    loop {
-        tcp = TcpClient::connect(&address);
-        if tcp.is_err() {
-            info!(
-                "Couldn't connect to server at {}. Retrying after 1 second...",
-                address
-            );
-            sleep(Duration::from_secs(1));
-            continue;
-        }
-        break;
-    }
-    let tcp = tcp.unwrap();
+    let mut reconnect = None;
+    let (sender, receiver): (Option<Box<dyn FrameSender>>, Box<dyn FrameReceiver>) =
+        if let Some(description) = cli.rtp {
+            match RtpReceiver::bind(description) {
+                Ok(receiver) => {
+                    info!("Waiting for RTP packets on {}", description.destination);
+                    (None, Box::new(receiver))
+                }
+                Err(e) => {
+                    error!(
+                        "Couldn't listen for RTP packets on {}: {}",
+                        description.destination, e
+                    );
+                    return;
+                }
+            }
+        } else if let Some(group) = cli.multicast {
+            match MulticastReceiver::join(group, cli.multicast_interface) {
+                Ok(receiver) => {
+                    info!("Joined multicast group {}, waiting for the stream", group);
+                    (None, Box::new(receiver))
+                }
+                Err(e) => {
+                    error!("Couldn't join multicast group {}: {}", group, e);
+                    return;
+                }
+            }
+        } else {
+            let address = match &cli.unix {
+                Some(path) => path.display().to_string(),
+                None => format!("{}:{}", cli.ip.unwrap(), cli.port.unwrap()),
+            };
+            if cli.reconnect_timeout > 0.0 {
+                reconnect = Some(Reconnect {
+                    address: address.clone(),
+                    unix: cli.unix.clone(),
+                    udp: cli.udp,
+                    timeout: Duration::from_secs_f64(cli.reconnect_timeout),
+                });
+            }
+            loop {
+                match connect(&address, cli.unix.as_deref(), cli.udp) {
+                    Ok((sender, receiver)) => break (Some(sender), receiver),
+                    Err(_) => {
+                        info!(
+                            "Couldn't connect to server at {}. Retrying after 1 second...",
+                            address
+                        );
+                        sleep(Duration::from_secs(1));
+                    }
+                }
+            }
+        };
     let stop: Arc<std::sync::atomic::AtomicBool> =
         Arc::new(std::sync::atomic::AtomicBool::new(false));
     {
@@ -182,27 +504,36 @@ fn main() {
         .expect("Error setting Ctrl-C handler");
     }
     let mut app = Application {
-        tcp_client: tcp,
+        sender,
+        receiver,
+        tracker: SequenceTracker::new(),
+        jitter: (cli.udp || cli.rtp.is_some() || cli.multicast.is_some())
+            .then(|| JitterBuffer::new(cli.jitter_buffer)),
+        reconnect,
         stop,
     };
-    if cli.default_speaker || cli.speaker.is_some() {
-        let speaker_name = cli.speaker.map(|s| s.name);
-        if let Err(error) = app.play_audio(speaker_name) {
-            error!("Error during audio playback: {:?}", error);
-            return;
+    if let Err(error) = app.handshake(None) {
+        error!("Handshake with server failed: {:?}", error);
+        return;
+    }
+    let mut sink: Box<dyn AudioSink> = if cli.default_speaker || cli.speaker.is_some() {
+        let mut speaker_builder = SpeakerOutputBuilder::new();
+        if let Some(speaker) = &cli.speaker {
+            speaker_builder.with_output_device(&speaker.name);
         }
+        Box::new(SpeakerSink::new(speaker_builder))
     } else if let Some(WavFile { path }) = cli.file {
-        let file = match path.to_str() {
-            Some(f) => f,
+        match path.to_str() {
+            Some(file) => Box::new(WavFileSink::new(file)),
             None => {
                 error!("Unexpected error: Invalid file path");
                 return;
             }
-        };
-
-        if let Err(error) = app.write_audio_to_file(file) {
-            error!("Error writing audio to file: {:?}", error);
-            return;
         }
+    } else {
+        Box::new(NullSink::new())
+    };
+    if let Err(error) = app.run(sink.as_mut()) {
+        error!("Error while receiving the stream: {:?}", error);
     }
 }
@@ -1,3 +1,23 @@
+#[cfg(feature = "tokio")]
+pub mod async_tcp;
+pub mod http;
+pub mod icy;
+pub mod memory;
+pub mod multicast;
+mod queue;
+pub mod rtp;
 pub mod tcp;
+mod transport;
+pub mod udp;
+pub mod websocket;
 
-pub use tcp::{TcpClient, TcpClientError, TcpServer};
+#[cfg(feature = "tokio")]
+pub use async_tcp::{AsyncTcpClient, AsyncTcpServer, MessageError};
+pub use http::HttpListener;
+pub use icy::StreamTitle;
+pub use multicast::{MulticastReceiver, MulticastSender};
+pub use queue::QueuePolicy;
+pub use tcp::{ClientGroup, ClientId, ClientLag, Handshake, TcpClient, TcpClientError, TcpServer};
+pub(crate) use transport::MAX_FRAME_SIZE;
+pub use transport::{FrameReceiver, FrameSender, TransportError};
+pub use udp::{LossInjector, UdpClient, UdpListener};
@@ -0,0 +1,742 @@
+//! Async equivalents of `TcpServer` and `TcpClient` for tokio based applications. They
+//! speak the same length-prefixed framing, so both flavours interoperate.
+
+use crate::audio::message::LengthError;
+use crate::audio::{AudioMessage, DeserializationError, Serializable};
+use crate::network::queue::{Pushed, QueuePolicy, State};
+use crate::network::tcp::{
+    ClientGroup, ClientId, HANDSHAKE_TIMEOUT, Handshake, HandshakeHandler, Replay, ReplayHandler,
+};
+use crate::network::transport::{MAX_FRAME_SIZE, TransportError, map_io_error};
+use futures_util::Stream;
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+
+/// Frames each client may have waiting before the queue policy kicks in.
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+fn lock<'a, T>(mutex: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        error!("{} mutex poisoned", name);
+        poisoned.into_inner()
+    })
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<usize, TransportError> {
+    let mut length_bytes = [0u8; 4];
+    reader
+        .read_exact(&mut length_bytes)
+        .await
+        .map_err(|e| map_io_error(e, "reading frame length"))?;
+    let length = u32::from_le_bytes(length_bytes) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(TransportError::FrameTooLarge {
+            length,
+            max: MAX_FRAME_SIZE,
+        });
+    }
+    buf.resize(length, 0);
+    reader
+        .read_exact(buf)
+        .await
+        .map_err(|e| map_io_error(e, "reading frame body"))?;
+    Ok(length)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    if data.len() > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Data length exceeds u32 maximum",
+        ));
+    }
+    writer.write_all(&(data.len() as u32).to_le_bytes()).await?;
+    writer.write_all(data).await
+}
+
+/// Like `write_frame`, logging failures the way the blocking server does.
+async fn send_frame<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8], context: &str) -> bool {
+    match write_frame(writer, data).await {
+        Ok(()) => true,
+        Err(e) => {
+            let is_disconnect = matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::UnexpectedEof
+            );
+            if is_disconnect {
+                info!("Client disconnected while {}: {}", context, e);
+            } else {
+                warn!("I/O error while {}: {}", context, e);
+            }
+            false
+        }
+    }
+}
+
+/// Frames waiting to be written to one client, filled by broadcasts and drained by the
+/// client's writer task.
+struct ClientQueue {
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+impl ClientQueue {
+    fn new() -> Self {
+        ClientQueue {
+            state: Mutex::new(State::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues `frame`, applying `policy` if `capacity` frames are already waiting.
+    async fn push(&self, frame: Arc<[u8]>, capacity: usize, policy: QueuePolicy) -> Pushed {
+        let mut dropped = 0;
+        loop {
+            // Registered before checking, so a change in between isn't missed
+            let changed = self.changed.notified();
+            {
+                let mut state = self.lock();
+                if state.closed {
+                    return Pushed::Rejected;
+                }
+                if state.frames.len() < capacity.max(1) {
+                    state.push(frame);
+                    drop(state);
+                    self.changed.notify_waiters();
+                    return if dropped > 0 {
+                        Pushed::DroppedOldest(dropped)
+                    } else {
+                        Pushed::Queued
+                    };
+                }
+                match policy {
+                    QueuePolicy::DropOldest => {
+                        state.pop();
+                        dropped += 1;
+                        continue;
+                    }
+                    QueuePolicy::DropClient => return Pushed::Rejected,
+                    QueuePolicy::Block => {}
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Waits for the next frame to write, `None` once the queue is closed.
+    async fn pop(&self) -> Option<Arc<[u8]>> {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let mut state = self.lock();
+                if state.closed {
+                    return None;
+                }
+                if let Some(frame) = state.pop() {
+                    drop(state);
+                    self.changed.notify_waiters();
+                    return Some(frame);
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Waits until the queue is closed.
+    async fn closed(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if self.lock().closed {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        state.clear();
+        drop(state);
+        self.changed.notify_waiters();
+    }
+}
+
+struct Client {
+    id: ClientId,
+    group: ClientGroup,
+    /// Frames waiting for the client's writer task. Closing it ends both tasks of the
+    /// client.
+    queue: Arc<ClientQueue>,
+}
+
+/// State shared between the server and the tasks serving its clients.
+struct Shared {
+    clients: Mutex<Vec<Client>>,
+    new_client_message: Mutex<Vec<u8>>,
+    group_messages: Mutex<HashMap<ClientGroup, Vec<u8>>>,
+    handshake_handler: Mutex<Option<HandshakeHandler>>,
+    replay_handler: Mutex<Option<ReplayHandler>>,
+    incoming: mpsc::UnboundedSender<(ClientId, Vec<u8>)>,
+    next_id: AtomicU64,
+}
+
+impl Shared {
+    fn clients(&self) -> MutexGuard<'_, Vec<Client>> {
+        lock(&self.clients, "clients")
+    }
+
+    fn new_client_message(&self, group: ClientGroup) -> Vec<u8> {
+        let group_message = lock(&self.group_messages, "group_messages")
+            .get(&group)
+            .cloned();
+        group_message
+            .unwrap_or_else(|| lock(&self.new_client_message, "new_client_message").clone())
+    }
+
+    /// Runs the handshake handler, if any, on a freshly accepted stream.
+    /// Returns the group of the client and where it resumes the stream, if it does, or
+    /// `None` when it must be dropped.
+    async fn handshake(&self, stream: &mut TcpStream) -> Option<(ClientGroup, Option<u64>)> {
+        if lock(&self.handshake_handler, "handshake_handler").is_none() {
+            return Some((0, None));
+        }
+        let mut request = Vec::new();
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(stream, &mut request)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                warn!("Client did not complete the handshake: {:?}", e);
+                return None;
+            }
+            Err(_) => {
+                warn!(
+                    "Client did not complete the handshake in {:?}",
+                    HANDSHAKE_TIMEOUT
+                );
+                return None;
+            }
+        }
+        let decision = match lock(&self.handshake_handler, "handshake_handler").as_ref() {
+            Some(handler) => handler(&request),
+            None => Handshake::Accept(Vec::new()),
+        };
+        let (reply, group, resume) = match decision {
+            Handshake::Accept(reply) => (reply, 0, None),
+            Handshake::AcceptInGroup { reply, group } => (reply, group, None),
+            Handshake::Resume {
+                reply,
+                group,
+                position,
+            } => (reply, group, Some(position)),
+            Handshake::Reject(reply) => {
+                if !reply.is_empty() {
+                    send_frame(stream, &reply, "rejecting handshake").await;
+                }
+                info!("Rejected client during handshake");
+                return None;
+            }
+        };
+        send_frame(stream, &reply, "answering handshake")
+            .await
+            .then_some((group, resume))
+    }
+
+    /// Serves a freshly accepted client until it disconnects or is disconnected.
+    async fn serve_client(self: Arc<Self>, mut stream: TcpStream) {
+        let Some((group, resume)) = self.handshake(&mut stream).await else {
+            return;
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let queue = Arc::new(ClientQueue::new());
+        // Sent by the writer task ahead of any broadcast
+        let message = self.new_client_message(group);
+        if !message.is_empty() {
+            debug!("Sending {} bytes to new client", message.len());
+        }
+        let replay = {
+            let mut clients = self.clients();
+            // Taken while broadcasts wait for the lock, so none slips in between
+            let replay = resume.and_then(|position| {
+                lock(&self.replay_handler, "replay_handler")
+                    .as_ref()
+                    .map(|handler| handler(group, position))
+            });
+            clients.push(Client {
+                id,
+                group,
+                queue: Arc::clone(&queue),
+            });
+            replay
+        };
+        let (mut reader, writer) = stream.into_split();
+        tokio::spawn(write_client_frames(
+            id,
+            writer,
+            message,
+            replay,
+            Arc::clone(&queue),
+        ));
+
+        let mut buf = Vec::new();
+        loop {
+            tokio::select! {
+                result = read_frame(&mut reader, &mut buf) => match result {
+                    Ok(_) => {
+                        if self.incoming.send((id, std::mem::take(&mut buf))).is_err() {
+                            break;
+                        }
+                    }
+                    Err(TransportError::Disconnected(_)) => {
+                        info!("Client {} disconnected", id);
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Error reading from client {}: {:?}", id, e);
+                        break;
+                    }
+                },
+                _ = queue.closed() => break,
+            }
+        }
+        queue.close();
+        self.clients().retain(|client| client.id != id);
+    }
+}
+
+/// Writes the new client `message` and the frames of `replay`, then those queued for a
+/// client until its queue is closed. The catch-up frames never wait in the queue, so they
+/// don't count as lag.
+async fn write_client_frames(
+    id: ClientId,
+    mut writer: OwnedWriteHalf,
+    message: Vec<u8>,
+    replay: Option<Replay>,
+    queue: Arc<ClientQueue>,
+) {
+    // Built off the runtime threads, encoding may take a while
+    let replayed = match replay {
+        Some(replay) => tokio::task::spawn_blocking(replay)
+            .await
+            .unwrap_or_default(),
+        None => Vec::new(),
+    };
+    let catch_up = std::iter::once(message)
+        .filter(|message| !message.is_empty())
+        .chain(replayed);
+    let mut caught_up = true;
+    for frame in catch_up {
+        if !send_frame(&mut writer, &frame, "sending catch-up frame").await {
+            debug!("Stopping writer of client {}", id);
+            caught_up = false;
+            break;
+        }
+    }
+    while caught_up && let Some(frame) = queue.pop().await {
+        if !send_frame(&mut writer, &frame, "sending queued frame").await {
+            debug!("Stopping writer of client {}", id);
+            break;
+        }
+    }
+    queue.close();
+    let _ = writer.shutdown().await;
+}
+
+pub struct AsyncTcpServer {
+    shared: Arc<Shared>,
+    incoming: mpsc::UnboundedReceiver<(ClientId, Vec<u8>)>,
+    queue_capacity: usize,
+    queue_policy: QueuePolicy,
+    accept_task: JoinHandle<()>,
+}
+
+impl AsyncTcpServer {
+    /// Listens on `address`, accepting clients on a task of the current tokio runtime.
+    pub async fn bind(address: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let (incoming_sender, incoming) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            clients: Mutex::new(Vec::new()),
+            new_client_message: Mutex::new(Vec::new()),
+            group_messages: Mutex::new(HashMap::new()),
+            handshake_handler: Mutex::new(None),
+            replay_handler: Mutex::new(None),
+            incoming: incoming_sender,
+            next_id: AtomicU64::new(0),
+        });
+        let shared_for_task = Arc::clone(&shared);
+        let accept_task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        info!("Accepted connection from {}", addr);
+                        tokio::spawn(Arc::clone(&shared_for_task).serve_client(stream));
+                    }
+                    Err(e) => error!("Could not accept connection: {}", e),
+                }
+            }
+        });
+        Ok(AsyncTcpServer {
+            shared,
+            incoming,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            queue_policy: QueuePolicy::default(),
+            accept_task,
+        })
+    }
+
+    pub fn set_new_client_message(&mut self, data: &[u8]) {
+        let mut message = lock(&self.shared.new_client_message, "new_client_message");
+        message.clear();
+        message.extend_from_slice(data);
+        debug!("Set new client message of {} bytes", data.len());
+    }
+
+    /// Replaces the new client message for clients accepted in `group`.
+    pub fn set_group_message(&mut self, group: ClientGroup, data: &[u8]) {
+        lock(&self.shared.group_messages, "group_messages").insert(group, data.to_vec());
+    }
+
+    /// Sets how many frames each client may have waiting to be written.
+    pub fn set_queue_capacity(&mut self, frames: usize) {
+        self.queue_capacity = frames.max(1);
+    }
+
+    /// Sets what broadcasts do for a client whose queue is full.
+    pub fn set_queue_policy(&mut self, policy: QueuePolicy) {
+        self.queue_policy = policy;
+    }
+
+    /// Makes every new client go through `handler` before being served, see
+    /// `TcpServer::set_handshake_handler`.
+    pub fn set_handshake_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&[u8]) -> Handshake + Send + 'static,
+    {
+        *lock(&self.shared.handshake_handler, "handshake_handler") = Some(Box::new(handler));
+    }
+
+    /// Gives clients accepted with `Handshake::Resume` their first frames, see
+    /// `TcpServer::set_replay_handler`.
+    pub fn set_replay_handler<F, R>(&mut self, handler: F)
+    where
+        F: Fn(ClientGroup, u64) -> R + Send + 'static,
+        R: FnOnce() -> Vec<Vec<u8>> + Send + 'static,
+    {
+        *lock(&self.shared.replay_handler, "replay_handler") =
+            Some(Box::new(move |group, position| -> Replay {
+                Box::new(handler(group, position))
+            }));
+    }
+
+    pub fn get_client_count(&self) -> usize {
+        self.shared.clients().len()
+    }
+
+    pub fn client_ids(&self) -> Vec<ClientId> {
+        self.shared
+            .clients()
+            .iter()
+            .map(|client| client.id)
+            .collect()
+    }
+
+    /// Groups having at least one client, without duplicates.
+    pub fn client_groups(&self) -> Vec<ClientGroup> {
+        let mut groups: Vec<ClientGroup> = self
+            .shared
+            .clients()
+            .iter()
+            .map(|client| client.group)
+            .collect();
+        groups.sort_unstable();
+        groups.dedup();
+        groups
+    }
+
+    /// Closes the connection of a client. Returns false if there is no such client.
+    pub fn disconnect_client(&mut self, id: ClientId) -> bool {
+        let mut clients = self.shared.clients();
+        let Some(index) = clients.iter().position(|client| client.id == id) else {
+            return false;
+        };
+        clients.remove(index).queue.close();
+        true
+    }
+
+    /// Waits for the next frame sent by any client.
+    pub async fn receive(&mut self) -> Option<(ClientId, Vec<u8>)> {
+        self.incoming.recv().await
+    }
+
+    pub async fn broadcast(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_to_clients(data, |_| true).await
+    }
+
+    /// Sends `data` to the clients accepted in `group` only.
+    pub async fn broadcast_to_group(&mut self, group: ClientGroup, data: &[u8]) -> io::Result<()> {
+        self.send_to_clients(data, |client| client.group == group)
+            .await
+    }
+
+    async fn send_to_clients(
+        &mut self,
+        data: &[u8],
+        filter: impl Fn(&Client) -> bool,
+    ) -> io::Result<()> {
+        if data.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Data length exceeds u32 maximum",
+            ));
+        }
+        let queues: Vec<(ClientId, Arc<ClientQueue>)> = self
+            .shared
+            .clients()
+            .iter()
+            .filter(|client| filter(client))
+            .map(|client| (client.id, Arc::clone(&client.queue)))
+            .collect();
+        let frame: Arc<[u8]> = data.into();
+        for (id, queue) in queues {
+            match queue
+                .push(Arc::clone(&frame), self.queue_capacity, self.queue_policy)
+                .await
+            {
+                Pushed::Queued => {}
+                Pushed::DroppedOldest(dropped) => {
+                    debug!("Dropped {} frames queued for client {}", dropped, id);
+                }
+                Pushed::Rejected => {
+                    warn!("Disconnecting client {}: its queue is full", id);
+                    self.disconnect_client(id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AsyncTcpServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        for client in self.shared.clients().drain(..) {
+            client.queue.close();
+        }
+    }
+}
+
+/// Why an `AudioMessage` couldn't be sent or received.
+#[derive(Debug)]
+pub enum MessageError {
+    Network(TransportError),
+    Serialization(LengthError),
+    Deserialization(DeserializationError),
+}
+
+pub struct AsyncTcpClient {
+    stream: TcpStream,
+}
+
+impl AsyncTcpClient {
+    pub async fn connect(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address).await?;
+        Ok(AsyncTcpClient { stream })
+    }
+
+    pub async fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        let length = read_frame(&mut self.stream, buf).await?;
+        debug!("Received {} bytes from server", length);
+        Ok(length)
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        if data.len() > u32::MAX as usize {
+            return Err(TransportError::FrameTooLarge {
+                length: data.len(),
+                max: u32::MAX as usize,
+            });
+        }
+        write_frame(&mut self.stream, data)
+            .await
+            .map_err(|e| map_io_error(e, "sending frame"))?;
+        debug!("Sent {} bytes to server", data.len());
+        Ok(())
+    }
+
+    pub async fn send_message(&mut self, message: &AudioMessage) -> Result<(), MessageError> {
+        let mut buf = Vec::new();
+        message
+            .serialize(&mut buf)
+            .map_err(MessageError::Serialization)?;
+        self.send(&buf).await.map_err(MessageError::Network)
+    }
+
+    pub async fn receive_message(&mut self) -> Result<AudioMessage, MessageError> {
+        let mut buf = Vec::new();
+        self.receive(&mut buf)
+            .await
+            .map_err(MessageError::Network)?;
+        AudioMessage::deserialize(&buf).map_err(MessageError::Deserialization)
+    }
+
+    /// Turns the client into the stream of messages the server sends. The stream ends
+    /// when the server closes the connection or after a network error; messages that
+    /// can't be deserialized are reported and skipped.
+    pub fn into_messages(self) -> impl Stream<Item = Result<AudioMessage, MessageError>> {
+        futures_util::stream::unfold(Some(self), |client| async move {
+            let mut client = client?;
+            match client.receive_message().await {
+                Err(MessageError::Network(TransportError::Disconnected(_))) => None,
+                Err(e @ MessageError::Network(_)) => Some((Err(e), None)),
+                result => Some((result, Some(client))),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsyncTcpClient, AsyncTcpServer};
+    use crate::audio::{AudioMessage, Serializable};
+    use crate::network::{Handshake, TcpClient, TcpServer};
+    use futures_util::StreamExt;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    #[tokio::test]
+    async fn broadcast_and_receive_test() {
+        let address = "localhost:50112";
+        let mut server = AsyncTcpServer::bind(address)
+            .await
+            .expect("Failed to start TCP server");
+        server.set_new_client_message(&[7, 7]);
+        server.set_handshake_handler(|request| {
+            if request == [1] {
+                Handshake::AcceptInGroup {
+                    reply: vec![2],
+                    group: 3,
+                }
+            } else {
+                Handshake::Reject(vec![0])
+            }
+        });
+
+        let mut client = AsyncTcpClient::connect(address)
+            .await
+            .expect("Failed to connect TCP client to server");
+        client.send(&[1]).await.expect("Failed to send handshake");
+        let mut buffer = Vec::new();
+        client
+            .receive(&mut buffer)
+            .await
+            .expect("Failed to receive reply");
+        assert_eq!(buffer, vec![2]);
+        client
+            .receive(&mut buffer)
+            .await
+            .expect("Failed to receive new client message");
+        assert_eq!(buffer, vec![7, 7]);
+        assert_eq!(server.client_groups(), vec![3]);
+
+        server
+            .broadcast_to_group(3, &[4, 5, 6])
+            .await
+            .expect("Failed to broadcast data");
+        client
+            .receive(&mut buffer)
+            .await
+            .expect("Failed to receive data");
+        assert_eq!(buffer, vec![4, 5, 6]);
+
+        client.send(&[8]).await.expect("Failed to send frame");
+        let (id, frame) = timeout(Duration::from_secs(1), server.receive())
+            .await
+            .expect("Server did not receive the frame")
+            .expect("Server stopped receiving");
+        assert_eq!(frame, vec![8]);
+
+        assert!(server.disconnect_client(id));
+        assert!(client.receive(&mut buffer).await.is_err());
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.get_client_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn message_stream_from_blocking_server_test() {
+        let address = "localhost:50113";
+        let mut server = TcpServer::bind(address).expect("Failed to start TCP server");
+        let client = AsyncTcpClient::connect(address)
+            .await
+            .expect("Failed to connect TCP client to server");
+        sleep(Duration::from_millis(100)).await; // Wait for the server to accept the connection
+
+        let messages = [
+            AudioMessage::EndOfStreamAck {
+                received_samples: 1,
+            },
+            AudioMessage::EndOfStream { total_samples: 2 },
+        ];
+        let mut buf = Vec::new();
+        for message in &messages {
+            buf.clear();
+            message.serialize(&mut buf).expect("Failed to serialize");
+            server.broadcast(&buf).expect("Failed to broadcast data");
+        }
+        // Not a message: reported, then the stream goes on
+        server.broadcast(&[0xFF]).expect("Failed to broadcast data");
+        sleep(Duration::from_millis(100)).await; // Let the writer thread flush the queue
+        drop(server);
+
+        let received: Vec<_> = client.into_messages().collect().await;
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0].as_ref().ok(), Some(&messages[0]));
+        assert_eq!(received[1].as_ref().ok(), Some(&messages[1]));
+        assert!(received[2].is_err());
+    }
+
+    #[tokio::test]
+    async fn blocking_client_test() {
+        let address = "localhost:50114";
+        let mut server = AsyncTcpServer::bind(address)
+            .await
+            .expect("Failed to start TCP server");
+        let mut client = tokio::task::spawn_blocking(move || {
+            TcpClient::connect(address).expect("Failed to connect TCP client to server")
+        })
+        .await
+        .expect("Client task panicked");
+        sleep(Duration::from_millis(100)).await; // Wait for the server to accept the connection
+        server
+            .broadcast(&[1, 2, 3])
+            .await
+            .expect("Failed to broadcast data");
+        let buffer = tokio::task::spawn_blocking(move || {
+            let mut buffer = Vec::new();
+            client.receive(&mut buffer).expect("Failed to receive data");
+            buffer
+        })
+        .await
+        .expect("Client task panicked");
+        assert_eq!(buffer, vec![1, 2, 3]);
+    }
+}
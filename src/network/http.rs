@@ -0,0 +1,531 @@
+//! HTTP streaming: the stream is served to plain HTTP clients, e.g. browsers, `curl` or
+//! VLC, as the body of a response that lasts as long as the stream.
+//!
+//! Frames are written to the body as they are, without the length prefix of the framed
+//! transports, so whatever is broadcast to HTTP clients must already be in the format
+//! announced by the response, e.g. a WAV header followed by samples.
+//!
+//! The listener can also act as an Icecast mount point, interleaving ICY metadata with
+//! the stream for the internet radio players asking for it, and serves a web player
+//! that gets the stream through `network::websocket`.
+
+use crate::network::icy::{ICY_METAINT, IcyInterleaver, StreamTitle};
+use crate::network::transport::{FrameReceiver, FrameSender, TransportError, map_io_error};
+use crate::network::websocket::{self, WEBSOCKET_PATH};
+use log::{debug, info, warn};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Paths the stream is served at.
+pub const STREAM_PATHS: [&str; 2] = ["/", "/stream.wav"];
+/// Path of the web player page.
+pub const PLAYER_PATH: &str = "/player";
+/// Page playing the stream in the browser, from the messages received over WebSocket.
+const PLAYER_PAGE: &str = include_str!("player.html");
+/// Longest request head accepted.
+const MAX_REQUEST_HEAD_SIZE: usize = 8 * 1024;
+/// Longest a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most requests answered at once, each on a thread of its own. Connections coming
+/// beyond are closed right away.
+const MAX_PENDING_REQUESTS: usize = 64;
+
+/// Request line and headers of an HTTP request.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// Path requested, without its query string.
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Parses the head of a request, up to but excluding the blank line ending it.
+    pub(crate) fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?.to_string();
+        let target = request_line.next()?;
+        if !request_line.next()?.starts_with("HTTP/") {
+            return None;
+        }
+        let path = target.split('?').next().unwrap_or(target).to_string();
+        let headers = lines
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect();
+        Some(Request {
+            method,
+            path,
+            headers,
+        })
+    }
+
+    /// Value of the header `name`, whatever its case.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads the head of the request sent on `stream`.
+fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let end = loop {
+        if let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if head.len() > MAX_REQUEST_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request head too large",
+            ));
+        }
+        let read = stream.read(&mut chunk)?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&chunk[..read]);
+    };
+    std::str::from_utf8(&head[..end])
+        .ok()
+        .and_then(Request::parse)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed request"))
+}
+
+/// Writes a response without body.
+pub(crate) fn respond_empty(stream: &mut TcpStream, status: &str, headers: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n{headers}Content-Length: 0\r\nConnection: close\r\n\r\n"
+    )
+}
+
+/// Body of the response sent to an HTTP client, frames are written as they are.
+pub(crate) struct ResponseBody {
+    stream: TcpStream,
+    /// Set for ICY clients, which get metadata amid the frames.
+    icy: Option<IcyInterleaver>,
+    interleaved: Vec<u8>,
+}
+
+impl FrameSender for ResponseBody {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        let data = match &mut self.icy {
+            Some(icy) => {
+                self.interleaved.clear();
+                icy.interleave(data, &mut self.interleaved);
+                &self.interleaved
+            }
+            None => data,
+        };
+        self.stream
+            .write_all(data)
+            .map_err(|e| map_io_error(e, "sending response body"))
+    }
+    fn close(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Reads from an HTTP client until it goes away. Clients have nothing to say once
+/// their request was answered, anything they send is ignored.
+pub(crate) struct ClientHangUp(pub(crate) TcpStream);
+
+impl FrameReceiver for ClientHangUp {
+    fn receive(&mut self, _buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        let mut scratch = [0u8; 1024];
+        loop {
+            match self.0.read(&mut scratch) {
+                Ok(0) => {
+                    return Err(TransportError::Disconnected(
+                        io::ErrorKind::UnexpectedEof.into(),
+                    ));
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(map_io_error(e, "reading from HTTP client")),
+            }
+        }
+    }
+}
+
+/// Takes the clients whose request was accepted, on the thread that answered it.
+pub(crate) type ClientHandler = Arc<dyn Fn(HttpClient) + Send + Sync>;
+
+/// Client whose request was accepted.
+pub(crate) enum HttpClient {
+    /// Gets the stream as the body of the response, whose head was sent. Its stream is
+    /// only read from, to notice when it goes away.
+    Stream {
+        stream: TcpStream,
+        body: ResponseBody,
+    },
+    /// Switched to WebSocket, speaks the protocol of framed clients.
+    WebSocket(TcpStream),
+}
+
+/// Icecast mount point: where it is and what listeners are told about it.
+#[derive(Debug, Clone)]
+struct IcecastMount {
+    path: String,
+    name: String,
+    title: StreamTitle,
+}
+
+/// Server side HTTP socket. Every client requesting one of the `STREAM_PATHS`, or the
+/// Icecast mount point, gets the stream, served by a `TcpServer` through
+/// `TcpServer::listen_http`. So do the WebSocket clients of the page at `PLAYER_PATH`.
+pub struct HttpListener {
+    listener: TcpListener,
+    icecast: Option<IcecastMount>,
+    /// Requests being answered.
+    pending_requests: Arc<AtomicUsize>,
+}
+
+impl HttpListener {
+    pub fn bind(address: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(HttpListener {
+            listener,
+            icecast: None,
+            pending_requests: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Also serves the stream at `path` the way an Icecast mount point named `name`
+    /// does, announcing `title` to the listeners asking for ICY metadata.
+    pub fn mount_icecast(&mut self, path: &str, name: &str, title: StreamTitle) {
+        self.icecast = Some(IcecastMount {
+            path: path.to_string(),
+            name: name.to_string(),
+            title,
+        });
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.listener.set_nonblocking(nonblocking)
+    }
+
+    /// Answers every connection waiting to be accepted, handing every client whose
+    /// request was accepted to `on_client`.
+    pub(crate) fn accept(&self, on_client: &ClientHandler) {
+        loop {
+            let (stream, address) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Could not accept HTTP connection: {}", e);
+                    return;
+                }
+            };
+            // Only this thread adds requests, so they can't go past the limit
+            if self.pending_requests.load(Ordering::Relaxed) >= MAX_PENDING_REQUESTS {
+                warn!(
+                    "Closing HTTP connection from {}: {} requests already pending",
+                    address, MAX_PENDING_REQUESTS
+                );
+                continue;
+            }
+            self.pending_requests.fetch_add(1, Ordering::Relaxed);
+            let pending_requests = Arc::clone(&self.pending_requests);
+            let on_client = Arc::clone(on_client);
+            let icecast = self.icecast.clone();
+            // Requests are read on a thread of their own so a slow client can't hold
+            // back the others
+            thread::spawn(move || {
+                match Self::answer(stream, icecast.as_ref()) {
+                    Ok(Some(client)) => {
+                        info!("Streaming to HTTP client {}", address);
+                        on_client(client);
+                    }
+                    Ok(None) => {}
+                    Err(e) => debug!("Couldn't answer HTTP client {}: {}", address, e),
+                }
+                pending_requests.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+
+    /// Reads the request of a client and answers it, up to the head of the response if
+    /// it must get the stream. Returns the client in that case.
+    fn answer(
+        mut stream: TcpStream,
+        icecast: Option<&IcecastMount>,
+    ) -> io::Result<Option<HttpClient>> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let request = read_request(&mut stream)?;
+        debug!("HTTP request: {:?}", request);
+        if request.method != "GET" && request.method != "HEAD" {
+            respond_empty(
+                &mut stream,
+                "405 Method Not Allowed",
+                "Allow: GET, HEAD\r\n",
+            )?;
+            return Ok(None);
+        }
+        if request.path == PLAYER_PATH {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                PLAYER_PAGE.len()
+            )?;
+            if request.method == "GET" {
+                stream.write_all(PLAYER_PAGE.as_bytes())?;
+            }
+            return Ok(None);
+        }
+        if request.path == WEBSOCKET_PATH {
+            if !websocket::upgrade(&mut stream, &request)? {
+                return Ok(None);
+            }
+            return Ok(Some(HttpClient::WebSocket(stream)));
+        }
+        let mut icy_headers = String::new();
+        let mut icy = None;
+        match icecast {
+            Some(mount) if request.path == mount.path => {
+                icy_headers = format!("icy-name: {}\r\nicy-pub: 0\r\n", mount.name);
+                if request.header("Icy-MetaData") == Some("1") {
+                    icy_headers.push_str(&format!("icy-metaint: {ICY_METAINT}\r\n"));
+                    icy = Some(IcyInterleaver::new(mount.title.clone()));
+                }
+            }
+            _ if STREAM_PATHS.contains(&request.path.as_str()) => {}
+            _ => {
+                respond_empty(&mut stream, "404 Not Found", "")?;
+                return Ok(None);
+            }
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: audio/wav\r\n{icy_headers}Cache-Control: no-cache, no-store\r\nConnection: close\r\n\r\n"
+        )?;
+        if request.method == "HEAD" {
+            return Ok(None);
+        }
+        stream.set_read_timeout(None)?;
+        let body = ResponseBody {
+            stream: stream.try_clone()?,
+            icy,
+            interleaved: Vec::new(),
+        };
+        Ok(Some(HttpClient::Stream { stream, body }))
+    }
+}
+
+impl AsFd for HttpListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HttpListener, MAX_PENDING_REQUESTS, PLAYER_PATH, Request};
+    use crate::network::icy::{ICY_METAINT, StreamTitle};
+    use crate::network::tcp::TcpServer;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_request_head() {
+        let request = Request::parse("GET /stream.wav?t=1 HTTP/1.1\r\nHost: x\r\nIcy-MetaData: 1")
+            .expect("Valid request");
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/stream.wav");
+        assert_eq!(
+            request.headers,
+            vec![
+                ("Host".to_string(), "x".to_string()),
+                ("Icy-MetaData".to_string(), "1".to_string())
+            ]
+        );
+        assert_eq!(request.header("icy-metadata"), Some("1"));
+        assert_eq!(Request::parse("GET /"), None);
+    }
+
+    fn get(address: &str, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(address).expect("Failed to connect");
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .expect("Failed to send request");
+        stream
+    }
+
+    #[test]
+    fn http_stream_test() {
+        let listener = HttpListener::bind("127.0.0.1:0").expect("Failed to bind HTTP listener");
+        let address = listener.local_addr().expect("No local address").to_string();
+        let mut server = TcpServer::bind("127.0.0.1:0").expect("Failed to start server");
+        server.set_group_message(7, b"header");
+        server
+            .listen_http(listener, 7)
+            .expect("Failed to listen for HTTP clients");
+
+        let mut response = String::new();
+        get(&address, "/missing")
+            .read_to_string(&mut response)
+            .expect("Failed to read response");
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+        let mut response = String::new();
+        get(&address, PLAYER_PATH)
+            .read_to_string(&mut response)
+            .expect("Failed to read response");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: text/html"), "{}", response);
+        assert!(response.ends_with("</html>\n"), "{}", response);
+
+        let mut client = get(&address, "/stream.wav");
+        sleep(Duration::from_millis(300)); // Wait for the server to accept the client
+        assert_eq!(server.get_client_count(), 1);
+        server
+            .broadcast_to_group(7, &[1, 2, 3])
+            .expect("Failed to broadcast");
+        server
+            .broadcast_to_group(8, &[4])
+            .expect("Failed to broadcast");
+        assert!(server.finish_client(server.client_ids()[0]));
+
+        let mut response = Vec::new();
+        client
+            .read_to_end(&mut response)
+            .expect("Failed to read response");
+        let head_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("No response head")
+            + 4;
+        let head = String::from_utf8_lossy(&response[..head_end]);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: audio/wav\r\n"), "{}", head);
+        assert_eq!(&response[head_end..], b"header\x01\x02\x03");
+        drop(client);
+        sleep(Duration::from_millis(100)); // Wait for the server to notice the disconnection
+        assert_eq!(server.get_client_count(), 0);
+    }
+    #[test]
+    fn icecast_mount_test() {
+        let mut listener = HttpListener::bind("127.0.0.1:0").expect("Failed to bind HTTP listener");
+        let address = listener.local_addr().expect("No local address").to_string();
+        let title = StreamTitle::new();
+        title.set("Band - Song");
+        listener.mount_icecast("/radio", "Test radio", title.clone());
+        let mut server = TcpServer::bind("127.0.0.1:0").expect("Failed to start server");
+        server
+            .listen_http(listener, 7)
+            .expect("Failed to listen for HTTP clients");
+
+        let mut client = TcpStream::connect(&address).expect("Failed to connect");
+        write!(
+            client,
+            "GET /radio HTTP/1.1\r\nHost: localhost\r\nIcy-MetaData: 1\r\n\r\n"
+        )
+        .expect("Failed to send request");
+        sleep(Duration::from_millis(300)); // Wait for the server to accept the client
+        let audio: Vec<u8> = (0..ICY_METAINT * 3).map(|i| i as u8).collect();
+        server
+            .broadcast_to_group(7, &audio[..ICY_METAINT * 2])
+            .expect("Failed to broadcast");
+        sleep(Duration::from_millis(100)); // Wait for the client to get the first title
+        title.set("Other band - Other song");
+        server
+            .broadcast_to_group(7, &audio[ICY_METAINT * 2..])
+            .expect("Failed to broadcast");
+        assert!(server.finish_client(server.client_ids()[0]));
+
+        let mut response = Vec::new();
+        client
+            .read_to_end(&mut response)
+            .expect("Failed to read response");
+        let head_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("No response head")
+            + 4;
+        let head = String::from_utf8_lossy(&response[..head_end]);
+        assert!(head.contains("icy-name: Test radio\r\n"), "{}", head);
+        let metaint: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("icy-metaint: "))
+            .expect("No icy-metaint header")
+            .parse()
+            .expect("Invalid icy-metaint header");
+
+        // Split the body the way a player does
+        let mut body = &response[head_end..];
+        let mut received = Vec::new();
+        let mut titles = Vec::new();
+        while !body.is_empty() {
+            let (chunk, rest) = body.split_at(metaint.min(body.len()));
+            received.extend_from_slice(chunk);
+            let Some((&blocks, rest)) = rest.split_first() else {
+                break;
+            };
+            let (metadata, rest) = rest.split_at(blocks as usize * 16);
+            if !metadata.is_empty() {
+                titles.push(
+                    String::from_utf8_lossy(metadata)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                );
+            }
+            body = rest;
+        }
+        assert_eq!(received, audio);
+        assert_eq!(
+            titles,
+            vec![
+                "StreamTitle='Band - Song';",
+                "StreamTitle='Other band - Other song';"
+            ]
+        );
+    }
+    #[test]
+    fn pending_requests_test() {
+        let listener = HttpListener::bind("127.0.0.1:0").expect("Failed to bind HTTP listener");
+        let address = listener.local_addr().expect("No local address").to_string();
+        let mut server = TcpServer::bind("127.0.0.1:0").expect("Failed to start server");
+        server
+            .listen_http(listener, 7)
+            .expect("Failed to listen for HTTP clients");
+
+        // Not one sends its request, so all of them stay pending
+        let idle: Vec<TcpStream> = (0..MAX_PENDING_REQUESTS)
+            .map(|_| TcpStream::connect(&address).expect("Failed to connect"))
+            .collect();
+        sleep(Duration::from_millis(300)); // Wait for the server to accept them
+        let mut client = TcpStream::connect(&address).expect("Failed to connect");
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .expect("Failed to set timeout");
+        assert_eq!(client.read(&mut [0u8; 1]).expect("Not closed in time"), 0);
+
+        drop(idle);
+        sleep(Duration::from_millis(300)); // Wait for the server to give up on them
+        let mut response = String::new();
+        get(&address, "/missing")
+            .read_to_string(&mut response)
+            .expect("Failed to read response");
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+}
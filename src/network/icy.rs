@@ -0,0 +1,148 @@
+//! ICY metadata, the way Icecast and SHOUTcast servers tell listeners what is playing.
+//!
+//! A client asking for it with an `Icy-MetaData: 1` request header gets a metadata block
+//! after every `icy-metaint` bytes of audio: one byte giving the length of the block in
+//! 16 byte units, then `StreamTitle='...';` padded with zeros. The block is empty, a
+//! single 0 byte, when the title didn't change since the last one the client got.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Bytes of audio between two metadata blocks, as Icecast sends by default.
+pub const ICY_METAINT: usize = 16_000;
+/// Longest metadata a block can carry.
+const MAX_METADATA_SIZE: usize = 255 * 16;
+
+/// Title announced to ICY listeners, shared between the server and the clients it
+/// streams to.
+#[derive(Debug, Clone, Default)]
+pub struct StreamTitle {
+    /// The title, and how many times it changed.
+    current: Arc<Mutex<(u64, String)>>,
+}
+
+impl StreamTitle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Announces `title` to every listener, with their next metadata block.
+    pub fn set(&self, title: &str) {
+        let mut current = self.lock();
+        if current.1 != title {
+            current.0 += 1;
+            current.1 = title.to_string();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, (u64, String)> {
+        self.current
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Metadata block announcing `title`, cut short if it doesn't fit.
+pub fn metadata_block(title: &str) -> Vec<u8> {
+    // Quotes would end the title early for most parsers
+    let mut title = title.replace('\'', "’");
+    let max_title = MAX_METADATA_SIZE - "StreamTitle='';".len();
+    if title.len() > max_title {
+        let mut end = max_title;
+        while !title.is_char_boundary(end) {
+            end -= 1;
+        }
+        title.truncate(end);
+    }
+    let metadata = format!("StreamTitle='{title}';").into_bytes();
+    let blocks = metadata.len().div_ceil(16);
+    let mut block = Vec::with_capacity(1 + blocks * 16);
+    block.push(blocks as u8);
+    block.extend_from_slice(&metadata);
+    block.resize(1 + blocks * 16, 0);
+    block
+}
+
+/// Inserts the metadata blocks into the audio sent to one client.
+pub(crate) struct IcyInterleaver {
+    title: StreamTitle,
+    /// Bytes of audio left before the next metadata block.
+    until_metadata: usize,
+    /// Version of the title the client got last.
+    sent_version: Option<u64>,
+}
+
+impl IcyInterleaver {
+    pub(crate) fn new(title: StreamTitle) -> Self {
+        IcyInterleaver {
+            title,
+            until_metadata: ICY_METAINT,
+            sent_version: None,
+        }
+    }
+
+    /// Appends `audio` to `out`, with metadata blocks wherever they are due.
+    pub(crate) fn interleave(&mut self, mut audio: &[u8], out: &mut Vec<u8>) {
+        while !audio.is_empty() {
+            let (now, later) = audio.split_at(self.until_metadata.min(audio.len()));
+            out.extend_from_slice(now);
+            audio = later;
+            self.until_metadata -= now.len();
+            if self.until_metadata == 0 {
+                self.write_metadata(out);
+                self.until_metadata = ICY_METAINT;
+            }
+        }
+    }
+
+    fn write_metadata(&mut self, out: &mut Vec<u8>) {
+        let (version, title) = self.title.lock().clone();
+        if self.sent_version == Some(version) {
+            out.push(0);
+        } else {
+            out.extend_from_slice(&metadata_block(&title));
+            self.sent_version = Some(version);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ICY_METAINT, IcyInterleaver, StreamTitle, metadata_block};
+
+    #[test]
+    fn metadata_blocks_are_padded() {
+        let block = metadata_block("Band - Song");
+        assert_eq!(block[0], 2);
+        assert_eq!(block.len(), 33);
+        assert!(block[1..].starts_with(b"StreamTitle='Band - Song';\0"));
+        assert!(metadata_block("It's")[1..].starts_with("StreamTitle='It’s';".as_bytes()));
+        assert_eq!(metadata_block(&"é".repeat(5_000)).len(), 1 + 255 * 16);
+    }
+
+    #[test]
+    fn titles_are_interleaved_when_they_change() {
+        let title = StreamTitle::new();
+        title.set("First");
+        let mut interleaver = IcyInterleaver::new(title.clone());
+        let mut body = Vec::new();
+        interleaver.interleave(&[1; ICY_METAINT / 2], &mut body);
+        interleaver.interleave(&[2; ICY_METAINT * 2], &mut body);
+        title.set("Second");
+        interleaver.interleave(&[3; ICY_METAINT], &mut body);
+
+        let first = metadata_block("First");
+        let second = metadata_block("Second");
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&[1; ICY_METAINT / 2]);
+        expected.extend_from_slice(&[2; ICY_METAINT / 2]);
+        expected.extend_from_slice(&first);
+        expected.extend_from_slice(&[2; ICY_METAINT]);
+        // Nothing new to say
+        expected.push(0);
+        expected.extend_from_slice(&[2; ICY_METAINT / 2]);
+        expected.extend_from_slice(&[3; ICY_METAINT / 2]);
+        expected.extend_from_slice(&second);
+        expected.extend_from_slice(&[3; ICY_METAINT / 2]);
+        assert_eq!(body, expected);
+    }
+}
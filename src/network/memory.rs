@@ -0,0 +1,87 @@
+//! In-process transport, to run clients and servers without sockets.
+
+use crate::network::transport::{FrameReceiver, FrameSender, MAX_FRAME_SIZE, TransportError};
+use std::io;
+use std::sync::mpsc::{Receiver, Sender};
+
+pub struct MemorySender {
+    /// `None` once closed.
+    frames: Option<Sender<Vec<u8>>>,
+}
+
+pub struct MemoryReceiver {
+    frames: Receiver<Vec<u8>>,
+}
+
+/// Creates a one-way in-memory connection. Two of them make a duplex connection.
+pub fn channel() -> (MemorySender, MemoryReceiver) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    (
+        MemorySender {
+            frames: Some(sender),
+        },
+        MemoryReceiver { frames: receiver },
+    )
+}
+
+impl FrameSender for MemorySender {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        if data.len() > MAX_FRAME_SIZE {
+            return Err(TransportError::FrameTooLarge {
+                length: data.len(),
+                max: MAX_FRAME_SIZE,
+            });
+        }
+        let sent = match &self.frames {
+            Some(frames) => frames.send(data.to_vec()).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(TransportError::Disconnected(
+                io::ErrorKind::BrokenPipe.into(),
+            ));
+        }
+        Ok(())
+    }
+    fn close(&mut self) {
+        self.frames = None;
+    }
+}
+
+impl FrameReceiver for MemoryReceiver {
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        let frame = self
+            .frames
+            .recv()
+            .map_err(|_| TransportError::Disconnected(io::ErrorKind::UnexpectedEof.into()))?;
+        *buf = frame;
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::channel;
+    use crate::network::{FrameReceiver, FrameSender, TransportError};
+
+    #[test]
+    fn frames_arrive_in_order_until_closed() {
+        let (mut sender, mut receiver) = channel();
+        sender.send(&[1, 2]).expect("Failed to send frame");
+        sender.send(&[]).expect("Failed to send frame");
+        sender.close();
+        assert!(matches!(
+            sender.send(&[3]),
+            Err(TransportError::Disconnected(_))
+        ));
+
+        let mut buf = Vec::new();
+        assert_eq!(receiver.receive(&mut buf).ok(), Some(2));
+        assert_eq!(buf, vec![1, 2]);
+        assert_eq!(receiver.receive(&mut buf).ok(), Some(0));
+        assert!(matches!(
+            receiver.receive(&mut buf),
+            Err(TransportError::Disconnected(_))
+        ));
+    }
+}
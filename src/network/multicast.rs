@@ -0,0 +1,239 @@
+//! Multicast transport: the server sends every frame once to a multicast group, and any
+//! number of receivers on the network join the group to get it.
+//!
+//! Datagrams are laid out as in `network::udp`. Receivers never talk back, so the server
+//! can't greet the ones joining late. Instead it re-sends an announcement, the stream
+//! spec, at a fixed interval.
+
+use crate::network::transport::{FrameReceiver, FrameSender, TransportError, map_io_error};
+use crate::network::udp::{
+    KIND_CLOSE, KIND_FRAME, LossInjector, MAX_DATAGRAM_SIZE, PEER_TIMEOUT, datagram,
+};
+use log::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// How often the announcement is sent to the group.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest a receiver waits for a datagram before checking for a timeout.
+const RECEIVER_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Sends frames to a multicast group.
+pub struct MulticastSender {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    /// Frame re-sent every `ANNOUNCE_INTERVAL` for receivers joining late.
+    announcement: Option<Vec<u8>>,
+    last_announced: Option<Instant>,
+    loss: Option<LossInjector>,
+    datagram: Vec<u8>,
+}
+
+impl MulticastSender {
+    /// Sends to `group` through the network interface having the address `interface`,
+    /// `Ipv4Addr::UNSPECIFIED` leaving the choice to the routing table.
+    pub fn new(group: SocketAddrV4, interface: Ipv4Addr) -> io::Result<Self> {
+        if !group.ip().is_multicast() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a multicast address", group.ip()),
+            ));
+        }
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_multicast_if_v4(&interface)?;
+        // Receivers on this host get the stream too
+        socket.set_multicast_loop_v4(true)?;
+        socket.bind(&SocketAddr::from((interface, 0)).into())?;
+        Ok(MulticastSender {
+            socket: socket.into(),
+            group,
+            announcement: None,
+            last_announced: None,
+            loss: None,
+            datagram: Vec::new(),
+        })
+    }
+
+    /// Lets the datagrams cross `ttl` routers, 1 keeps them on the local network.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.socket.set_multicast_ttl_v4(ttl)
+    }
+
+    /// Drops some of the datagrams sent, see `LossInjector`.
+    pub fn set_loss(&mut self, loss: LossInjector) {
+        self.loss = Some(loss);
+    }
+
+    /// Sets the frame announced to the group before the next frame sent, then every
+    /// `ANNOUNCE_INTERVAL`. It plays the part `TcpServer::set_new_client_message` plays
+    /// for connected clients.
+    pub fn set_announcement(&mut self, frame: &[u8]) {
+        self.announcement = Some(frame.to_vec());
+        self.last_announced = None;
+    }
+
+    fn announce(&mut self) -> Result<(), TransportError> {
+        if self
+            .last_announced
+            .is_some_and(|announced| announced.elapsed() < ANNOUNCE_INTERVAL)
+        {
+            return Ok(());
+        }
+        let Some(announcement) = self.announcement.take() else {
+            return Ok(());
+        };
+        let result = self.send_datagram(KIND_FRAME, &announcement);
+        self.announcement = Some(announcement);
+        self.last_announced = Some(Instant::now());
+        result
+    }
+
+    fn send_datagram(&mut self, kind: u8, data: &[u8]) -> Result<(), TransportError> {
+        datagram(kind, data, &mut self.datagram)?;
+        if let Some(loss) = &mut self.loss
+            && loss.drops()
+        {
+            debug!(
+                "Dropping datagram of {} bytes to {}",
+                data.len(),
+                self.group
+            );
+            return Ok(());
+        }
+        self.socket
+            .send_to(&self.datagram, self.group)
+            .map_err(|e| map_io_error(e, "sending datagram"))?;
+        Ok(())
+    }
+}
+
+impl FrameSender for MulticastSender {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        self.announce()?;
+        self.send_datagram(KIND_FRAME, data)
+    }
+    fn close(&mut self) {
+        let _ = self.socket.send_to(&[KIND_CLOSE], self.group);
+    }
+}
+
+/// Receives the frames sent to a multicast group.
+pub struct MulticastReceiver {
+    socket: UdpSocket,
+    /// When the last datagram was received, `None` until the first one.
+    last_heard: Option<Instant>,
+    datagram: Vec<u8>,
+}
+
+impl MulticastReceiver {
+    /// Joins `group` on the network interface having the address `interface`. Several
+    /// receivers on the same host can join the same group.
+    pub fn join(group: SocketAddrV4, interface: Ipv4Addr) -> io::Result<Self> {
+        if !group.ip().is_multicast() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a multicast address", group.ip()),
+            ));
+        }
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        // Bound to the group rather than any address, so unicast datagrams sent to the
+        // same port aren't mistaken for frames
+        socket.bind(&SocketAddr::from(group).into())?;
+        socket.join_multicast_v4(group.ip(), &interface)?;
+        socket.set_read_timeout(Some(RECEIVER_POLL_INTERVAL))?;
+        Ok(MulticastReceiver {
+            socket: socket.into(),
+            last_heard: None,
+            datagram: Vec::new(),
+        })
+    }
+}
+
+impl FrameReceiver for MulticastReceiver {
+    /// Waits as long as it takes for the stream to start, but gives up on a stream
+    /// silent for `PEER_TIMEOUT` once it started.
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        self.datagram.resize(MAX_DATAGRAM_SIZE, 0);
+        loop {
+            let length = match self.socket.recv(&mut self.datagram) {
+                Ok(length) => length,
+                Err(ref e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self
+                        .last_heard
+                        .is_some_and(|heard| heard.elapsed() >= PEER_TIMEOUT)
+                    {
+                        return Err(TransportError::Disconnected(io::ErrorKind::TimedOut.into()));
+                    }
+                    continue;
+                }
+                Err(e) => return Err(map_io_error(e, "receiving datagram")),
+            };
+            self.last_heard = Some(Instant::now());
+            match self.datagram[..length].split_first() {
+                Some((&KIND_FRAME, payload)) => {
+                    buf.clear();
+                    buf.extend_from_slice(payload);
+                    return Ok(payload.len());
+                }
+                Some((&KIND_CLOSE, _)) => {
+                    return Err(TransportError::Disconnected(
+                        io::ErrorKind::ConnectionAborted.into(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ANNOUNCE_INTERVAL, MulticastReceiver, MulticastSender};
+    use crate::network::{FrameReceiver, FrameSender, TransportError};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::thread::sleep;
+
+    #[test]
+    fn multicast_stream_test() {
+        // Loopback keeps the test off the network, whatever the routes of the host
+        let interface = Ipv4Addr::LOCALHOST;
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 50, 121), 50121);
+        let mut first = MulticastReceiver::join(group, interface).expect("Failed to join group");
+        let mut sender = MulticastSender::new(group, interface).expect("Failed to open sender");
+        sender.set_announcement(&[0]);
+        sender.send(&[1]).expect("Failed to send frame");
+        sender.send(&[2]).expect("Failed to send frame");
+
+        let mut buffer = Vec::new();
+        for expected in [[0], [1], [2]] {
+            first.receive(&mut buffer).expect("Failed to receive frame");
+            assert_eq!(buffer, expected);
+        }
+
+        // A receiver joining late gets the announcement again once it is due
+        let mut late = MulticastReceiver::join(group, interface).expect("Failed to join group");
+        sender.send(&[3]).expect("Failed to send frame");
+        sleep(ANNOUNCE_INTERVAL);
+        sender.send(&[4]).expect("Failed to send frame");
+        sender.close();
+        for receiver in [&mut first, &mut late] {
+            let mut received = Vec::new();
+            let error = loop {
+                match receiver.receive(&mut buffer) {
+                    Ok(_) => received.push(buffer[0]),
+                    Err(error) => break error,
+                }
+            };
+            assert_eq!(received, vec![3, 0, 4]);
+            assert!(matches!(error, TransportError::Disconnected(_)));
+        }
+    }
+}
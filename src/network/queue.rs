@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// What a broadcast does when a client's outgoing queue is full.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum QueuePolicy {
+    /// Discard the oldest queued frame to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Disconnect the client.
+    DropClient,
+    /// Wait until the client's writer thread made room.
+    Block,
+}
+
+/// Outcome of [`ClientQueue::push`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Pushed {
+    Queued,
+    /// Queued after discarding that many older frames.
+    DroppedOldest(usize),
+    /// The queue is full and the policy says the client must go, or it is closed.
+    Rejected,
+}
+
+/// Frames of a client queue, also used by the async server.
+pub(crate) struct State {
+    pub(crate) frames: VecDeque<Arc<[u8]>>,
+    /// Total length of `frames`.
+    pub(crate) bytes: usize,
+    pub(crate) closed: bool,
+    /// Set once the queue takes no more frames, it closes when the last one is written.
+    pub(crate) finishing: bool,
+}
+
+impl State {
+    pub(crate) fn new() -> Self {
+        State {
+            frames: VecDeque::new(),
+            bytes: 0,
+            closed: false,
+            finishing: false,
+        }
+    }
+    pub(crate) fn push(&mut self, frame: Arc<[u8]>) {
+        self.bytes += frame.len();
+        self.frames.push_back(frame);
+    }
+    pub(crate) fn pop(&mut self) -> Option<Arc<[u8]>> {
+        let frame = self.frames.pop_front()?;
+        self.bytes -= frame.len();
+        Some(frame)
+    }
+    pub(crate) fn clear(&mut self) {
+        self.frames.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Frames waiting to be written to one client, shared between the broadcasting thread
+/// and the client's writer thread.
+pub(crate) struct ClientQueue {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl ClientQueue {
+    pub(crate) fn new() -> Self {
+        ClientQueue {
+            state: Mutex::new(State::new()),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues `frame`, applying `policy` if `capacity` frames are already waiting.
+    pub(crate) fn push(&self, frame: Arc<[u8]>, capacity: usize, policy: QueuePolicy) -> Pushed {
+        let mut state = self.lock();
+        let mut dropped = 0;
+        loop {
+            if state.closed || state.finishing {
+                return Pushed::Rejected;
+            }
+            if state.frames.len() < capacity.max(1) {
+                break;
+            }
+            match policy {
+                QueuePolicy::DropOldest => {
+                    state.pop();
+                    dropped += 1;
+                }
+                QueuePolicy::DropClient => return Pushed::Rejected,
+                QueuePolicy::Block => {
+                    state = self
+                        .changed
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+            }
+        }
+        state.push(frame);
+        self.changed.notify_all();
+        if dropped > 0 {
+            Pushed::DroppedOldest(dropped)
+        } else {
+            Pushed::Queued
+        }
+    }
+
+    /// Waits for the next frame to write, `None` once the queue is closed or finished.
+    pub(crate) fn pop(&self) -> Option<Arc<[u8]>> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(frame) = state.pop() {
+                self.changed.notify_all();
+                return Some(frame);
+            }
+            if state.finishing {
+                return None;
+            }
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// Number of frames and bytes waiting to be written.
+    pub(crate) fn pending(&self) -> (usize, usize) {
+        let state = self.lock();
+        (state.frames.len(), state.bytes)
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    /// Discards pending frames.
+    pub(crate) fn clear(&self) {
+        self.lock().clear();
+        self.changed.notify_all();
+    }
+
+    /// Takes no more frames, the writer stops once it wrote those pending.
+    pub(crate) fn finish(&self) {
+        self.lock().finishing = true;
+        self.changed.notify_all();
+    }
+
+    /// Discards pending frames and wakes up everyone waiting on the queue.
+    pub(crate) fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        state.clear();
+        self.changed.notify_all();
+    }
+}
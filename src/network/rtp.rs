@@ -0,0 +1,722 @@
+//! RTP output (RFC 3550) with an L16 payload (RFC 3551), so standard players such as VLC,
+//! ffmpeg or GStreamer can listen to the stream given its SDP description.
+//!
+//! Samples are sent as big endian 16-bit integers, whatever the source format. RTCP
+//! sender reports go to the next port up so receivers can sync, and an RTCP `BYE` ends
+//! the stream.
+
+use crate::audio::{AudioMessage, Codec, SampleBuffer, SamplesFrame, Serializable};
+use crate::network::transport::{FrameReceiver, TransportError, map_io_error};
+use hound::{SampleFormat, WavSpec};
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const RTP_VERSION: u8 = 2;
+const RTP_HEADER_SIZE: usize = 12;
+/// Most payload bytes per packet, so a packet fits in an Ethernet frame.
+const MAX_PAYLOAD_SIZE: usize = 1_400;
+/// Static payload types of RFC 3551 for 44100 Hz L16.
+const PAYLOAD_TYPE_L16_STEREO: u8 = 10;
+const PAYLOAD_TYPE_L16_MONO: u8 = 11;
+/// First dynamic payload type, used for every other L16 layout.
+const PAYLOAD_TYPE_DYNAMIC: u8 = 96;
+
+const RTCP_SENDER_REPORT: u8 = 200;
+const RTCP_SOURCE_DESCRIPTION: u8 = 202;
+const RTCP_BYE: u8 = 203;
+const SDES_CNAME: u8 = 1;
+/// RFC 3550 recommends at least 5 seconds between reports.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+/// Silence after which a receiver considers the stream gone.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fixed part of an RTP packet header.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RtpHeader {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.push(RTP_VERSION << 6);
+        buf.push(((self.marker as u8) << 7) | (self.payload_type & 0x7f));
+        buf.extend_from_slice(&self.sequence.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.ssrc.to_be_bytes());
+    }
+
+    /// Parses the header of `packet`, returning it along with the payload. Padding,
+    /// contributing sources and header extensions are skipped.
+    pub fn parse(packet: &[u8]) -> Option<(RtpHeader, &[u8])> {
+        if packet.len() < RTP_HEADER_SIZE || packet[0] >> 6 != RTP_VERSION {
+            return None;
+        }
+        let padding = packet[0] & 0x20 != 0;
+        let extension = packet[0] & 0x10 != 0;
+        let contributors = (packet[0] & 0x0f) as usize;
+        let header = RtpHeader {
+            marker: packet[1] & 0x80 != 0,
+            payload_type: packet[1] & 0x7f,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        };
+        let mut start = RTP_HEADER_SIZE + 4 * contributors;
+        if extension {
+            let words = packet.get(start + 2..start + 4)?;
+            start += 4 + 4 * u16::from_be_bytes([words[0], words[1]]) as usize;
+        }
+        let mut end = packet.len();
+        if padding {
+            end = end.checked_sub(*packet.last()? as usize)?;
+        }
+        Some((header, packet.get(start..end)?))
+    }
+}
+
+/// What an SDP file tells about an L16 stream.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SdpDescription {
+    /// Where the RTP packets are sent, RTCP goes to the next port.
+    pub destination: SocketAddr,
+    pub payload_type: u8,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl SdpDescription {
+    /// Writes the session description players open to receive the stream.
+    pub fn to_sdp(&self) -> String {
+        let (family, unspecified) = match self.destination.ip() {
+            IpAddr::V4(_) => ("IP4", "0.0.0.0"),
+            IpAddr::V6(_) => ("IP6", "::"),
+        };
+        let mut connection = self.destination.ip().to_string();
+        if self.destination.ip().is_multicast() && self.destination.is_ipv4() {
+            // Time to live of the multicast packets, required for IPv4 groups
+            connection.push_str("/16");
+        }
+        let session = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() + NTP_UNIX_OFFSET);
+        format!(
+            "v=0\r\n\
+             o=- {session} {session} IN {family} {unspecified}\r\n\
+             s=sonos-challenge\r\n\
+             c=IN {family} {connection}\r\n\
+             t=0 0\r\n\
+             m=audio {port} RTP/AVP {pt}\r\n\
+             a=rtpmap:{pt} L16/{rate}/{channels}\r\n\
+             a=recvonly\r\n",
+            port = self.destination.port(),
+            pt = self.payload_type,
+            rate = self.sample_rate,
+            channels = self.channels,
+        )
+    }
+
+    /// Reads the first L16 audio stream of an SDP file.
+    pub fn parse(sdp: &str) -> Result<Self, String> {
+        let mut address = None;
+        let mut media = None;
+        let mut rtpmap = None;
+        for line in sdp.lines().map(str::trim) {
+            if let Some(connection) = line.strip_prefix("c=IN ") {
+                let host = connection.split_whitespace().nth(1).unwrap_or("");
+                let host = host.split('/').next().unwrap_or("");
+                address = Some(
+                    host.parse::<IpAddr>()
+                        .map_err(|_| format!("Invalid connection address '{}'", host))?,
+                );
+            } else if let Some(description) = line.strip_prefix("m=audio ") {
+                let fields: Vec<&str> = description.split_whitespace().collect();
+                let (Some(port), Some(&"RTP/AVP"), Some(pt)) =
+                    (fields.first(), fields.get(1), fields.get(2))
+                else {
+                    return Err(format!("Unsupported media description '{}'", line));
+                };
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| format!("Invalid port '{}'", port))?;
+                let pt = pt
+                    .parse::<u8>()
+                    .map_err(|_| format!("Invalid payload type '{}'", pt))?;
+                media = Some((port, pt));
+            } else if let Some(map) = line.strip_prefix("a=rtpmap:") {
+                rtpmap = Some(map.to_string());
+            }
+        }
+        let (port, payload_type) = media.ok_or("No audio stream in the description")?;
+        let (sample_rate, channels) = match (payload_type, rtpmap) {
+            (_, Some(map)) => {
+                let mut fields = map.split([' ', '/']);
+                if fields.next() != Some(&payload_type.to_string()) {
+                    return Err(format!("rtpmap '{}' doesn't describe the stream", map));
+                }
+                if !fields
+                    .next()
+                    .is_some_and(|encoding| encoding.eq_ignore_ascii_case("L16"))
+                {
+                    return Err(format!("Only L16 streams are supported, got '{}'", map));
+                }
+                let rate = fields.next().and_then(|rate| rate.parse().ok());
+                let channels = fields.next().map_or(Some(1), |c| c.parse().ok());
+                match (rate, channels) {
+                    (Some(rate), Some(channels)) if rate > 0 && channels > 0 => (rate, channels),
+                    _ => return Err(format!("Invalid rtpmap '{}'", map)),
+                }
+            }
+            (PAYLOAD_TYPE_L16_STEREO, None) => (44_100, 2),
+            (PAYLOAD_TYPE_L16_MONO, None) => (44_100, 1),
+            (pt, None) => return Err(format!("No rtpmap for payload type {}", pt)),
+        };
+        Ok(SdpDescription {
+            destination: SocketAddr::new(address.ok_or("No connection address")?, port),
+            payload_type,
+            sample_rate,
+            channels,
+        })
+    }
+
+    /// Spec of the decoded samples.
+    pub fn spec(&self) -> WavSpec {
+        WavSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        }
+    }
+}
+
+/// Random enough for SSRCs and initial sequence numbers, which only need to differ
+/// between sessions.
+fn random_u32() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    let mixed = (nanos ^ (std::process::id() as u64) << 32).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    (mixed >> 32) as u32
+}
+
+fn ntp_now() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_epoch.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (seconds << 32) | fraction
+}
+
+/// Sends the stream as RTP packets to a unicast or multicast destination.
+pub struct RtpSender {
+    socket: UdpSocket,
+    description: SdpDescription,
+    ssrc: u32,
+    next_sequence: u16,
+    /// RTP timestamp of the first sample of the stream.
+    base_timestamp: u32,
+    last_timestamp: u32,
+    packets: u32,
+    octets: u32,
+    last_report: Option<Instant>,
+    packet: Vec<u8>,
+    samples: Vec<i16>,
+}
+
+impl RtpSender {
+    /// Prepares a stream of the samples described by `spec`, sent to `destination`.
+    pub fn new(destination: SocketAddr, spec: &WavSpec) -> io::Result<Self> {
+        let local = match destination {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local)?;
+        let payload_type = match (spec.sample_rate, spec.channels) {
+            (44_100, 2) => PAYLOAD_TYPE_L16_STEREO,
+            (44_100, 1) => PAYLOAD_TYPE_L16_MONO,
+            _ => PAYLOAD_TYPE_DYNAMIC,
+        };
+        let base_timestamp = random_u32();
+        Ok(RtpSender {
+            socket,
+            description: SdpDescription {
+                destination,
+                payload_type,
+                sample_rate: spec.sample_rate,
+                channels: spec.channels.max(1),
+            },
+            ssrc: random_u32(),
+            next_sequence: random_u32() as u16,
+            base_timestamp,
+            last_timestamp: base_timestamp,
+            packets: 0,
+            octets: 0,
+            last_report: None,
+            packet: Vec::new(),
+            samples: Vec::new(),
+        })
+    }
+
+    pub fn description(&self) -> SdpDescription {
+        self.description
+    }
+
+    /// Lets multicast packets cross `ttl` routers, 1 keeps them on the local network.
+    pub fn set_multicast_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.socket.set_multicast_ttl_v4(ttl)
+    }
+
+    fn rtcp_destination(&self) -> SocketAddr {
+        let mut destination = self.description.destination;
+        destination.set_port(destination.port().wrapping_add(1));
+        destination
+    }
+
+    /// Sends the samples of `frame`, split in as many packets as needed.
+    pub fn send(&mut self, frame: &SamplesFrame) -> io::Result<()> {
+        let channels = self.description.channels as usize;
+        self.samples.clear();
+        frame.samples.extend_i16(&mut self.samples);
+        // Whole sample frames only, a packet never splits the channels of one
+        let per_packet = (MAX_PAYLOAD_SIZE / 2 / channels).max(1) * channels;
+        let first_frame = frame.position / channels as u64;
+        for (index, chunk) in self.samples.chunks(per_packet).enumerate() {
+            let offset = (index * per_packet / channels) as u64;
+            let timestamp = self
+                .base_timestamp
+                .wrapping_add((first_frame + offset) as u32);
+            let header = RtpHeader {
+                // Marks the first packet of the stream
+                marker: self.packets == 0,
+                payload_type: self.description.payload_type,
+                sequence: self.next_sequence,
+                timestamp,
+                ssrc: self.ssrc,
+            };
+            self.packet.clear();
+            header.write(&mut self.packet);
+            self.packet
+                .extend(chunk.iter().flat_map(|sample| sample.to_be_bytes()));
+            self.socket
+                .send_to(&self.packet, self.description.destination)?;
+            self.next_sequence = self.next_sequence.wrapping_add(1);
+            self.last_timestamp = timestamp;
+            self.packets = self.packets.wrapping_add(1);
+            self.octets = self.octets.wrapping_add((chunk.len() * 2) as u32);
+        }
+        if self
+            .last_report
+            .is_none_or(|sent| sent.elapsed() >= REPORT_INTERVAL)
+        {
+            self.send_report(false)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a compound RTCP packet: a sender report, the source description and, at
+    /// the end of the stream, a `BYE`.
+    fn send_report(&mut self, bye: bool) -> io::Result<()> {
+        let mut packet = Vec::new();
+        // Sender report without report blocks: 7 words after the first one
+        packet.extend_from_slice(&[RTP_VERSION << 6, RTCP_SENDER_REPORT]);
+        packet.extend_from_slice(&6u16.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(&ntp_now().to_be_bytes());
+        packet.extend_from_slice(&self.last_timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.packets.to_be_bytes());
+        packet.extend_from_slice(&self.octets.to_be_bytes());
+
+        let cname = format!("sonos-challenge-{:08x}", self.ssrc);
+        let mut chunk = self.ssrc.to_be_bytes().to_vec();
+        chunk.extend_from_slice(&[SDES_CNAME, cname.len() as u8]);
+        chunk.extend_from_slice(cname.as_bytes());
+        // The item list ends with a null octet, then pads the chunk to a whole word
+        chunk.push(0);
+        chunk.resize(chunk.len().div_ceil(4) * 4, 0);
+        packet.extend_from_slice(&[(RTP_VERSION << 6) | 1, RTCP_SOURCE_DESCRIPTION]);
+        packet.extend_from_slice(&((chunk.len() / 4) as u16).to_be_bytes());
+        packet.extend_from_slice(&chunk);
+
+        if bye {
+            packet.extend_from_slice(&[(RTP_VERSION << 6) | 1, RTCP_BYE]);
+            packet.extend_from_slice(&1u16.to_be_bytes());
+            packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        }
+        self.socket.send_to(&packet, self.rtcp_destination())?;
+        self.last_report = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Tells receivers the stream is over.
+    pub fn close(&mut self) -> io::Result<()> {
+        info!("Sent {} RTP packets, ending the RTP stream", self.packets);
+        self.send_report(true)
+    }
+}
+
+/// Receives an L16 RTP stream described by an SDP file, delivering it as the same
+/// `AudioMessage` frames a server sends: a `Spec`, `Samples` frames numbered after the
+/// RTP packets, then `EndOfStream` when the sender says `BYE`.
+///
+/// Packets are delivered as they arrive, lost or reordered ones are left to a
+/// `JitterBuffer`.
+pub struct RtpReceiver {
+    description: SdpDescription,
+    rtp: UdpSocket,
+    rtcp: UdpSocket,
+    /// Messages decoded but not delivered yet.
+    messages: VecDeque<AudioMessage>,
+    ssrc: Option<u32>,
+    highest_sequence: Option<u64>,
+    first_timestamp: Option<u64>,
+    highest_timestamp: u64,
+    /// Position right after the newest samples received.
+    end_position: u64,
+    last_heard: Instant,
+    /// Set once the sender said `BYE`, the stream ends when no packet is left.
+    bye: bool,
+    ended: bool,
+    packet: Vec<u8>,
+}
+
+impl RtpReceiver {
+    /// Listens on the ports of `description`, joining its group if it is multicast. With
+    /// port 0, it listens on free ports instead, see `description`.
+    pub fn bind(mut description: SdpDescription) -> io::Result<Self> {
+        let ip = description.destination.ip();
+        let local = match ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let (rtp, rtcp) = Self::bind_ports(local, description.destination.port())?;
+        description.destination.set_port(rtp.local_addr()?.port());
+        if let IpAddr::V4(group) = ip
+            && group.is_multicast()
+        {
+            rtp.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+            rtcp.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        }
+        rtp.set_read_timeout(Some(Duration::from_millis(100)))?;
+        rtcp.set_nonblocking(true)?;
+        let mut messages = VecDeque::new();
+        messages.push_back(AudioMessage::Spec {
+            spec: description.spec(),
+            codec: Codec::Pcm,
+        });
+        Ok(RtpReceiver {
+            description,
+            rtp,
+            rtcp,
+            messages,
+            ssrc: None,
+            highest_sequence: None,
+            first_timestamp: None,
+            highest_timestamp: 0,
+            end_position: 0,
+            last_heard: Instant::now(),
+            bye: false,
+            ended: false,
+            packet: vec![0; 65_536],
+        })
+    }
+
+    /// Binds the RTP socket on `port` and the RTCP one on the next port, or on the first
+    /// free pair of ports the system gives if `port` is 0.
+    fn bind_ports(ip: IpAddr, port: u16) -> io::Result<(UdpSocket, UdpSocket)> {
+        const ATTEMPTS: usize = 16;
+        if port != 0 {
+            let rtp = UdpSocket::bind((ip, port))?;
+            return Ok((rtp, UdpSocket::bind((ip, port.wrapping_add(1)))?));
+        }
+        let mut attempt = 1;
+        loop {
+            let rtp = UdpSocket::bind((ip, 0))?;
+            let port = rtp.local_addr()?.port();
+            match UdpSocket::bind((ip, port.wrapping_add(1))) {
+                Ok(rtcp) => return Ok((rtp, rtcp)),
+                Err(e) if attempt == ATTEMPTS => return Err(e),
+                Err(_) => attempt += 1,
+            }
+        }
+    }
+
+    /// Description of the stream listened to, on the ports actually bound.
+    pub fn description(&self) -> SdpDescription {
+        self.description
+    }
+
+    /// Reads `received` as following `highest`, allowing for wrapping around.
+    fn extend(highest: u64, received: u64, bits: u32) -> u64 {
+        let modulus = 1u64 << bits;
+        let half = modulus / 2;
+        let candidate = (highest & !(modulus - 1)) | received;
+        if candidate + half < highest {
+            candidate + modulus
+        } else if candidate > highest + half && candidate >= modulus {
+            candidate - modulus
+        } else {
+            candidate
+        }
+    }
+
+    fn handle_rtp(&mut self, length: usize) {
+        let Some((header, payload)) = RtpHeader::parse(&self.packet[..length]) else {
+            debug!("Ignoring invalid RTP packet of {} bytes", length);
+            return;
+        };
+        if header.payload_type != self.description.payload_type {
+            debug!(
+                "Ignoring RTP packet of payload type {}",
+                header.payload_type
+            );
+            return;
+        }
+        if self.ssrc.is_some_and(|ssrc| ssrc != header.ssrc) {
+            debug!("Ignoring RTP packet of another source {:08x}", header.ssrc);
+            return;
+        }
+        self.ssrc = Some(header.ssrc);
+        let sequence = match self.highest_sequence {
+            None => header.sequence as u64,
+            Some(highest) => Self::extend(highest, header.sequence as u64, 16),
+        };
+        self.highest_sequence = Some(self.highest_sequence.map_or(sequence, |h| h.max(sequence)));
+        let timestamp = match self.first_timestamp {
+            None => {
+                self.first_timestamp = Some(header.timestamp as u64);
+                self.highest_timestamp = header.timestamp as u64;
+                header.timestamp as u64
+            }
+            Some(_) => Self::extend(self.highest_timestamp, header.timestamp as u64, 32),
+        };
+        self.highest_timestamp = self.highest_timestamp.max(timestamp);
+        let Some(frames) = timestamp.checked_sub(self.first_timestamp.unwrap_or(timestamp)) else {
+            debug!("Ignoring RTP packet from before the first one received");
+            return;
+        };
+        let samples: Vec<i16> = payload
+            .chunks_exact(2)
+            .map(|bytes| i16::from_be_bytes([bytes[0], bytes[1]]))
+            .collect();
+        let position = frames * self.description.channels as u64;
+        self.end_position = self.end_position.max(position + samples.len() as u64);
+        self.messages.push_back(AudioMessage::Samples(SamplesFrame {
+            sequence,
+            position,
+            samples: SampleBuffer::I16(samples),
+        }));
+    }
+
+    /// Looks for a `BYE` in the RTCP packets received so far.
+    fn poll_rtcp(&mut self) {
+        loop {
+            let length = match self.rtcp.recv(&mut self.packet) {
+                Ok(length) => length,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Error receiving RTCP packet: {}", e);
+                    return;
+                }
+            };
+            self.last_heard = Instant::now();
+            let mut packet = &self.packet[..length];
+            // Walks the packets of the compound packet
+            while packet.len() >= 4 {
+                let packet_type = packet[1];
+                let size = 4 * (u16::from_be_bytes([packet[2], packet[3]]) as usize + 1);
+                if packet_type == RTCP_BYE {
+                    self.bye = true;
+                } else if packet_type == RTCP_SENDER_REPORT {
+                    debug!("Received RTCP sender report");
+                }
+                packet = packet.get(size..).unwrap_or_default();
+            }
+        }
+    }
+}
+
+impl FrameReceiver for RtpReceiver {
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        loop {
+            if let Some(message) = self.messages.pop_front() {
+                buf.clear();
+                if message.serialize(buf).is_err() {
+                    return Err(TransportError::FrameTooLarge {
+                        length: buf.len(),
+                        max: u32::MAX as usize,
+                    });
+                }
+                return Ok(buf.len());
+            }
+            if self.ended {
+                return Err(TransportError::Disconnected(
+                    io::ErrorKind::UnexpectedEof.into(),
+                ));
+            }
+            match self.rtp.recv(&mut self.packet) {
+                Ok(length) => {
+                    self.last_heard = Instant::now();
+                    self.handle_rtp(length);
+                }
+                // Packets sent before the BYE may still be waiting, until a read
+                // times out
+                Err(ref e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self.bye {
+                        self.ended = true;
+                        self.messages.push_back(AudioMessage::EndOfStream {
+                            total_samples: self.end_position,
+                        });
+                        continue;
+                    }
+                }
+                Err(e) => return Err(map_io_error(e, "receiving RTP packet")),
+            }
+            self.poll_rtcp();
+            if self.messages.is_empty() && self.last_heard.elapsed() >= RECEIVE_TIMEOUT {
+                return Err(TransportError::Disconnected(io::ErrorKind::TimedOut.into()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PAYLOAD_TYPE_DYNAMIC, RtpHeader, RtpReceiver, RtpSender, SdpDescription};
+    use crate::audio::{AudioMessage, SampleBuffer, SamplesFrame, Serializable};
+    use crate::network::{FrameReceiver, TransportError};
+    use hound::{SampleFormat, WavSpec};
+
+    #[test]
+    fn header_round_trip() {
+        let header = RtpHeader {
+            marker: true,
+            payload_type: 96,
+            sequence: 65_535,
+            timestamp: 0xdead_beef,
+            ssrc: 42,
+        };
+        let mut packet = Vec::new();
+        header.write(&mut packet);
+        packet.extend_from_slice(&[1, 2]);
+        assert_eq!(RtpHeader::parse(&packet), Some((header, &[1u8, 2][..])));
+        assert_eq!(RtpHeader::parse(&packet[..8]), None);
+    }
+
+    #[test]
+    fn sdp_round_trip() {
+        let description = SdpDescription {
+            destination: "239.1.2.3:5004".parse().unwrap(),
+            payload_type: 96,
+            sample_rate: 48_000,
+            channels: 2,
+        };
+        let sdp = description.to_sdp();
+        assert!(sdp.contains("c=IN IP4 239.1.2.3/16\r\n"));
+        assert!(sdp.contains("a=rtpmap:96 L16/48000/2\r\n"));
+        assert_eq!(SdpDescription::parse(&sdp), Ok(description));
+        // Static payload types don't need an rtpmap
+        let minimal = "v=0\nc=IN IP4 127.0.0.1\nm=audio 5004 RTP/AVP 11\n";
+        let parsed = SdpDescription::parse(minimal).expect("Failed to parse SDP");
+        assert_eq!((parsed.sample_rate, parsed.channels), (44_100, 1));
+        // Nothing could be played at such a rate or with no channel
+        for rtpmap in ["L16/0/2", "L16/48000/0"] {
+            let sdp =
+                format!("c=IN IP4 127.0.0.1\nm=audio 5004 RTP/AVP 96\na=rtpmap:96 {rtpmap}\n");
+            assert!(SdpDescription::parse(&sdp).is_err(), "{}", rtpmap);
+        }
+    }
+
+    #[test]
+    fn extends_wrapping_counters() {
+        assert_eq!(RtpReceiver::extend(65_535, 0, 16), 65_536);
+        assert_eq!(RtpReceiver::extend(65_536, 65_535, 16), 65_535);
+        assert_eq!(RtpReceiver::extend(70_000, 4_466, 16), 70_002);
+        assert_eq!(RtpReceiver::extend(10, 8, 16), 8);
+    }
+
+    #[test]
+    fn rtp_stream_test() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 48_000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let description = SdpDescription {
+            destination: "127.0.0.1:0".parse().unwrap(),
+            payload_type: PAYLOAD_TYPE_DYNAMIC,
+            sample_rate: spec.sample_rate,
+            channels: spec.channels,
+        };
+        let mut receiver = RtpReceiver::bind(description).expect("Failed to bind RTP receiver");
+        let description = receiver.description();
+        assert_ne!(description.destination.port(), 0);
+        let mut sender =
+            RtpSender::new(description.destination, &spec).expect("Failed to create RTP sender");
+        assert_eq!(
+            SdpDescription::parse(&sender.description().to_sdp()),
+            Ok(description)
+        );
+
+        // 1000 samples don't fit in one packet
+        let samples: Vec<i16> = (0..1_000).map(|i| i * 30 - 15_000).collect();
+        for sequence in 0..2 {
+            let frame = SamplesFrame {
+                sequence,
+                position: sequence * 1_000,
+                samples: SampleBuffer::I16(samples.clone()),
+            };
+            sender.send(&frame).expect("Failed to send frame");
+        }
+        sender.close().expect("Failed to end stream");
+
+        let mut buffer = Vec::new();
+        let mut messages = Vec::new();
+        while receiver.receive(&mut buffer).is_ok() {
+            messages.push(AudioMessage::deserialize(&buffer).expect("Invalid message"));
+        }
+        assert!(
+            matches!(messages[0], AudioMessage::Spec { spec: received, .. } if received == spec)
+        );
+        let mut received = Vec::new();
+        let mut position = 0;
+        for message in &messages[1..messages.len() - 1] {
+            let AudioMessage::Samples(frame) = message else {
+                panic!("Expected samples, got {:?}", message);
+            };
+            assert_eq!(frame.position, position);
+            let SampleBuffer::I16(chunk) = &frame.samples else {
+                panic!("Expected 16-bit samples");
+            };
+            assert_eq!(chunk.len() % 2, 0);
+            position += chunk.len() as u64;
+            received.extend_from_slice(chunk);
+        }
+        assert_eq!(received, [samples.clone(), samples].concat());
+        assert_eq!(
+            messages.last(),
+            Some(&AudioMessage::EndOfStream {
+                total_samples: 2_000
+            })
+        );
+        assert!(matches!(
+            receiver.receive(&mut buffer),
+            Err(TransportError::Disconnected(_))
+        ));
+    }
+}
@@ -1,109 +1,476 @@
+mod event_loop;
+mod socket;
+
+use crate::network::http::{ClientHandler, ClientHangUp, HttpClient, HttpListener};
+use crate::network::queue::{ClientQueue, Pushed, QueuePolicy};
+use crate::network::transport::{FrameReceiver, FrameSender, TransportError};
+use crate::network::udp::UdpListener;
+use crate::network::websocket;
+use event_loop::{Accepted, EventLoop, Incoming, Shared};
 use log::{debug, error, info, warn};
-use std::collections::VecDeque;
-use std::io::{Read, Write};
+use polling::Poller;
+use socket::Listener;
+use std::collections::{HashMap, VecDeque};
 use std::net::{TcpListener, TcpStream};
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{io, thread};
 
+/// Decision taken on the first frame a new client sends.
+pub enum Handshake {
+    /// Keep the client and send it the reply frame.
+    Accept(Vec<u8>),
+    /// Like `Accept`, but the client only receives broadcasts sent to `group`.
+    AcceptInGroup { reply: Vec<u8>, group: ClientGroup },
+    /// Send the reply frame (if not empty) and close the connection.
+    Reject(Vec<u8>),
+    /// Like `AcceptInGroup`, for a client picking the stream up at `position`: the
+    /// frames the replay handler gives for it are sent right after the new client
+    /// message of `group`, ahead of any broadcast.
+    Resume {
+        reply: Vec<u8>,
+        group: ClientGroup,
+        position: u64,
+    },
+}
+
+pub(crate) type HandshakeHandler = Box<dyn Fn(&[u8]) -> Handshake + Send>;
+/// Gives the frames a client resuming the stream must be sent first, once called.
+pub(crate) type Replay = Box<dyn FnOnce() -> Vec<Vec<u8>> + Send>;
+/// Gives the `Replay` of a client of a group resuming at a position.
+pub(crate) type ReplayHandler = Box<dyn Fn(ClientGroup, u64) -> Replay + Send>;
+
+/// Boxes `handler` along with the replays it gives.
+fn boxed_replay_handler<F, R>(handler: F) -> ReplayHandler
+where
+    F: Fn(ClientGroup, u64) -> R + Send + 'static,
+    R: FnOnce() -> Vec<Vec<u8>> + Send + 'static,
+{
+    Box::new(move |group, position| -> Replay { Box::new(handler(group, position)) })
+}
+
+pub type ClientId = u64;
+/// Clients sharing what they are sent, e.g. the codec negotiated at handshake.
+pub type ClientGroup = u8;
+
+/// How far behind a client is: the broadcasts waiting in its outgoing queue, not the
+/// frames it is caught up with when it joins.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ClientLag {
+    pub id: ClientId,
+    pub group: ClientGroup,
+    pub queued_frames: usize,
+    pub queued_bytes: usize,
+}
+
+/// Unblocks the writer thread of a client that stopped reading.
+type ShutdownHook = Box<dyn Fn() + Send>;
+
+struct Client {
+    id: ClientId,
+    group: ClientGroup,
+    /// Frames waiting for the client's writer thread.
+    queue: Arc<ClientQueue>,
+    /// `None` for transports whose sends never block.
+    shutdown: Option<ShutdownHook>,
+}
+
+impl Client {
+    /// Stops the writer thread of the client, which closes the connection.
+    fn disconnect(&self) {
+        self.queue.close();
+        if let Some(shutdown) = &self.shutdown {
+            shutdown();
+        }
+    }
+}
+
 pub struct TcpServer {
-    streams: Arc<Mutex<VecDeque<TcpStream>>>,
+    streams: Arc<Mutex<VecDeque<Client>>>,
     new_client_message: Arc<Mutex<Vec<u8>>>,
+    group_messages: Arc<Mutex<HashMap<ClientGroup, Vec<u8>>>>,
+    handshake_handler: Arc<Mutex<Option<HandshakeHandler>>>,
+    replay_handler: Arc<Mutex<Option<ReplayHandler>>>,
+    incoming: Receiver<(ClientId, Vec<u8>)>,
+    queue_capacity: usize,
+    queue_policy: QueuePolicy,
     handle: Option<thread::JoinHandle<()>>,
     shutdown: Arc<AtomicBool>,
+    /// Wakes up the server thread when it must stop or serve a new client.
+    poller: Arc<Poller>,
+    accepted: Sender<Accepted>,
+    listeners: Sender<Listener>,
+    /// Threads of the UDP and HTTP listeners.
+    listener_threads: Vec<thread::JoinHandle<()>>,
 }
 
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Frames each client may have waiting before the queue policy kicks in.
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
 impl TcpServer {
-    fn send_frame(stream: &mut TcpStream, data: &[u8], context: &str) -> bool {
-        let len_bytes = (data.len() as u32).to_le_bytes();
-        let result = stream
-            .write_all(&len_bytes)
-            .and_then(|_| stream.write_all(data));
-
-        if let Err(e) = result {
-            let is_disconnect = matches!(
-                e.kind(),
-                io::ErrorKind::BrokenPipe
-                    | io::ErrorKind::ConnectionReset
-                    | io::ErrorKind::ConnectionAborted
-                    | io::ErrorKind::UnexpectedEof
-            );
-            if is_disconnect {
+    fn send_frame(sender: &mut dyn FrameSender, data: &[u8], context: &str) -> bool {
+        match sender.send(data) {
+            Ok(()) => true,
+            Err(TransportError::Disconnected(e)) => {
                 info!("Client disconnected while {}: {}", context, e);
-            } else {
-                warn!("I/O error while {}: {}", context, e);
+                false
+            }
+            Err(e) => {
+                warn!("Error while {}: {:?}", context, e);
+                false
             }
-            return false;
         }
-
-        true
     }
-    pub fn bind(address: &str) -> io::Result<Self> {
-        let listener = TcpListener::bind(address)?;
-        listener.set_nonblocking(true)?;
-
-        let streams = Arc::new(Mutex::new(VecDeque::new()));
-        let streams_for_thread = Arc::clone(&streams);
-
-        let shutdown = Arc::new(AtomicBool::new(false));
-        let shutdown_for_thread = Arc::clone(&shutdown);
-
-        let new_client_message = Arc::new(Mutex::new(Vec::new()));
-        let new_client_message_for_thread = Arc::clone(&new_client_message);
-        let handle = thread::spawn(move || {
-            loop {
-                if shutdown_for_thread.load(Ordering::Relaxed) {
-                    info!("Shutting down TCP server listener thread");
-                    break;
+    /// Runs the handshake handler, if any, on a freshly accepted connection.
+    /// Returns the group of the client and where it resumes the stream, if it does, or
+    /// `None` when it must be dropped.
+    fn handshake(
+        receiver: &mut dyn FrameReceiver,
+        sender: &mut dyn FrameSender,
+        handler: &Mutex<Option<HandshakeHandler>>,
+    ) -> Option<(ClientGroup, Option<u64>)> {
+        let lock_handler = || {
+            handler.lock().unwrap_or_else(|poisoned| {
+                error!("handshake_handler mutex poisoned");
+                poisoned.into_inner()
+            })
+        };
+        if lock_handler().is_none() {
+            return Some((0, None));
+        }
+        // The handler is only locked once the request arrived, so a silent client
+        // doesn't hold back the handshakes of others
+        let mut request = Vec::new();
+        if let Err(e) = receiver.receive(&mut request) {
+            warn!("Client did not complete the handshake: {:?}", e);
+            return None;
+        }
+        let decision = match lock_handler().as_ref() {
+            Some(handler) => handler(&request),
+            None => Handshake::Accept(Vec::new()),
+        };
+        let (reply, group, resume) = match decision {
+            Handshake::Accept(reply) => (reply, 0, None),
+            Handshake::AcceptInGroup { reply, group } => (reply, group, None),
+            Handshake::Resume {
+                reply,
+                group,
+                position,
+            } => (reply, group, Some(position)),
+            Handshake::Reject(reply) => {
+                if !reply.is_empty() {
+                    Self::send_frame(sender, &reply, "rejecting handshake");
                 }
-                match listener.accept() {
-                    Ok((mut stream, _)) => {
-                        match stream.peer_addr() {
-                            Ok(addr) => info!("Accepted connection from {}", addr),
-                            Err(e) => error!("Could not get peer address: {}", e),
-                        }
-                        let data =
-                            new_client_message_for_thread
-                                .lock()
-                                .unwrap_or_else(|poisoned| {
-                                    error!("new_client_message mutex poisoned");
-                                    poisoned.into_inner()
-                                });
-                        if !data.is_empty() {
-                            debug!("Sending {} bytes to new client", data.len());
-                            if !Self::send_frame(&mut stream, &data, "sending new client message") {
-                                continue;
-                            }
-                            // the stream is closed on drop, when the variable goes out of scope
-                        }
-                        match streams_for_thread.lock() {
-                            Ok(mut streams) => streams.push_front(stream),
-                            Err(_) => {
-                                error!("streams mutex poisoned");
-                            }
-                        }
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        // no pending connection; give up the CPU a bit
-                        thread::sleep(Duration::from_millis(50));
-                        continue;
-                    }
-                    Err(e) => {
-                        error!("Could not accept connection: {}", e);
-                        continue;
+                info!("Rejected client during handshake");
+                return None;
+            }
+        };
+        Self::send_frame(sender, &reply, "answering handshake").then_some((group, resume))
+    }
+    /// Writes the new client `message` and the frames of `replay`, then those queued for
+    /// a client until its queue is closed, then closes the connection. The catch-up
+    /// frames never wait in the queue, so they don't count as lag.
+    fn write_client_frames(
+        id: ClientId,
+        mut sender: Box<dyn FrameSender>,
+        message: Vec<u8>,
+        replay: Option<Replay>,
+        queue: Arc<ClientQueue>,
+    ) {
+        let replayed = replay.map_or_else(Vec::new, |replay| replay());
+        if !replayed.is_empty() {
+            debug!("Replaying {} frames to client {}", replayed.len(), id);
+        }
+        let caught_up = std::iter::once(message)
+            .filter(|message| !message.is_empty())
+            .chain(replayed)
+            .all(|frame| Self::send_frame(&mut *sender, &frame, "sending catch-up frame"));
+        while caught_up && let Some(frame) = queue.pop() {
+            if !Self::send_frame(&mut *sender, &frame, "sending queued frame") {
+                debug!("Stopping writer of client {}", id);
+                break;
+            }
+        }
+        queue.close();
+        sender.close();
+    }
+    /// Forwards every frame a client sends to the server until its connection or queue
+    /// is closed, for clients the event loop doesn't read.
+    fn read_client_frames(
+        id: ClientId,
+        mut receiver: Box<dyn FrameReceiver>,
+        queue: Arc<ClientQueue>,
+        streams: &Mutex<VecDeque<Client>>,
+        incoming: Sender<(ClientId, Vec<u8>)>,
+    ) {
+        let mut buf = Vec::new();
+        loop {
+            match receiver.receive(&mut buf) {
+                Ok(_) => {
+                    if queue.is_closed() || incoming.send((id, std::mem::take(&mut buf))).is_err() {
+                        break;
                     }
                 }
+                Err(TransportError::Disconnected(_)) => {
+                    info!("Client {} disconnected", id);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Error reading from client {}: {:?}", id, e);
+                    break;
+                }
             }
+        }
+        Self::remove_client(streams, id);
+    }
+    /// Forgets a client, stopping its writer thread.
+    fn remove_client(streams: &Mutex<VecDeque<Client>>, id: ClientId) {
+        let mut streams = streams.lock().unwrap_or_else(|poisoned| {
+            error!("streams mutex poisoned");
+            poisoned.into_inner()
+        });
+        streams.retain(|client| {
+            if client.id == id {
+                client.queue.close();
+            }
+            client.id != id
         });
+    }
+    pub fn bind(address: &str) -> io::Result<Self> {
+        Self::serve(vec![Listener::Tcp(TcpListener::bind(address)?)], None, None)
+    }
+
+    /// Like `bind`, with the handshake and replay handlers installed before the first
+    /// client is accepted, so that none skips them. See `set_handshake_handler` and
+    /// `set_replay_handler`.
+    pub fn bind_with<H, F, R>(address: &str, handshake: H, replay: F) -> io::Result<Self>
+    where
+        H: Fn(&[u8]) -> Handshake + Send + 'static,
+        F: Fn(ClientGroup, u64) -> R + Send + 'static,
+        R: FnOnce() -> Vec<Vec<u8>> + Send + 'static,
+    {
+        Self::serve(
+            vec![Listener::Tcp(TcpListener::bind(address)?)],
+            Some(Box::new(handshake)),
+            Some(boxed_replay_handler(replay)),
+        )
+    }
+
+    /// Listens on a Unix domain socket at `path` only, replacing any socket file
+    /// already there.
+    pub fn bind_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::serve(vec![Listener::bind_unix(path.as_ref())?], None, None)
+    }
+
+    /// Also accepts clients on a Unix domain socket at `path`. They are served exactly
+    /// like TCP clients.
+    pub fn listen_unix(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let listener = Listener::bind_unix(path.as_ref())?;
+        info!("Listening on {}", path.as_ref().display());
+        self.listeners
+            .send(listener)
+            .map_err(|_| io::Error::other("TCP server thread stopped"))?;
+        self.poller.notify()
+    }
+
+    fn serve(
+        listeners: Vec<Listener>,
+        handshake_handler: Option<HandshakeHandler>,
+        replay_handler: Option<ReplayHandler>,
+    ) -> io::Result<Self> {
+        let poller = Arc::new(Poller::new()?);
+        let streams = Arc::new(Mutex::new(VecDeque::new()));
+        let new_client_message = Arc::new(Mutex::new(Vec::new()));
+        let group_messages = Arc::new(Mutex::new(HashMap::new()));
+        let handshake_handler = Arc::new(Mutex::new(handshake_handler));
+        let replay_handler = Arc::new(Mutex::new(replay_handler));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (incoming_sender, incoming) = channel();
+        let (accepted, accepted_receiver) = channel();
+        let (new_listeners, new_listeners_receiver) = channel();
+
+        let event_loop = EventLoop::new(
+            listeners,
+            Shared {
+                streams: Arc::clone(&streams),
+                new_client_message: Arc::clone(&new_client_message),
+                group_messages: Arc::clone(&group_messages),
+                handshake_handler: Arc::clone(&handshake_handler),
+                replay_handler: Arc::clone(&replay_handler),
+                incoming: incoming_sender,
+                shutdown: Arc::clone(&shutdown),
+                poller: Arc::clone(&poller),
+                accepted: accepted.clone(),
+            },
+            new_listeners_receiver,
+            accepted_receiver,
+        )?;
+        let handle = thread::spawn(move || event_loop.run());
         Ok(TcpServer {
             streams,
             new_client_message,
+            group_messages,
+            handshake_handler,
+            replay_handler,
+            incoming,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            queue_policy: QueuePolicy::default(),
             handle: Some(handle),
             shutdown,
+            poller,
+            accepted,
+            listeners: new_listeners,
+            listener_threads: Vec::new(),
+        })
+    }
+
+    /// Serves a client connected through another transport than the TCP listener, e.g.
+    /// a Unix socket or an in-memory channel. It goes through the handshake and gets the
+    /// broadcasts like any other client.
+    pub fn add_client(&mut self, sender: Box<dyn FrameSender>, receiver: Box<dyn FrameReceiver>) {
+        Self::spawn_handshake(
+            Arc::clone(&self.handshake_handler),
+            self.accepted.clone(),
+            Arc::clone(&self.poller),
+            sender,
+            receiver,
+        );
+    }
+
+    /// Also serves every peer `listener` hears from, like clients added with
+    /// `add_client`. The listener stops with the server.
+    pub fn listen_udp(&mut self, listener: UdpListener) {
+        if let Ok(address) = listener.local_addr() {
+            info!("Listening for UDP clients on {}", address);
+        }
+        let handler = Arc::clone(&self.handshake_handler);
+        let accepted = self.accepted.clone();
+        let poller = Arc::clone(&self.poller);
+        let shutdown = Arc::clone(&self.shutdown);
+        let handle = thread::spawn(move || {
+            listener.run(&shutdown, |sender, receiver| {
+                Self::spawn_handshake(
+                    Arc::clone(&handler),
+                    accepted.clone(),
+                    Arc::clone(&poller),
+                    sender,
+                    receiver,
+                )
+            })
+        });
+        self.listener_threads.push(handle);
+    }
+
+    /// Also serves every client `listener` accepts. Those getting the stream over plain
+    /// HTTP are served in `group` and without handshake since they only speak HTTP: they
+    /// get the new client message of `group` first, then its broadcasts. WebSocket
+    /// clients are served like TCP clients. The listener stops with the server.
+    pub fn listen_http(&mut self, listener: HttpListener, group: ClientGroup) -> io::Result<()> {
+        if let Ok(address) = listener.local_addr() {
+            info!("Listening for HTTP clients on {}", address);
+        }
+        let handler = Arc::clone(&self.handshake_handler);
+        let accepted = self.accepted.clone();
+        let poller = Arc::clone(&self.poller);
+        let on_client: ClientHandler = Arc::new(move |client| {
+            let client = match client {
+                HttpClient::Stream { stream, body } => {
+                    Self::shutdown_hook(&stream).map(|shutdown| Accepted {
+                        group,
+                        resume: None,
+                        sender: Box::new(body),
+                        receiver: Incoming::Threaded(Box::new(ClientHangUp(stream))),
+                        shutdown: Some(shutdown),
+                    })
+                }
+                HttpClient::WebSocket(stream) => Self::accept_websocket(stream, &handler),
+            };
+            if let Some(client) = client {
+                event_loop::hand_over(&accepted, &poller, client);
+            }
+        });
+        self.listeners
+            .send(Listener::Http {
+                listener,
+                on_client,
+            })
+            .map_err(|_| io::Error::other("TCP server thread stopped"))?;
+        self.poller.notify()
+    }
+
+    /// Unblocks the writer thread of a client connected through `stream`.
+    fn shutdown_hook(stream: &TcpStream) -> Option<ShutdownHook> {
+        match stream.try_clone() {
+            Ok(stream) => Some(Box::new(move || {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            })),
+            Err(e) => {
+                error!("Could not clone client stream: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Runs the handshake of a client upgraded to WebSocket, on the thread that
+    /// answered its request. Returns the client unless it must be dropped.
+    fn accept_websocket(
+        stream: TcpStream,
+        handler: &Mutex<Option<HandshakeHandler>>,
+    ) -> Option<Accepted> {
+        let shutdown = Self::shutdown_hook(&stream)?;
+        let split = stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .and_then(|_| Ok((stream.try_clone()?, websocket::split(stream)?)));
+        let (stream, (mut sender, mut receiver)) = match split {
+            Ok(split) => split,
+            Err(e) => {
+                error!("Could not serve WebSocket client: {}", e);
+                return None;
+            }
+        };
+        let (group, resume) = Self::handshake(&mut receiver, &mut sender, handler)?;
+        if let Err(e) = stream.set_read_timeout(None) {
+            error!("Could not serve WebSocket client: {}", e);
+            return None;
+        }
+        Some(Accepted {
+            group,
+            resume,
+            sender: Box::new(sender),
+            receiver: Incoming::Threaded(Box::new(receiver)),
+            shutdown: Some(shutdown),
         })
     }
 
+    /// Runs the handshake of a client served through its own threads on a thread of its
+    /// own, then hands it over to the event loop.
+    fn spawn_handshake(
+        handler: Arc<Mutex<Option<HandshakeHandler>>>,
+        accepted: Sender<Accepted>,
+        poller: Arc<Poller>,
+        mut sender: Box<dyn FrameSender>,
+        mut receiver: Box<dyn FrameReceiver>,
+    ) {
+        thread::spawn(move || {
+            let Some((group, resume)) = Self::handshake(&mut *receiver, &mut *sender, &handler)
+            else {
+                return;
+            };
+            let client = Accepted {
+                group,
+                resume,
+                sender,
+                receiver: Incoming::Threaded(receiver),
+                shutdown: None,
+            };
+            event_loop::hand_over(&accepted, &poller, client);
+        });
+    }
+
     pub fn set_new_client_message(&mut self, data: &[u8]) {
         if data.len() > u32::MAX as usize {
             info!(
@@ -120,6 +487,58 @@ impl TcpServer {
         debug!("Set new client message of {} bytes", data.len());
     }
 
+    /// Sets how many frames each client may have waiting to be written.
+    pub fn set_queue_capacity(&mut self, frames: usize) {
+        self.queue_capacity = frames.max(1);
+    }
+
+    /// Sets what broadcasts do for a client whose queue is full.
+    pub fn set_queue_policy(&mut self, policy: QueuePolicy) {
+        self.queue_policy = policy;
+    }
+
+    /// Replaces the new client message for clients accepted in `group`.
+    pub fn set_group_message(&mut self, group: ClientGroup, data: &[u8]) {
+        let mut messages = self.group_messages.lock().unwrap_or_else(|poisoned| {
+            error!("group_messages mutex poisoned");
+            poisoned.into_inner()
+        });
+        messages.insert(group, data.to_vec());
+        debug!("Set group {} message of {} bytes", group, data.len());
+    }
+
+    /// Installs a handler that receives the first frame of every new client and decides
+    /// whether to keep it. Without a handler clients are accepted straight away, so those
+    /// connecting before it is installed skip it: `bind_with` avoids that.
+    pub fn set_handshake_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&[u8]) -> Handshake + Send + 'static,
+    {
+        let mut current = self.handshake_handler.lock().unwrap_or_else(|poisoned| {
+            error!("handshake_handler mutex poisoned");
+            poisoned.into_inner()
+        });
+        *current = Some(Box::new(handler));
+    }
+
+    /// Installs a handler giving the frames a client accepted with `Handshake::Resume`
+    /// gets before any broadcast, e.g. those it missed while disconnected. It is called
+    /// while broadcasts wait, so a frame broadcast before the client joins must already
+    /// be among those it returns. Those are only built when the closure it returns is
+    /// called, by the writer of the client once broadcasts went on: the handler itself
+    /// should do no more than take a snapshot.
+    pub fn set_replay_handler<F, R>(&mut self, handler: F)
+    where
+        F: Fn(ClientGroup, u64) -> R + Send + 'static,
+        R: FnOnce() -> Vec<Vec<u8>> + Send + 'static,
+    {
+        let mut current = self.replay_handler.lock().unwrap_or_else(|poisoned| {
+            error!("replay_handler mutex poisoned");
+            poisoned.into_inner()
+        });
+        *current = Some(boxed_replay_handler(handler));
+    }
+
     pub fn get_client_count(&self) -> usize {
         let streams = self.streams.lock().unwrap_or_else(|poisoned| {
             error!("streams mutex poisoned");
@@ -128,37 +547,160 @@ impl TcpServer {
         streams.len()
     }
 
+    pub fn client_ids(&self) -> Vec<ClientId> {
+        let streams = self.streams.lock().unwrap_or_else(|poisoned| {
+            error!("streams mutex poisoned");
+            poisoned.into_inner()
+        });
+        streams.iter().map(|client| client.id).collect()
+    }
+
+    /// Groups having at least one connected client.
+    pub fn client_groups(&self) -> Vec<ClientGroup> {
+        let streams = self.streams.lock().unwrap_or_else(|poisoned| {
+            error!("streams mutex poisoned");
+            poisoned.into_inner()
+        });
+        let mut groups: Vec<ClientGroup> = streams.iter().map(|client| client.group).collect();
+        groups.sort_unstable();
+        groups.dedup();
+        groups
+    }
+
+    /// Outgoing backlog of every client.
+    pub fn client_lags(&self) -> Vec<ClientLag> {
+        let streams = self.streams.lock().unwrap_or_else(|poisoned| {
+            error!("streams mutex poisoned");
+            poisoned.into_inner()
+        });
+        streams
+            .iter()
+            .map(|client| {
+                let (queued_frames, queued_bytes) = client.queue.pending();
+                ClientLag {
+                    id: client.id,
+                    group: client.group,
+                    queued_frames,
+                    queued_bytes,
+                }
+            })
+            .collect()
+    }
+
+    /// Closes the connection of a client. Returns false if it was already gone.
+    pub fn disconnect_client(&mut self, id: ClientId) -> bool {
+        let mut streams = self.streams.lock().unwrap_or_else(|poisoned| {
+            error!("streams mutex poisoned");
+            poisoned.into_inner()
+        });
+        let Some(index) = streams.iter().position(|client| client.id == id) else {
+            return false;
+        };
+        if let Some(client) = streams.remove(index) {
+            client.disconnect();
+        }
+        true
+    }
+
+    /// Closes the connection of a client once everything queued for it was written,
+    /// rejecting further broadcasts to it. Returns false if it was already gone.
+    pub fn finish_client(&mut self, id: ClientId) -> bool {
+        let streams = self.streams.lock().unwrap_or_else(|poisoned| {
+            error!("streams mutex poisoned");
+            poisoned.into_inner()
+        });
+        let Some(client) = streams.iter().find(|client| client.id == id) else {
+            return false;
+        };
+        client.queue.finish();
+        true
+    }
+
+    /// Moves a client to `group`, discarding its backlog and queueing `message` (if not
+    /// empty) ahead of the broadcasts of its new group. Returns false if it is gone.
+    pub fn move_client(&mut self, id: ClientId, group: ClientGroup, message: &[u8]) -> bool {
+        let mut streams = self.streams.lock().unwrap_or_else(|poisoned| {
+            error!("streams mutex poisoned");
+            poisoned.into_inner()
+        });
+        let Some(client) = streams.iter_mut().find(|client| client.id == id) else {
+            return false;
+        };
+        client.group = group;
+        client.queue.clear();
+        if !message.is_empty() {
+            // The queue was just emptied, so the push can't block nor drop anything
+            let pushed =
+                client
+                    .queue
+                    .push(Arc::from(message), self.queue_capacity, self.queue_policy);
+            return pushed != Pushed::Rejected;
+        }
+        true
+    }
+
+    /// Waits up to `timeout` for a frame sent by any client.
+    pub fn receive(&mut self, timeout: Duration) -> Option<(ClientId, Vec<u8>)> {
+        self.incoming.recv_timeout(timeout).ok()
+    }
+
     pub fn broadcast(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_to_clients(data, |_| true)
+    }
+
+    /// Sends `data` to the clients accepted in `group` only.
+    pub fn broadcast_to_group(&mut self, group: ClientGroup, data: &[u8]) -> io::Result<()> {
+        self.send_to_clients(data, |client| client.group == group)
+    }
+
+    fn send_to_clients(&mut self, data: &[u8], filter: impl Fn(&Client) -> bool) -> io::Result<()> {
         if data.len() > u32::MAX as usize {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Data length exceeds u32 maximum",
             ));
         }
-        let mut streams = self.streams.lock().unwrap_or_else(|poisoned| {
+        let streams = self.streams.lock().unwrap_or_else(|poisoned| {
             error!("streams mutex poisoned");
             poisoned.into_inner()
         });
-        let clients: Vec<TcpStream> = streams.drain(..).collect();
+        // Queues are filled without holding the lock, a blocking push must not stall
+        // accepting or forgetting clients
+        let recipients: Vec<(ClientId, Arc<ClientQueue>)> = streams
+            .iter()
+            .filter(|client| filter(client))
+            .map(|client| (client.id, Arc::clone(&client.queue)))
+            .collect();
         drop(streams);
-        let mut surviving_streams = Vec::with_capacity(clients.len());
-        for mut stream in clients {
-            if !Self::send_frame(&mut stream, data, "broadcasting data") {
-                continue;
+        let frame: Arc<[u8]> = Arc::from(data);
+        let mut rejected = Vec::new();
+        for (id, queue) in &recipients {
+            match queue.push(Arc::clone(&frame), self.queue_capacity, self.queue_policy) {
+                Pushed::Queued => {}
+                Pushed::DroppedOldest(count) => {
+                    debug!("Client {} queue full, dropped {} frames", id, count)
+                }
+                Pushed::Rejected => rejected.push(*id),
             }
-            surviving_streams.push(stream);
         }
-        let mut streams = self.streams.lock().unwrap_or_else(|poisoned| {
-            error!("streams mutex poisoned");
-            poisoned.into_inner()
-        });
-        for stream in surviving_streams {
-            streams.push_back(stream);
+        if !rejected.is_empty() {
+            let mut streams = self.streams.lock().unwrap_or_else(|poisoned| {
+                error!("streams mutex poisoned");
+                poisoned.into_inner()
+            });
+            streams.retain(|client| {
+                if !rejected.contains(&client.id) {
+                    return true;
+                }
+                warn!("Dropping client {}, its queue is full or closed", client.id);
+                client.disconnect();
+                false
+            });
         }
         debug!(
-            "Broadcasted {} bytes to {} clients",
+            "Queued {} bytes for {} clients",
             data.len(),
-            streams.len()
+            recipients.len()
         );
         Ok(())
     }
@@ -167,73 +709,88 @@ impl TcpServer {
 impl Drop for TcpServer {
     fn drop(&mut self) {
         self.shutdown.store(true, Ordering::Relaxed);
+        let streams = self.streams.lock().unwrap_or_else(|poisoned| {
+            error!("streams mutex poisoned");
+            poisoned.into_inner()
+        });
+        for client in streams.iter() {
+            client.disconnect();
+        }
+        drop(streams);
+        if let Err(e) = self.poller.notify() {
+            error!("Could not wake up the TCP server thread: {}", e);
+        }
         if let Some(handle) = self.handle.take()
             && let Err(e) = handle.join()
         {
             error!("TCP listener thread panicked: {:?}", e);
         }
+        for handle in self.listener_threads.drain(..) {
+            if let Err(e) = handle.join() {
+                error!("Listener thread panicked: {:?}", e);
+            }
+        }
     }
 }
 
+/// Former name of `TransportError`, kept for the code using it. Its `ServerDisconnected`
+/// variant is `TransportError::Disconnected` now.
+pub type TcpClientError = TransportError;
+
 pub struct TcpClient {
     stream: TcpStream,
 }
 
-#[derive(Debug)]
-pub enum TcpClientError {
-    ServerDisconnected(io::Error),
-    FrameTooLarge { length: usize, max: usize },
-    Io(io::Error),
-}
-
 impl TcpClient {
     pub fn connect(address: &str) -> io::Result<Self> {
         let stream = TcpStream::connect(address)?;
         Ok(TcpClient { stream })
     }
-    fn map_read_error(e: io::Error, context: &'static str) -> TcpClientError {
-        if matches!(
-            e.kind(),
-            io::ErrorKind::UnexpectedEof
-                | io::ErrorKind::ConnectionReset
-                | io::ErrorKind::ConnectionAborted
-                | io::ErrorKind::BrokenPipe
-        ) {
-            debug!("Server closed connection while {}: {}", context, e);
-            TcpClientError::ServerDisconnected(e)
-        } else {
-            error!("I/O error while {}: {}", context, e);
-            TcpClientError::Io(e)
-        }
+
+    /// Another handle to the same connection, e.g. to send and receive from different
+    /// threads.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(TcpClient {
+            stream: self.stream.try_clone()?,
+        })
     }
 
-    const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024; // 16 MB
-    pub fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TcpClientError> {
-        let mut length_bytes = [0u8; 4];
-        self.stream
-            .read_exact(&mut length_bytes)
-            .map_err(|e| Self::map_read_error(e, "reading frame length"))?;
-        debug!("Length bytes: {:02X?}", length_bytes);
-        let length = u32::from_le_bytes(length_bytes) as usize;
-        if length > Self::MAX_FRAME_SIZE {
-            return Err(TcpClientError::FrameTooLarge {
-                length,
-                max: Self::MAX_FRAME_SIZE,
-            });
-        }
-        buf.resize(length, 0);
-        self.stream
-            .read_exact(buf)
-            .map_err(|e| Self::map_read_error(e, "reading frame body"))?;
+    pub fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        let length = self.stream.receive(buf)?;
         debug!("Received {} bytes from server", length);
         Ok(length)
     }
+    pub fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        FrameSender::send(&mut self.stream, data)?;
+        debug!("Sent {} bytes to server", data.len());
+        Ok(())
+    }
+}
+
+impl FrameSender for TcpClient {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        TcpClient::send(self, data)
+    }
+    fn close(&mut self) {
+        self.stream.close();
+    }
+}
+
+impl FrameReceiver for TcpClient {
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        TcpClient::receive(self, buf)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{Handshake, TransportError};
+    use crate::network::QueuePolicy;
+    use crate::network::{FrameReceiver, FrameSender, memory};
+    use std::os::unix::net::UnixStream;
     use std::thread::sleep;
     use std::time::Duration;
+    use std::time::Instant;
 
     #[test]
     fn broadcast_test() {
@@ -271,4 +828,380 @@ mod tests {
         // Verify that the server has one connected client after acknowledging the new client message
         assert_eq!(server.get_client_count(), 1);
     }
+    #[test]
+    fn handshake_test() {
+        let address = "localhost:50106";
+        let mut server = super::TcpServer::bind(address).expect("Failed to start TCP server");
+        server.set_new_client_message(&[7, 7, 7]);
+        server.set_handshake_handler(|request| {
+            if request == [1] {
+                Handshake::Accept(vec![2])
+            } else {
+                Handshake::Reject(vec![0])
+            }
+        });
+
+        let mut accepted =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        accepted.send(&[1]).expect("Failed to send handshake");
+        let mut buffer: Vec<u8> = Vec::new();
+        accepted
+            .receive(&mut buffer)
+            .expect("Failed to receive reply");
+        assert_eq!(buffer, vec![2]);
+        accepted
+            .receive(&mut buffer)
+            .expect("Failed to receive new client message");
+        assert_eq!(buffer, vec![7, 7, 7]);
+
+        let mut rejected =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        rejected.send(&[9]).expect("Failed to send handshake");
+        rejected
+            .receive(&mut buffer)
+            .expect("Failed to receive reply");
+        assert_eq!(buffer, vec![0]);
+        assert!(matches!(
+            rejected.receive(&mut buffer),
+            Err(TransportError::Disconnected(_))
+        ));
+        assert_eq!(server.get_client_count(), 1);
+    }
+    #[test]
+    fn group_broadcast_test() {
+        let address = "localhost:50108";
+        let mut server = super::TcpServer::bind(address).expect("Failed to start TCP server");
+        server.set_handshake_handler(|request| Handshake::AcceptInGroup {
+            reply: vec![],
+            group: request[0],
+        });
+        server.set_new_client_message(&[0]);
+        server.set_group_message(2, &[2]);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut clients = Vec::new();
+        for group in [1, 2] {
+            let mut client =
+                super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+            client.send(&[group]).expect("Failed to send handshake");
+            client
+                .receive(&mut buffer)
+                .expect("Failed to receive reply");
+            client
+                .receive(&mut buffer)
+                .expect("Failed to receive new client message");
+            assert_eq!(buffer, vec![if group == 2 { 2 } else { 0 }]);
+            clients.push(client);
+        }
+        sleep(Duration::from_millis(100)); // Wait for the server to register both clients
+        assert_eq!(server.client_groups(), vec![1, 2]);
+
+        server
+            .broadcast_to_group(2, &[2, 2])
+            .expect("Failed to broadcast to group");
+        server.broadcast(&[3]).expect("Failed to broadcast data");
+        clients[0]
+            .receive(&mut buffer)
+            .expect("Failed to receive data");
+        assert_eq!(buffer, vec![3]);
+        clients[1]
+            .receive(&mut buffer)
+            .expect("Failed to receive data");
+        assert_eq!(buffer, vec![2, 2]);
+        assert_eq!(server.get_client_count(), 2);
+    }
+    #[test]
+    fn resume_test() {
+        let address = "localhost:50116";
+        let mut server = super::TcpServer::bind(address).expect("Failed to start TCP server");
+        server.set_handshake_handler(|request| Handshake::Resume {
+            reply: vec![],
+            group: 1,
+            position: request[0] as u64,
+        });
+        server.set_group_message(1, &[0]);
+        server.set_replay_handler(|group, position| {
+            assert_eq!(group, 1);
+            move || (position as u8..3).map(|frame| vec![frame]).collect()
+        });
+
+        let mut client =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        client.send(&[1]).expect("Failed to send handshake");
+        let mut buffer: Vec<u8> = Vec::new();
+        client
+            .receive(&mut buffer)
+            .expect("Failed to receive reply");
+        sleep(Duration::from_millis(100)); // Wait for the server to register the client
+        server.broadcast(&[3]).expect("Failed to broadcast data");
+        // The group message, the replayed frames, then the broadcast
+        for expected in 0..4 {
+            client.receive(&mut buffer).expect("Failed to receive data");
+            assert_eq!(buffer, vec![expected]);
+        }
+    }
+    #[test]
+    fn bind_with_test() {
+        let address = "localhost:50123";
+        let mut server = super::TcpServer::bind_with(
+            address,
+            |_| Handshake::Resume {
+                reply: vec![9],
+                group: 1,
+                position: 0,
+            },
+            |_, _| || vec![vec![0]],
+        )
+        .expect("Failed to start TCP server");
+
+        // Connecting right away, the client is still greeted and caught up
+        let mut client =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        client.send(&[0]).expect("Failed to send handshake");
+        let mut buffer: Vec<u8> = Vec::new();
+        client
+            .receive(&mut buffer)
+            .expect("Failed to receive reply");
+        assert_eq!(buffer, vec![9]);
+        sleep(Duration::from_millis(100)); // Wait for the server to register the client
+        server.broadcast(&[1]).expect("Failed to broadcast data");
+        for expected in 0..2 {
+            client.receive(&mut buffer).expect("Failed to receive data");
+            assert_eq!(buffer, vec![expected]);
+        }
+    }
+    #[test]
+    fn replay_lag_test() {
+        let address = "localhost:50122";
+        let mut server = super::TcpServer::bind(address).expect("Failed to start TCP server");
+        server.set_handshake_handler(|_| Handshake::Resume {
+            reply: vec![],
+            group: 1,
+            position: 0,
+        });
+        let frame = vec![0u8; 1024 * 1024];
+        let replayed = frame.clone();
+        server.set_replay_handler(move |_, _| {
+            let replayed = replayed.clone();
+            move || vec![replayed; 16]
+        });
+
+        let mut client =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        client.send(&[0]).expect("Failed to send handshake");
+        let mut buffer: Vec<u8> = Vec::new();
+        client
+            .receive(&mut buffer)
+            .expect("Failed to receive reply");
+        sleep(Duration::from_millis(100)); // Wait for the server to register the client
+        // Far more than the socket buffers hold is replayed, yet only the broadcast,
+        // which waits behind it, counts as lag
+        server.broadcast(&[1]).expect("Failed to broadcast data");
+        let lags = server.client_lags();
+        assert_eq!(lags.len(), 1);
+        assert_eq!((lags[0].queued_frames, lags[0].queued_bytes), (1, 1));
+        for _ in 0..16 {
+            client.receive(&mut buffer).expect("Failed to receive data");
+            assert_eq!(buffer, frame);
+        }
+        client.receive(&mut buffer).expect("Failed to receive data");
+        assert_eq!(buffer, vec![1]);
+    }
+    #[test]
+    fn stalled_client_test() {
+        let address = "localhost:50109";
+        let mut server = super::TcpServer::bind(address).expect("Failed to start TCP server");
+        server.set_queue_capacity(16);
+        server.set_queue_policy(QueuePolicy::DropClient);
+        let _stalled =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        let mut reader =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        sleep(Duration::from_millis(100)); // Wait for the server to accept both connections
+        assert_eq!(server.get_client_count(), 2);
+
+        let frames = 64;
+        let reading = std::thread::spawn(move || {
+            let mut buffer: Vec<u8> = Vec::new();
+            for _ in 0..frames {
+                reader.receive(&mut buffer).expect("Failed to receive data");
+            }
+            (reader, buffer.len())
+        });
+        // The stalled client never reads, so its socket buffers then its queue fill up,
+        // without holding back the broadcasts
+        let data = vec![0u8; 1024 * 1024];
+        for _ in 0..frames {
+            let start = Instant::now();
+            server.broadcast(&data).expect("Failed to broadcast data");
+            assert!(start.elapsed() < Duration::from_millis(100));
+            sleep(Duration::from_millis(10)); // Pace like a stream, the reader keeps up
+        }
+        let (_reader, received) = reading.join().expect("Reader panicked");
+        assert_eq!(received, data.len());
+        assert_eq!(server.get_client_count(), 1);
+    }
+    #[test]
+    fn lagging_client_test() {
+        let address = "localhost:50110";
+        let mut server = super::TcpServer::bind(address).expect("Failed to start TCP server");
+        let mut client =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        sleep(Duration::from_millis(100)); // Wait for the server to accept the connection
+
+        // Far more than the socket buffers hold, as the client doesn't read yet
+        let data = vec![0u8; 1024 * 1024];
+        for _ in 0..32 {
+            server.broadcast(&data).expect("Failed to broadcast data");
+        }
+        sleep(Duration::from_millis(100)); // Let the writer fill the socket buffers
+        let lags = server.client_lags();
+        assert_eq!(lags.len(), 1);
+        assert!(lags[0].queued_frames > 0);
+        assert_eq!(lags[0].queued_bytes, lags[0].queued_frames * data.len());
+
+        let id = lags[0].id;
+        assert!(server.move_client(id, 3, &[3]));
+        assert_eq!(server.client_groups(), vec![3]);
+        assert_eq!(server.client_lags()[0].queued_frames, 1);
+        let mut buffer: Vec<u8> = Vec::new();
+        while buffer != [3] {
+            client.receive(&mut buffer).expect("Failed to receive data");
+        }
+
+        assert!(server.disconnect_client(id));
+        assert!(!server.disconnect_client(id));
+        assert!(matches!(
+            client.receive(&mut buffer),
+            Err(TransportError::Disconnected(_))
+        ));
+        assert_eq!(server.get_client_count(), 0);
+    }
+    #[test]
+    fn client_frames_test() {
+        let address = "localhost:50107";
+        let mut server = super::TcpServer::bind(address).expect("Failed to start TCP server");
+        let mut client =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        sleep(Duration::from_millis(100)); // Wait for the server to accept the connection
+        let ids = server.client_ids();
+        assert_eq!(ids.len(), 1);
+
+        client.send(&[4, 2]).expect("Failed to send data");
+        let (id, data) = server
+            .receive(Duration::from_secs(1))
+            .expect("Failed to receive client frame");
+        assert_eq!(id, ids[0]);
+        assert_eq!(data, vec![4, 2]);
+
+        drop(client);
+        sleep(Duration::from_millis(100)); // Wait for the server to notice the disconnection
+        assert_eq!(server.get_client_count(), 0);
+    }
+    #[test]
+    fn silent_client_does_not_block_accept_test() {
+        let address = "localhost:50111";
+        let mut server = super::TcpServer::bind(address).expect("Failed to start TCP server");
+        server.set_handshake_handler(|_| Handshake::Accept(vec![2]));
+
+        // Connects but never sends its handshake
+        let _silent =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        let start = Instant::now();
+        let mut client =
+            super::TcpClient::connect(address).expect("Failed to connect TCP client to server");
+        client.send(&[1]).expect("Failed to send handshake");
+        let mut buffer: Vec<u8> = Vec::new();
+        client
+            .receive(&mut buffer)
+            .expect("Failed to receive reply");
+        assert_eq!(buffer, vec![2]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        client.send(&[3, 4]).expect("Failed to send frame");
+        let (_, frame) = server
+            .receive(Duration::from_secs(1))
+            .expect("Server did not receive the frame");
+        assert_eq!(frame, vec![3, 4]);
+    }
+    #[test]
+    fn memory_client_test() {
+        let mut server =
+            super::TcpServer::bind("localhost:50115").expect("Failed to start TCP server");
+        server.set_new_client_message(&[7]);
+        server.set_handshake_handler(|request| {
+            if request == [1] {
+                Handshake::Accept(vec![2])
+            } else {
+                Handshake::Reject(vec![0])
+            }
+        });
+        let (mut to_server, from_client) = memory::channel();
+        let (to_client, mut from_server) = memory::channel();
+        server.add_client(Box::new(to_client), Box::new(from_client));
+
+        to_server.send(&[1]).expect("Failed to send handshake");
+        let mut buffer = Vec::new();
+        from_server
+            .receive(&mut buffer)
+            .expect("Failed to receive reply");
+        assert_eq!(buffer, vec![2]);
+        from_server
+            .receive(&mut buffer)
+            .expect("Failed to receive new client message");
+        assert_eq!(buffer, vec![7]);
+
+        server.broadcast(&[3, 4]).expect("Failed to broadcast data");
+        from_server
+            .receive(&mut buffer)
+            .expect("Failed to receive data");
+        assert_eq!(buffer, vec![3, 4]);
+
+        to_server.send(&[5]).expect("Failed to send frame");
+        let (id, frame) = server
+            .receive(Duration::from_secs(1))
+            .expect("Server did not receive the frame");
+        assert_eq!(frame, vec![5]);
+
+        assert!(server.disconnect_client(id));
+        assert!(matches!(
+            from_server.receive(&mut buffer),
+            Err(TransportError::Disconnected(_))
+        ));
+        assert_eq!(server.get_client_count(), 0);
+    }
+    #[test]
+    fn unix_socket_test() {
+        let path =
+            std::env::temp_dir().join(format!("sonos-challenge-{}.sock", std::process::id()));
+        // A socket file left behind by a previous server doesn't get in the way
+        drop(std::os::unix::net::UnixListener::bind(&path));
+        let mut server = super::TcpServer::bind_unix(&path).expect("Failed to start server");
+        server.set_handshake_handler(|_| Handshake::Accept(vec![2]));
+
+        let mut client = UnixStream::connect(&path).expect("Failed to connect to server");
+        client.send(&[1]).expect("Failed to send handshake");
+        let mut buffer = Vec::new();
+        client
+            .receive(&mut buffer)
+            .expect("Failed to receive reply");
+        assert_eq!(buffer, vec![2]);
+        sleep(Duration::from_millis(100)); // Wait for the server to register the client
+        assert_eq!(server.get_client_count(), 1);
+
+        server.broadcast(&[3, 4]).expect("Failed to broadcast data");
+        client.receive(&mut buffer).expect("Failed to receive data");
+        assert_eq!(buffer, vec![3, 4]);
+        client.send(&[5]).expect("Failed to send frame");
+        let (_, frame) = server
+            .receive(Duration::from_secs(1))
+            .expect("Server did not receive the frame");
+        assert_eq!(frame, vec![5]);
+
+        drop(client);
+        sleep(Duration::from_millis(100)); // Wait for the server to notice the disconnection
+        assert_eq!(server.get_client_count(), 0);
+        drop(server);
+        assert!(!path.exists());
+    }
 }
@@ -0,0 +1,382 @@
+//! The thread of a `TcpServer`: waits for readiness of the listeners and of every
+//! client socket at once, instead of polling them in turn.
+
+use crate::network::queue::ClientQueue;
+use crate::network::tcp::socket::{Listener, Stream};
+use crate::network::tcp::{
+    Client, ClientGroup, ClientId, HANDSHAKE_TIMEOUT, HandshakeHandler, Replay, ReplayHandler,
+    ShutdownHook, TcpServer,
+};
+use crate::network::transport::{FrameReceiver, FrameSender, MAX_FRAME_SIZE};
+use log::{debug, error, info, warn};
+use polling::{Event, Events, PollMode, Poller};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read};
+use std::os::fd::{AsFd, AsRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Clients are registered under their id, listeners under keys counting down from there.
+const FIRST_LISTENER_KEY: usize = usize::MAX - 1;
+/// Most bytes read from a client per readiness event.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// A client that completed its handshake, waiting for the server thread to serve it.
+pub(super) struct Accepted {
+    pub(super) group: ClientGroup,
+    /// Position the client resumes the stream at, see `Handshake::Resume`.
+    pub(super) resume: Option<u64>,
+    pub(super) sender: Box<dyn FrameSender>,
+    pub(super) receiver: Incoming,
+    pub(super) shutdown: Option<ShutdownHook>,
+}
+
+/// How the frames of a client are received.
+pub(super) enum Incoming {
+    /// Read by the event loop when the socket is readable.
+    Polled(Stream),
+    /// Read by a thread of its own, for transports the event loop can't wait on.
+    Threaded(Box<dyn FrameReceiver>),
+}
+
+/// Queues a client for the server thread and wakes it up.
+pub(super) fn hand_over(accepted: &Sender<Accepted>, poller: &Poller, client: Accepted) {
+    if accepted.send(client).is_ok()
+        && let Err(e) = poller.notify()
+    {
+        error!("Could not wake up the TCP server thread: {}", e);
+    }
+}
+
+/// State the event loop shares with its `TcpServer`.
+pub(super) struct Shared {
+    pub(super) streams: Arc<Mutex<VecDeque<Client>>>,
+    pub(super) new_client_message: Arc<Mutex<Vec<u8>>>,
+    pub(super) group_messages: Arc<Mutex<HashMap<ClientGroup, Vec<u8>>>>,
+    pub(super) handshake_handler: Arc<Mutex<Option<HandshakeHandler>>>,
+    pub(super) replay_handler: Arc<Mutex<Option<ReplayHandler>>>,
+    pub(super) incoming: Sender<(ClientId, Vec<u8>)>,
+    pub(super) shutdown: Arc<AtomicBool>,
+    pub(super) poller: Arc<Poller>,
+    /// Clients whose handshake thread accepted them.
+    pub(super) accepted: Sender<Accepted>,
+}
+
+/// Socket of a client the loop reads from, and the bytes of its next frame so far.
+struct Connection {
+    stream: Stream,
+    pending: Vec<u8>,
+}
+
+pub(super) struct EventLoop {
+    shared: Shared,
+    listeners: HashMap<usize, Listener>,
+    /// Listeners added while the server runs.
+    new_listeners: Receiver<Listener>,
+    connections: HashMap<ClientId, Connection>,
+    accepted: Receiver<Accepted>,
+    next_id: ClientId,
+}
+
+impl EventLoop {
+    pub(super) fn new(
+        listeners: Vec<Listener>,
+        shared: Shared,
+        new_listeners: Receiver<Listener>,
+        accepted: Receiver<Accepted>,
+    ) -> io::Result<Self> {
+        let mut event_loop = EventLoop {
+            shared,
+            listeners: HashMap::new(),
+            new_listeners,
+            connections: HashMap::new(),
+            accepted,
+            next_id: 0,
+        };
+        for listener in listeners {
+            event_loop.add_listener(listener)?;
+        }
+        Ok(event_loop)
+    }
+
+    fn add_listener(&mut self, listener: Listener) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        let key = FIRST_LISTENER_KEY - self.listeners.len();
+        // SAFETY: the listener is removed from the poller before the loop drops it.
+        unsafe {
+            self.shared.poller.add_with_mode(
+                listener.as_fd().as_raw_fd(),
+                Event::readable(key),
+                PollMode::Level,
+            )?;
+        }
+        self.listeners.insert(key, listener);
+        Ok(())
+    }
+
+    /// Serves clients until the server shuts down.
+    pub(super) fn run(mut self) {
+        let mut events = Events::new();
+        let mut buf = vec![0u8; READ_CHUNK];
+        loop {
+            events.clear();
+            if let Err(e) = self.shared.poller.wait(&mut events, None)
+                && e.kind() != io::ErrorKind::Interrupted
+            {
+                error!("Could not wait for socket events: {}", e);
+                break;
+            }
+            if self.shared.shutdown.load(Ordering::Relaxed) {
+                info!("Shutting down TCP server listener thread");
+                break;
+            }
+            for event in events.iter() {
+                if let Some(Listener::Http {
+                    listener,
+                    on_client,
+                }) = self.listeners.get(&event.key)
+                {
+                    listener.accept(on_client);
+                } else if let Some(listener) = self.listeners.get(&event.key) {
+                    self.accept(listener);
+                } else {
+                    let id = event.key as ClientId;
+                    if !self.read(id, &mut buf) {
+                        self.forget(id);
+                    }
+                }
+            }
+            while let Ok(listener) = self.new_listeners.try_recv() {
+                if let Err(e) = self.add_listener(listener) {
+                    error!("Could not watch new listener: {}", e);
+                }
+            }
+            self.register_accepted();
+        }
+        let ids: Vec<ClientId> = self.connections.keys().copied().collect();
+        for id in ids {
+            self.forget(id);
+        }
+        for listener in self.listeners.values() {
+            if let Err(e) = self.shared.poller.delete(listener) {
+                warn!("Could not stop watching a listener: {}", e);
+            }
+        }
+    }
+
+    /// Accepts every pending connection, handing each one to a handshake thread so a
+    /// slow client can't hold back the others.
+    fn accept(&self, listener: &Listener) {
+        loop {
+            let mut stream = match listener.accept() {
+                Ok((stream, origin)) => {
+                    info!("Accepted connection from {}", origin);
+                    stream
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Could not accept connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(false) {
+                error!("Could not make client stream blocking: {}", e);
+                continue;
+            }
+            let handler = Arc::clone(&self.shared.handshake_handler);
+            let accepted = self.shared.accepted.clone();
+            let poller = Arc::clone(&self.shared.poller);
+            thread::spawn(move || {
+                let (mut writer, shutdown_stream) = match (stream.try_clone(), stream.try_clone()) {
+                    (Ok(writer), Ok(shutdown_stream)) => (writer, shutdown_stream),
+                    (Err(e), _) | (_, Err(e)) => {
+                        error!("Could not clone client stream: {}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+                    warn!("Could not set handshake timeout: {}", e);
+                    return;
+                }
+                let Some((group, resume)) =
+                    TcpServer::handshake(&mut stream, &mut writer, &handler)
+                else {
+                    return;
+                };
+                if let Err(e) = stream.set_read_timeout(None) {
+                    warn!("Could not clear handshake timeout: {}", e);
+                    return;
+                }
+                let client = Accepted {
+                    group,
+                    resume,
+                    sender: Box::new(writer),
+                    receiver: Incoming::Polled(stream),
+                    shutdown: Some(Box::new(move || shutdown_stream.shutdown())),
+                };
+                hand_over(&accepted, &poller, client);
+            });
+        }
+    }
+
+    /// Starts serving the clients that completed their handshake.
+    fn register_accepted(&mut self) {
+        while let Ok(client) = self.accepted.try_recv() {
+            let id = self.next_id;
+            self.next_id += 1;
+            let queue = Arc::new(ClientQueue::new());
+            // Sent by the writer thread ahead of any broadcast
+            let message = self.new_client_message(client.group);
+            if !message.is_empty() {
+                debug!("Sending {} bytes to new client", message.len());
+            }
+            let reader = match client.receiver {
+                Incoming::Polled(stream) => {
+                    // SAFETY: the stream is removed from the poller in `forget` before it
+                    // drops.
+                    let added = unsafe {
+                        self.shared.poller.add_with_mode(
+                            stream.as_fd().as_raw_fd(),
+                            Event::readable(id as usize),
+                            PollMode::Level,
+                        )
+                    };
+                    if let Err(e) = added {
+                        error!("Could not watch client stream: {}", e);
+                        continue;
+                    }
+                    self.connections.insert(
+                        id,
+                        Connection {
+                            stream,
+                            pending: Vec::new(),
+                        },
+                    );
+                    None
+                }
+                Incoming::Threaded(receiver) => Some(receiver),
+            };
+            let mut streams = self.shared.streams.lock().unwrap_or_else(|poisoned| {
+                error!("streams mutex poisoned");
+                poisoned.into_inner()
+            });
+            // Taken while broadcasts wait for the lock, so none slips in between
+            let replay = client.resume.and_then(|position| {
+                debug!("Client {} resumes the stream at {}", id, position);
+                self.replay(client.group, position)
+            });
+            streams.push_front(Client {
+                id,
+                group: client.group,
+                queue: Arc::clone(&queue),
+                shutdown: client.shutdown,
+            });
+            drop(streams);
+            if let Some(receiver) = reader {
+                let streams = Arc::clone(&self.shared.streams);
+                let incoming = self.shared.incoming.clone();
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || {
+                    TcpServer::read_client_frames(id, receiver, queue, &streams, incoming)
+                });
+            }
+            let sender = client.sender;
+            thread::spawn(move || {
+                TcpServer::write_client_frames(id, sender, message, replay, queue)
+            });
+        }
+    }
+
+    /// What the replay handler gives for a client of `group` resuming at `position`.
+    fn replay(&self, group: ClientGroup, position: u64) -> Option<Replay> {
+        let handler = self
+            .shared
+            .replay_handler
+            .lock()
+            .unwrap_or_else(|poisoned| {
+                error!("replay_handler mutex poisoned");
+                poisoned.into_inner()
+            });
+        handler.as_ref().map(|handler| handler(group, position))
+    }
+
+    fn new_client_message(&self, group: ClientGroup) -> Vec<u8> {
+        let group_message = self
+            .shared
+            .group_messages
+            .lock()
+            .unwrap_or_else(|poisoned| {
+                error!("group_messages mutex poisoned");
+                poisoned.into_inner()
+            })
+            .get(&group)
+            .cloned();
+        group_message.unwrap_or_else(|| {
+            self.shared
+                .new_client_message
+                .lock()
+                .unwrap_or_else(|poisoned| {
+                    error!("new_client_message mutex poisoned");
+                    poisoned.into_inner()
+                })
+                .clone()
+        })
+    }
+
+    /// Reads what a readable client sent and forwards its complete frames to the
+    /// server. Returns false once the client must be forgotten.
+    fn read(&mut self, id: ClientId, buf: &mut [u8]) -> bool {
+        let Some(connection) = self.connections.get_mut(&id) else {
+            return true;
+        };
+        // The socket is readable, so a single read doesn't block. Reading only once
+        // keeps a chatty client from starving the others; what's left is reported
+        // again by the next wait.
+        let read = match connection.stream.read(buf) {
+            Ok(0) => {
+                info!("Client {} disconnected", id);
+                return false;
+            }
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return true,
+            Err(e) => {
+                warn!("Error reading from client {}: {}", id, e);
+                return false;
+            }
+        };
+        connection.pending.extend_from_slice(&buf[..read]);
+        while connection.pending.len() >= 4 {
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&connection.pending[..4]);
+            let length = u32::from_le_bytes(length_bytes) as usize;
+            if length > MAX_FRAME_SIZE {
+                warn!(
+                    "Client {} sent a frame of {} bytes, more than the {} allowed",
+                    id, length, MAX_FRAME_SIZE
+                );
+                return false;
+            }
+            if connection.pending.len() < 4 + length {
+                break;
+            }
+            let frame = connection.pending[4..4 + length].to_vec();
+            connection.pending.drain(..4 + length);
+            // The server may not be listening anymore; frames are simply dropped then
+            let _ = self.shared.incoming.send((id, frame));
+        }
+        true
+    }
+
+    /// Stops watching a client and drops it from the server.
+    fn forget(&mut self, id: ClientId) {
+        if let Some(connection) = self.connections.remove(&id) {
+            if let Err(e) = self.shared.poller.delete(&connection.stream) {
+                warn!("Could not stop watching client {}: {}", id, e);
+            }
+            connection.stream.shutdown();
+        }
+        TcpServer::remove_client(&self.shared.streams, id);
+    }
+}
@@ -0,0 +1,164 @@
+//! The sockets a `TcpServer` listens on and serves clients through: TCP, or Unix domain
+//! sockets for consumers on the same host. It also listens for HTTP requests.
+
+use crate::network::http::{ClientHandler, HttpListener};
+use crate::network::transport::{FrameReceiver, FrameSender, TransportError};
+use log::warn;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub(super) enum Listener {
+    Tcp(TcpListener),
+    /// Removes its socket file when dropped.
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+    },
+    /// Answers its connections itself, handing the clients it accepts to `on_client`.
+    Http {
+        listener: HttpListener,
+        on_client: ClientHandler,
+    },
+}
+
+impl Listener {
+    /// Listens on `path`, replacing the socket file a previous server may have left.
+    pub(super) fn bind_unix(path: &Path) -> io::Result<Self> {
+        if let Ok(metadata) = fs::symlink_metadata(path)
+            && metadata.file_type().is_socket()
+        {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        Ok(Listener::Unix {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub(super) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix { listener, .. } => listener.set_nonblocking(nonblocking),
+            Listener::Http { listener, .. } => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Accepts a connection, along with a description of where it comes from.
+    pub(super) fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), addr.to_string()))
+            }
+            Listener::Unix { listener, path } => {
+                let (stream, _) = listener.accept()?;
+                Ok((Stream::Unix(stream), path.display().to_string()))
+            }
+            Listener::Http { .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "HTTP connections are answered by their listener",
+            )),
+        }
+    }
+}
+
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Listener::Tcp(listener) => listener.as_fd(),
+            Listener::Unix { listener, .. } => listener.as_fd(),
+            Listener::Http { listener, .. } => listener.as_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { path, .. } = self
+            && let Err(e) = fs::remove_file(&*path)
+        {
+            warn!("Could not remove socket file {}: {}", path.display(), e);
+        }
+    }
+}
+
+pub(super) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub(super) fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub(super) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub(super) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub(super) fn shutdown(&self) {
+        let _ = match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl AsFd for Stream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Stream::Tcp(stream) => stream.as_fd(),
+            Stream::Unix(stream) => stream.as_fd(),
+        }
+    }
+}
+
+impl FrameSender for Stream {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        match self {
+            Stream::Tcp(stream) => stream.send(data),
+            Stream::Unix(stream) => stream.send(data),
+        }
+    }
+    fn close(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl FrameReceiver for Stream {
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        match self {
+            Stream::Tcp(stream) => stream.receive(buf),
+            Stream::Unix(stream) => stream.receive(buf),
+        }
+    }
+}
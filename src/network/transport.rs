@@ -0,0 +1,132 @@
+//! Framed byte transport. Every transport carries the same frames, so clients and
+//! servers don't care whether they talk over TCP, a Unix socket or an in-memory channel.
+
+use log::{debug, error};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+
+pub(crate) const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024; // 16 MB
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// The other end closed the connection.
+    Disconnected(io::Error),
+    FrameTooLarge {
+        length: usize,
+        max: usize,
+    },
+    Io(io::Error),
+}
+
+/// Sending half of a connection.
+pub trait FrameSender: Send {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError>;
+    /// Closes the connection, the other end sees it as disconnected.
+    fn close(&mut self);
+}
+
+/// Receiving half of a connection.
+pub trait FrameReceiver: Send {
+    /// Replaces the content of `buf` with the next frame, returning its length.
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError>;
+}
+
+impl<T: FrameSender + ?Sized> FrameSender for Box<T> {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        (**self).send(data)
+    }
+    fn close(&mut self) {
+        (**self).close()
+    }
+}
+
+impl<T: FrameReceiver + ?Sized> FrameReceiver for Box<T> {
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        (**self).receive(buf)
+    }
+}
+
+pub(crate) fn map_io_error(e: io::Error, context: &'static str) -> TransportError {
+    if matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    ) {
+        debug!("Connection closed while {}: {}", context, e);
+        TransportError::Disconnected(e)
+    } else {
+        error!("I/O error while {}: {}", context, e);
+        TransportError::Io(e)
+    }
+}
+
+/// Reads a frame prefixed with its length as a little endian u32.
+pub(crate) fn read_frame(
+    stream: &mut impl Read,
+    buf: &mut Vec<u8>,
+) -> Result<usize, TransportError> {
+    let mut length_bytes = [0u8; 4];
+    stream
+        .read_exact(&mut length_bytes)
+        .map_err(|e| map_io_error(e, "reading frame length"))?;
+    debug!("Length bytes: {:02X?}", length_bytes);
+    let length = u32::from_le_bytes(length_bytes) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(TransportError::FrameTooLarge {
+            length,
+            max: MAX_FRAME_SIZE,
+        });
+    }
+    buf.resize(length, 0);
+    stream
+        .read_exact(buf)
+        .map_err(|e| map_io_error(e, "reading frame body"))?;
+    Ok(length)
+}
+
+pub(crate) fn write_frame(stream: &mut impl Write, data: &[u8]) -> Result<(), TransportError> {
+    if data.len() > u32::MAX as usize {
+        return Err(TransportError::FrameTooLarge {
+            length: data.len(),
+            max: u32::MAX as usize,
+        });
+    }
+    let len_bytes = (data.len() as u32).to_le_bytes();
+    stream
+        .write_all(&len_bytes)
+        .and_then(|_| stream.write_all(data))
+        .map_err(|e| map_io_error(e, "sending frame"))
+}
+
+impl FrameSender for TcpStream {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        write_frame(self, data)
+    }
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl FrameReceiver for TcpStream {
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        read_frame(self, buf)
+    }
+}
+
+impl FrameSender for UnixStream {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        write_frame(self, data)
+    }
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl FrameReceiver for UnixStream {
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        read_frame(self, buf)
+    }
+}
@@ -0,0 +1,565 @@
+//! Datagram transport: every frame travels in a datagram of its own, so a lost datagram
+//! never holds back the ones behind it. Nothing is retransmitted, receivers of audio
+//! put the samples frames back in order with an `audio::JitterBuffer`.
+//!
+//! UDP has no connections, so both ends send keep-alives and give up on a peer they
+//! haven't heard from in a while. A `Close` datagram ends the session right away.
+//!
+//! A client opens a session by echoing a token the server challenges its address with,
+//! so a spoofed datagram can't make the server stream to someone who never asked for it.
+//! Until then the server keeps nothing about the address and answers no more than it
+//! was sent.
+
+use crate::network::memory::{self, MemorySender};
+use crate::network::transport::{FrameReceiver, FrameSender, TransportError, map_io_error};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Largest payload of a UDP datagram over IPv4.
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65_507;
+/// Every datagram starts with its kind.
+const HEADER_SIZE: usize = 1;
+/// Largest frame a datagram can carry.
+pub const MAX_UDP_FRAME_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER_SIZE;
+
+pub(crate) const KIND_FRAME: u8 = 0;
+const KIND_KEEP_ALIVE: u8 = 1;
+pub(crate) const KIND_CLOSE: u8 = 2;
+/// Opens a session, carrying the token of the client address (zeros until it got one).
+/// The server acknowledges with an `Open` datagram of its own once the token is right.
+const KIND_OPEN: u8 = 3;
+/// Sent by the server to an address opening a session, with the token to echo.
+const KIND_CHALLENGE: u8 = 4;
+/// Size of the token of a client address.
+const TOKEN_SIZE: usize = 8;
+
+/// How often a client tells the server it is still there.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// Silence after which either end considers the other gone.
+pub(crate) const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest the listener waits for a datagram before checking for expired peers.
+const LISTENER_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long a client opening a session waits for an answer before asking again.
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Drops a fraction of the datagrams sent, to test how clients cope with a lossy network.
+#[derive(Debug, Clone)]
+pub struct LossInjector {
+    rate: f64,
+    state: u64,
+}
+
+impl LossInjector {
+    /// Drops each datagram with probability `rate`. The losses are drawn from a generator
+    /// seeded with `seed`, so a test sees the same losses on every run.
+    pub fn new(rate: f64, seed: u64) -> Self {
+        LossInjector {
+            rate: rate.clamp(0.0, 1.0),
+            // xorshift gets stuck on 0
+            state: seed | 1,
+        }
+    }
+
+    /// Whether the next datagram must be dropped.
+    pub fn drops(&mut self) -> bool {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let draw = (self.state >> 11) as f64 / (1u64 << 53) as f64;
+        draw < self.rate
+    }
+}
+
+pub(crate) fn datagram(kind: u8, data: &[u8], out: &mut Vec<u8>) -> Result<(), TransportError> {
+    if data.len() > MAX_UDP_FRAME_SIZE {
+        return Err(TransportError::FrameTooLarge {
+            length: data.len(),
+            max: MAX_UDP_FRAME_SIZE,
+        });
+    }
+    out.clear();
+    out.push(kind);
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+/// Server end of the session with one UDP client.
+struct UdpPeerSender {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    loss: Option<LossInjector>,
+    closed: bool,
+    datagram: Vec<u8>,
+}
+
+impl FrameSender for UdpPeerSender {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        if self.closed {
+            return Err(TransportError::Disconnected(
+                io::ErrorKind::BrokenPipe.into(),
+            ));
+        }
+        datagram(KIND_FRAME, data, &mut self.datagram)?;
+        if let Some(loss) = &mut self.loss
+            && loss.drops()
+        {
+            debug!("Dropping datagram of {} bytes to {}", data.len(), self.peer);
+            return Ok(());
+        }
+        self.socket
+            .send_to(&self.datagram, self.peer)
+            .map_err(|e| map_io_error(e, "sending datagram"))?;
+        Ok(())
+    }
+    fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            let _ = self.socket.send_to(&[KIND_CLOSE], self.peer);
+        }
+    }
+}
+
+impl Drop for UdpPeerSender {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+struct Peer {
+    frames: MemorySender,
+    last_seen: Instant,
+}
+
+/// Server side UDP socket. Every address opening a session becomes a client, served by
+/// a `TcpServer` through `TcpServer::listen_udp`.
+pub struct UdpListener {
+    socket: Arc<UdpSocket>,
+    loss: Option<LossInjector>,
+    /// Keys the tokens of client addresses, so they can't be guessed.
+    secret: RandomState,
+}
+
+impl UdpListener {
+    pub fn bind(address: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(LISTENER_POLL_INTERVAL))?;
+        Ok(UdpListener {
+            socket: Arc::new(socket),
+            loss: None,
+            secret: RandomState::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Drops some of the datagrams sent to every client, see `LossInjector`.
+    pub fn set_loss(&mut self, loss: LossInjector) {
+        self.loss = Some(loss);
+    }
+
+    /// Dispatches the datagrams received to their peer until `shutdown` is set, handing
+    /// the connection of every new peer to `on_peer`.
+    pub(crate) fn run(
+        self,
+        shutdown: &AtomicBool,
+        mut on_peer: impl FnMut(Box<dyn FrameSender>, Box<dyn FrameReceiver>),
+    ) {
+        let mut peers: HashMap<SocketAddr, Peer> = HashMap::new();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        while !shutdown.load(Ordering::Relaxed) {
+            match self.socket.recv_from(&mut buf) {
+                Ok((length, address)) => {
+                    self.dispatch(&mut peers, address, &buf[..length], &mut on_peer)
+                }
+                Err(ref e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => warn!("Error receiving datagram: {}", e),
+            }
+            peers.retain(|address, peer| {
+                let alive = peer.last_seen.elapsed() < PEER_TIMEOUT;
+                if !alive {
+                    info!("UDP client {} timed out", address);
+                }
+                alive
+            });
+        }
+    }
+
+    fn dispatch(
+        &self,
+        peers: &mut HashMap<SocketAddr, Peer>,
+        address: SocketAddr,
+        datagram: &[u8],
+        on_peer: &mut impl FnMut(Box<dyn FrameSender>, Box<dyn FrameReceiver>),
+    ) {
+        let Some((&kind, payload)) = datagram.split_first() else {
+            debug!("Ignoring empty datagram from {}", address);
+            return;
+        };
+        if kind == KIND_CLOSE {
+            if peers.remove(&address).is_some() {
+                info!("UDP client {} disconnected", address);
+            }
+            return;
+        }
+        if kind == KIND_OPEN {
+            self.open(peers, address, payload, on_peer);
+            return;
+        }
+        let Some(peer) = peers.get_mut(&address) else {
+            debug!(
+                "Ignoring datagram from {}, which opened no session",
+                address
+            );
+            return;
+        };
+        peer.last_seen = Instant::now();
+        match kind {
+            // Frames of a client the server already dropped are ignored until it goes
+            // silent and expires
+            KIND_FRAME => {
+                let _ = peer.frames.send(payload);
+            }
+            KIND_KEEP_ALIVE => {}
+            _ => debug!(
+                "Ignoring datagram of unknown kind {} from {}",
+                kind, address
+            ),
+        }
+    }
+
+    /// Answers an address opening a session with `token`: with a challenge until the
+    /// token is the one of the address, proving it gets what is sent to it, then with an
+    /// acknowledgement, making it a peer.
+    fn open(
+        &self,
+        peers: &mut HashMap<SocketAddr, Peer>,
+        address: SocketAddr,
+        token: &[u8],
+        on_peer: &mut impl FnMut(Box<dyn FrameSender>, Box<dyn FrameReceiver>),
+    ) {
+        let expected = self.secret.hash_one(address).to_be_bytes();
+        if token != expected {
+            // Never larger than what was received, so spoofed requests aren't amplified
+            if token.len() >= TOKEN_SIZE {
+                let mut challenge = vec![KIND_CHALLENGE];
+                challenge.extend_from_slice(&expected);
+                if let Err(e) = self.socket.send_to(&challenge, address) {
+                    debug!("Couldn't challenge {}: {}", address, e);
+                }
+            }
+            return;
+        }
+        let peer = peers.entry(address).or_insert_with(|| {
+            info!("Accepted UDP client {}", address);
+            let (frames, receiver) = memory::channel();
+            let sender = UdpPeerSender {
+                socket: Arc::clone(&self.socket),
+                peer: address,
+                loss: self.loss.clone(),
+                closed: false,
+                datagram: Vec::new(),
+            };
+            on_peer(Box::new(sender), Box::new(receiver));
+            Peer {
+                frames,
+                last_seen: Instant::now(),
+            }
+        });
+        peer.last_seen = Instant::now();
+        // Sent again for every echo, in case the previous one got lost
+        if let Err(e) = self.socket.send_to(&[KIND_OPEN], address) {
+            debug!("Couldn't acknowledge {}: {}", address, e);
+        }
+    }
+}
+
+/// Client end of a UDP session with a server.
+pub struct UdpClient {
+    socket: UdpSocket,
+    last_sent: Instant,
+    last_heard: Instant,
+    datagram: Vec<u8>,
+}
+
+impl UdpClient {
+    /// Opens a session with the server at `address`, waiting until it accepts it.
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let server = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Address resolved to nothing")
+        })?;
+        let local = if server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+        socket.set_read_timeout(Some(OPEN_RETRY_INTERVAL))?;
+        Self::open(&socket)?;
+        socket.set_read_timeout(Some(KEEP_ALIVE_INTERVAL))?;
+        Ok(UdpClient {
+            socket,
+            last_sent: Instant::now(),
+            last_heard: Instant::now(),
+            datagram: Vec::new(),
+        })
+    }
+
+    /// Asks the server connected to `socket` to open a session until it accepts,
+    /// echoing the token it challenges us with.
+    fn open(socket: &UdpSocket) -> io::Result<()> {
+        // As large as the challenge, which the server never makes larger than the request
+        let mut request = [0u8; HEADER_SIZE + TOKEN_SIZE];
+        request[0] = KIND_OPEN;
+        let mut reply = [0u8; HEADER_SIZE + TOKEN_SIZE];
+        let started = Instant::now();
+        while started.elapsed() < PEER_TIMEOUT {
+            socket.send(&request)?;
+            let length = match socket.recv(&mut reply) {
+                Ok(length) => length,
+                Err(ref e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            match reply[..length].split_first() {
+                Some((&KIND_CHALLENGE, token)) if token.len() == TOKEN_SIZE => {
+                    request[HEADER_SIZE..].copy_from_slice(token);
+                }
+                Some((&KIND_OPEN, _)) => return Ok(()),
+                _ => {}
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Server did not accept the session",
+        ))
+    }
+
+    /// Another handle to the same session, e.g. to send and receive from different
+    /// threads.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(UdpClient {
+            socket: self.socket.try_clone()?,
+            last_sent: self.last_sent,
+            last_heard: self.last_heard,
+            datagram: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn keep_alive(&mut self) {
+        if self.last_sent.elapsed() < KEEP_ALIVE_INTERVAL {
+            return;
+        }
+        if let Err(e) = self.socket.send(&[KIND_KEEP_ALIVE]) {
+            debug!("Couldn't send keep-alive: {}", e);
+        }
+        self.last_sent = Instant::now();
+    }
+}
+
+impl FrameSender for UdpClient {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        datagram(KIND_FRAME, data, &mut self.datagram)?;
+        self.socket
+            .send(&self.datagram)
+            .map_err(|e| map_io_error(e, "sending datagram"))?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+    fn close(&mut self) {
+        let _ = self.socket.send(&[KIND_CLOSE]);
+    }
+}
+
+impl FrameReceiver for UdpClient {
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        self.datagram.resize(MAX_DATAGRAM_SIZE, 0);
+        loop {
+            self.keep_alive();
+            let length = match self.socket.recv(&mut self.datagram) {
+                Ok(length) => length,
+                Err(ref e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self.last_heard.elapsed() >= PEER_TIMEOUT {
+                        return Err(TransportError::Disconnected(io::ErrorKind::TimedOut.into()));
+                    }
+                    continue;
+                }
+                Err(e) => return Err(map_io_error(e, "receiving datagram")),
+            };
+            self.last_heard = Instant::now();
+            match self.datagram[..length].split_first() {
+                Some((&KIND_FRAME, payload)) => {
+                    buf.clear();
+                    buf.extend_from_slice(payload);
+                    return Ok(payload.len());
+                }
+                Some((&KIND_CLOSE, _)) => {
+                    return Err(TransportError::Disconnected(
+                        io::ErrorKind::ConnectionAborted.into(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        KIND_CHALLENGE, KIND_FRAME, KIND_KEEP_ALIVE, KIND_OPEN, LossInjector, UdpClient,
+        UdpListener,
+    };
+    use crate::network::tcp::{Handshake, TcpServer};
+    use crate::network::{FrameReceiver, FrameSender, TransportError};
+    use std::net::UdpSocket;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn loss_injector_drops_about_the_requested_rate() {
+        let mut loss = LossInjector::new(0.25, 42);
+        let dropped = (0..10_000).filter(|_| loss.drops()).count();
+        assert!((2_300..2_700).contains(&dropped), "dropped {}", dropped);
+        let mut none = LossInjector::new(0.0, 42);
+        assert!((0..1_000).all(|_| !none.drops()));
+    }
+
+    #[test]
+    fn udp_session_test() {
+        let listener = UdpListener::bind("127.0.0.1:0").expect("Failed to bind UDP listener");
+        let address = listener.local_addr().expect("No local address").to_string();
+        let mut server = TcpServer::bind("127.0.0.1:0").expect("Failed to start server");
+        server.set_handshake_handler(|_| Handshake::Accept(vec![2]));
+        server.listen_udp(listener);
+
+        let mut client = UdpClient::connect(&address).expect("Failed to open UDP session");
+        client.send(&[1]).expect("Failed to send handshake");
+        let mut buffer = Vec::new();
+        client
+            .receive(&mut buffer)
+            .expect("Failed to receive reply");
+        assert_eq!(buffer, vec![2]);
+        sleep(Duration::from_millis(100)); // Wait for the server to register the client
+        assert_eq!(server.get_client_count(), 1);
+
+        server.broadcast(&[3, 4]).expect("Failed to broadcast data");
+        client.receive(&mut buffer).expect("Failed to receive data");
+        assert_eq!(buffer, vec![3, 4]);
+        client.send(&[5]).expect("Failed to send frame");
+        let (_, frame) = server
+            .receive(Duration::from_secs(1))
+            .expect("Server did not receive the frame");
+        assert_eq!(frame, vec![5]);
+
+        client.close();
+        sleep(Duration::from_millis(100)); // Wait for the server to notice the disconnection
+        assert_eq!(server.get_client_count(), 0);
+        drop(server);
+        assert!(matches!(
+            client.receive(&mut buffer),
+            Err(TransportError::Disconnected(_))
+        ));
+    }
+
+    #[test]
+    fn udp_open_test() {
+        let listener = UdpListener::bind("127.0.0.1:0").expect("Failed to bind UDP listener");
+        let address = listener.local_addr().expect("No local address");
+        let mut server = TcpServer::bind("127.0.0.1:0").expect("Failed to start server");
+        server.set_handshake_handler(|_| Handshake::Accept(vec![2]));
+        server.listen_udp(listener);
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("Failed to bind UDP socket");
+        socket
+            .connect(address)
+            .expect("Failed to connect UDP socket");
+        socket
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .expect("Failed to set timeout");
+        let mut reply = [0u8; 64];
+
+        // Anything but an opening, e.g. from a spoofed address, is ignored
+        for datagram in [&[KIND_KEEP_ALIVE][..], &[KIND_FRAME, 1], &[KIND_OPEN]] {
+            socket.send(datagram).expect("Failed to send datagram");
+        }
+        assert!(socket.recv(&mut reply).is_err());
+        assert_eq!(server.get_client_count(), 0);
+
+        // Openings are challenged with a datagram no larger than theirs
+        let mut request = [KIND_OPEN, 0, 0, 0, 0, 0, 0, 0, 0];
+        socket.send(&request).expect("Failed to send opening");
+        let length = socket.recv(&mut reply).expect("No challenge");
+        assert_eq!((length, reply[0]), (request.len(), KIND_CHALLENGE));
+        sleep(Duration::from_millis(100));
+        assert_eq!(server.get_client_count(), 0);
+
+        // Echoing the token opens the session
+        request[1..].copy_from_slice(&reply[1..length]);
+        socket.send(&request).expect("Failed to send token");
+        let length = socket.recv(&mut reply).expect("No acknowledgement");
+        assert_eq!(&reply[..length], [KIND_OPEN]);
+        socket
+            .send(&[KIND_FRAME, 1])
+            .expect("Failed to send handshake");
+        let length = socket.recv(&mut reply).expect("No handshake reply");
+        assert_eq!(&reply[..length], [KIND_FRAME, 2]);
+        sleep(Duration::from_millis(100)); // Wait for the server to register the client
+        assert_eq!(server.get_client_count(), 1);
+    }
+
+    #[test]
+    fn lossy_udp_session_test() {
+        let mut listener = UdpListener::bind("127.0.0.1:0").expect("Failed to bind UDP listener");
+        listener.set_loss(LossInjector::new(0.2, 7));
+        let address = listener.local_addr().expect("No local address").to_string();
+        let mut server = TcpServer::bind("127.0.0.1:0").expect("Failed to start server");
+        server.listen_udp(listener);
+
+        let mut client = UdpClient::connect(&address).expect("Failed to open UDP session");
+        sleep(Duration::from_millis(100)); // Wait for the server to register the client
+        assert_eq!(server.get_client_count(), 1);
+        let frames = 200u8;
+        for i in 0..frames {
+            server.broadcast(&[i]).expect("Failed to broadcast data");
+        }
+        sleep(Duration::from_millis(200)); // Wait for the writer to send everything queued
+        server.disconnect_client(server.client_ids()[0]);
+
+        let mut buffer = Vec::new();
+        let mut received = Vec::new();
+        while client.receive(&mut buffer).is_ok() {
+            received.push(buffer[0]);
+        }
+        // Whatever got through arrives in order, on loopback only the injector loses some
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+        let lost = frames as usize - received.len();
+        assert!((20..60).contains(&lost), "lost {} frames", lost);
+    }
+}
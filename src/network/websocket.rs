@@ -0,0 +1,356 @@
+//! WebSocket transport (RFC 6455), for browsers: the HTTP listener upgrades requests for
+//! `WEBSOCKET_PATH`, after which every frame travels as one binary WebSocket message.
+//! Clients speak the same protocol as over TCP, starting with the handshake.
+
+use crate::network::http::{Request, respond_empty};
+use crate::network::transport::{
+    FrameReceiver, FrameSender, MAX_FRAME_SIZE, TransportError, map_io_error,
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::debug;
+use sha1::{Digest, Sha1};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Path of the WebSocket endpoint on the HTTP listener.
+pub const WEBSOCKET_PATH: &str = "/ws";
+/// Appended to the key of the client to prove the server understood the upgrade.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+/// Close frame payload: status 1000, normal closure.
+const NORMAL_CLOSURE: [u8; 2] = 1000u16.to_be_bytes();
+
+/// Value of the `Sec-WebSocket-Accept` header answering the `Sec-WebSocket-Key` `key`.
+pub(crate) fn accept_key(key: &str) -> String {
+    let digest = Sha1::new()
+        .chain_update(key)
+        .chain_update(ACCEPT_GUID)
+        .finalize();
+    STANDARD.encode(digest)
+}
+
+/// Answers a request for `WEBSOCKET_PATH`, switching the connection to WebSocket if it
+/// is a valid upgrade request. Returns whether it did.
+pub(crate) fn upgrade(stream: &mut TcpStream, request: &Request) -> io::Result<bool> {
+    let upgrade = request
+        .header("Upgrade")
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let key = request.header("Sec-WebSocket-Key");
+    let (true, Some(key), Some("13")) = (upgrade, key, request.header("Sec-WebSocket-Version"))
+    else {
+        respond_empty(stream, "400 Bad Request", "Sec-WebSocket-Version: 13\r\n")?;
+        return Ok(false);
+    };
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+    Ok(true)
+}
+
+/// Appends a frame, unmasked as servers send them, holding all of `payload` to `out`.
+fn write_frame(opcode: u8, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(FIN | opcode);
+    match payload.len() {
+        length @ 0..126 => out.push(length as u8),
+        length @ 126..=0xFFFF => {
+            out.push(126);
+            out.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            out.push(127);
+            out.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+/// Both halves of a WebSocket connection write to it: the receiver answers pings and
+/// close frames itself.
+type SharedWriter = Arc<Mutex<TcpStream>>;
+
+fn lock(writer: &SharedWriter) -> MutexGuard<'_, TcpStream> {
+    writer
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Splits an upgraded connection into the halves a `TcpServer` serves a client through.
+pub(crate) fn split(stream: TcpStream) -> io::Result<(WebSocketSender, WebSocketReceiver)> {
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let sender = WebSocketSender {
+        writer: Arc::clone(&writer),
+        frame: Vec::new(),
+    };
+    let receiver = WebSocketReceiver {
+        stream,
+        writer,
+        payload: Vec::new(),
+    };
+    Ok((sender, receiver))
+}
+
+/// Sends every frame as a binary message.
+pub(crate) struct WebSocketSender {
+    writer: SharedWriter,
+    frame: Vec<u8>,
+}
+
+impl FrameSender for WebSocketSender {
+    fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        self.frame.clear();
+        write_frame(OPCODE_BINARY, data, &mut self.frame);
+        lock(&self.writer)
+            .write_all(&self.frame)
+            .map_err(|e| map_io_error(e, "sending WebSocket message"))
+    }
+    fn close(&mut self) {
+        let mut frame = Vec::new();
+        write_frame(OPCODE_CLOSE, &NORMAL_CLOSURE, &mut frame);
+        let mut stream = lock(&self.writer);
+        let _ = stream.write_all(&frame);
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Receives the binary messages of a client as frames.
+pub(crate) struct WebSocketReceiver {
+    stream: TcpStream,
+    writer: SharedWriter,
+    /// Payload of the WebSocket frame being read.
+    payload: Vec<u8>,
+}
+
+impl WebSocketReceiver {
+    /// Reads the next WebSocket frame into `payload`, returning whether it ends a
+    /// message and its opcode.
+    fn read_frame(&mut self) -> Result<(bool, u8), TransportError> {
+        let mut header = [0u8; 2];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(|e| map_io_error(e, "reading WebSocket frame header"))?;
+        let fin = header[0] & FIN != 0;
+        let opcode = header[0] & 0x0F;
+        if header[1] & MASKED == 0 {
+            // Clients must mask what they send
+            return Err(TransportError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unmasked WebSocket frame from client",
+            )));
+        }
+        let length = match header[1] & 0x7F {
+            126 => {
+                let mut length = [0u8; 2];
+                self.stream
+                    .read_exact(&mut length)
+                    .map_err(|e| map_io_error(e, "reading WebSocket frame length"))?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0u8; 8];
+                self.stream
+                    .read_exact(&mut length)
+                    .map_err(|e| map_io_error(e, "reading WebSocket frame length"))?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        if length > MAX_FRAME_SIZE as u64 {
+            return Err(TransportError::FrameTooLarge {
+                length: length as usize,
+                max: MAX_FRAME_SIZE,
+            });
+        }
+        let mut mask = [0u8; 4];
+        self.stream
+            .read_exact(&mut mask)
+            .map_err(|e| map_io_error(e, "reading WebSocket frame mask"))?;
+        self.payload.resize(length as usize, 0);
+        self.stream
+            .read_exact(&mut self.payload)
+            .map_err(|e| map_io_error(e, "reading WebSocket frame payload"))?;
+        for (i, byte) in self.payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok((fin, opcode))
+    }
+
+    fn reply(&self, opcode: u8, payload: &[u8]) {
+        let mut frame = Vec::new();
+        write_frame(opcode, payload, &mut frame);
+        if let Err(e) = lock(&self.writer).write_all(&frame) {
+            debug!("Could not reply to WebSocket control frame: {}", e);
+        }
+    }
+}
+
+impl FrameReceiver for WebSocketReceiver {
+    fn receive(&mut self, buf: &mut Vec<u8>) -> Result<usize, TransportError> {
+        buf.clear();
+        loop {
+            let (fin, opcode) = self.read_frame()?;
+            match opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                    if buf.len() + self.payload.len() > MAX_FRAME_SIZE {
+                        return Err(TransportError::FrameTooLarge {
+                            length: buf.len() + self.payload.len(),
+                            max: MAX_FRAME_SIZE,
+                        });
+                    }
+                    buf.extend_from_slice(&self.payload);
+                    if fin {
+                        return Ok(buf.len());
+                    }
+                }
+                OPCODE_CLOSE => {
+                    self.reply(OPCODE_CLOSE, &NORMAL_CLOSURE);
+                    return Err(TransportError::Disconnected(
+                        io::ErrorKind::ConnectionAborted.into(),
+                    ));
+                }
+                OPCODE_PING => self.reply(OPCODE_PONG, &self.payload),
+                // Pongs, and whatever a later revision may add
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FIN, MASKED, OPCODE_BINARY, OPCODE_CLOSE, WEBSOCKET_PATH, accept_key};
+    use crate::network::http::HttpListener;
+    use crate::network::tcp::{Handshake, TcpServer};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn opening_handshake_keys() {
+        // Example of RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    /// Sends `payload` in a masked frame, as browsers do.
+    fn send_masked(stream: &mut TcpStream, opcode: u8, payload: &[u8]) {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![FIN | opcode];
+        if payload.len() < 126 {
+            frame.push(MASKED | payload.len() as u8);
+        } else {
+            frame.push(MASKED | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        stream.write_all(&frame).expect("Failed to send frame");
+    }
+
+    /// Reads an unmasked frame, returning its opcode and payload.
+    fn read_unmasked(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        stream
+            .read_exact(&mut header)
+            .expect("Failed to read frame");
+        assert_eq!(header[1] & MASKED, 0);
+        let length = match header[1] {
+            126 => {
+                let mut length = [0u8; 2];
+                stream
+                    .read_exact(&mut length)
+                    .expect("Failed to read frame");
+                u16::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        stream
+            .read_exact(&mut payload)
+            .expect("Failed to read frame");
+        (header[0] & 0x0F, payload)
+    }
+
+    #[test]
+    fn websocket_stream_test() {
+        let listener = HttpListener::bind("127.0.0.1:0").expect("Failed to bind HTTP listener");
+        let address = listener.local_addr().expect("No local address").to_string();
+        let mut server = TcpServer::bind("127.0.0.1:0").expect("Failed to start server");
+        server.set_handshake_handler(|request| {
+            if request == [1] {
+                Handshake::AcceptInGroup {
+                    reply: vec![2],
+                    group: 3,
+                }
+            } else {
+                Handshake::Reject(vec![0])
+            }
+        });
+        server
+            .listen_http(listener, 7)
+            .expect("Failed to listen for HTTP clients");
+
+        let mut client = TcpStream::connect(&address).expect("Failed to connect");
+        write!(
+            client,
+            "GET {WEBSOCKET_PATH} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .expect("Failed to send request");
+        let mut head = Vec::new();
+        let mut byte = [0u8];
+        while !head.ends_with(b"\r\n\r\n") {
+            client
+                .read_exact(&mut byte)
+                .expect("Failed to read response");
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        assert!(
+            head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+            "{}",
+            head
+        );
+
+        // Then the usual handshake, over WebSocket messages
+        send_masked(&mut client, OPCODE_BINARY, &[1]);
+        assert_eq!(read_unmasked(&mut client), (OPCODE_BINARY, vec![2]));
+        sleep(Duration::from_millis(100)); // Wait for the server to serve the client
+        assert_eq!(server.client_groups(), vec![3]);
+        let large = vec![5; 1000];
+        server
+            .broadcast_to_group(3, &large)
+            .expect("Failed to broadcast");
+        assert_eq!(read_unmasked(&mut client), (OPCODE_BINARY, large));
+
+        send_masked(&mut client, OPCODE_BINARY, &[4, 2]);
+        let (id, frame) = server
+            .receive(Duration::from_secs(1))
+            .expect("No frame from client");
+        assert_eq!(frame, vec![4, 2]);
+
+        assert!(server.finish_client(id));
+        assert_eq!(read_unmasked(&mut client).0, OPCODE_CLOSE);
+        sleep(Duration::from_millis(100)); // Wait for the server to forget the client
+        assert_eq!(server.get_client_count(), 0);
+    }
+}
@@ -1,115 +1,568 @@
 use clap::Parser;
-use log::{LevelFilter, error, info};
-use sonos_challenge::audio::{AudioMessage, Serializable, WavAudioInput};
-use sonos_challenge::cli::ServerCli;
-use sonos_challenge::network::tcp::TcpServer;
+use log::{LevelFilter, error, info, warn};
+use sonos_challenge::audio::{
+    AudioMessage, AudioSource, Codec, DeserializationError, EncodedFrame, Features, PcmFormat,
+    ReplayWindow, SampleBuffer, SamplesFrame, Serializable, Welcome, open_audio_file,
+    streaming_wav_header, write_wav_samples,
+};
+use sonos_challenge::cli::{LagPolicy, ServerCli};
+use sonos_challenge::network::http::HttpListener;
+use sonos_challenge::network::multicast::MulticastSender;
+use sonos_challenge::network::rtp::RtpSender;
+use sonos_challenge::network::tcp::{ClientGroup, ClientId, Handshake, TcpServer};
+use sonos_challenge::network::udp::{LossInjector, UdpListener};
+use sonos_challenge::network::{FrameSender, StreamTitle};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Optional protocol features this server implements.
+const SERVER_FEATURES: Features = Features::END_OF_STREAM.union(Features::RESUME);
+/// Codec lagging clients are switched to by the downgrade lag policy.
+const DOWNGRADE_CODEC: Codec = Codec::ImaAdpcm;
+/// Flag set in the group of clients able to decode `DOWNGRADE_CODEC` into samples of the
+/// format they already get. The rest of the group is the codec tag of the client.
+const DOWNGRADABLE: ClientGroup = 0x80;
+/// Group of the HTTP clients, which get the stream as a WAV file rather than messages.
+const HTTP_GROUP: ClientGroup = 0x40;
+/// Flag set in the group of clients that negotiated `Features::END_OF_STREAM`, the only
+/// ones sent an `EndOfStream` and waited for to acknowledge it.
+const ACKNOWLEDGES_END: ClientGroup = 0x20;
+/// Amount of audio (in seconds) to preload before pacing
+/// to build up a latency buffer on the client. Clients joining later get as much of
+/// the latest audio right away.
+const INITIAL_BUFFER_SECONDS: usize = 3;
+
+fn group_codec(group: ClientGroup) -> Option<Codec> {
+    Codec::try_from(group & !(DOWNGRADABLE | ACKNOWLEDGES_END)).ok()
+}
+
+/// The message carrying `frame` encoded with `codec`.
+fn samples_message(frame: &SamplesFrame, codec: Codec, channels: u16) -> AudioMessage {
+    match codec {
+        Codec::Pcm => AudioMessage::Samples(frame.clone()),
+        codec => AudioMessage::EncodedSamples(EncodedFrame::encode(frame, codec, channels)),
+    }
+}
+
+fn lock_window(window: &Mutex<ReplayWindow>) -> MutexGuard<'_, ReplayWindow> {
+    window
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Messages catching up a client of `group` with `frames`, then the end of the stream
+/// if it is over, lasting `total_samples`.
+fn replay_messages(
+    frames: &[SamplesFrame],
+    total_samples: Option<u64>,
+    group: ClientGroup,
+    channels: u16,
+) -> Vec<Vec<u8>> {
+    let Some(codec) = group_codec(group) else {
+        return Vec::new();
+    };
+    let end = total_samples
+        .filter(|_| group & ACKNOWLEDGES_END != 0)
+        .map(|total_samples| AudioMessage::EndOfStream { total_samples });
+    let mut messages = Vec::new();
+    for message in frames
+        .iter()
+        .map(|frame| samples_message(frame, codec, channels))
+        .chain(end)
+    {
+        let mut buffer = Vec::new();
+        if message.serialize(&mut buffer).is_err() {
+            error!("Couldn't serialize replayed message");
+            return Vec::new();
+        }
+        messages.push(buffer);
+    }
+    messages
+}
 
 struct Application {
     tcp: TcpServer,
+    /// Also streams to standard RTP players when set.
+    rtp: Option<RtpSender>,
+    /// Also sends every frame once to a multicast group when set, encoded with the
+    /// preferred codec.
+    multicast: Option<MulticastSender>,
+    /// Title announced to the players of the Icecast mount point, when there is one.
+    title: Option<StreamTitle>,
+    /// Codecs offered to clients, the preferred one first.
+    codecs: Vec<Codec>,
+    max_lag: Duration,
+    lag_policy: LagPolicy,
+    /// How long clients get to acknowledge the end of the stream before being dropped.
+    end_of_stream_timeout: Duration,
+    /// Latest audio kept for the clients resuming the stream after a disconnection, or
+    /// joining it late.
+    replay: Arc<Mutex<ReplayWindow>>,
+    /// Serialized `Spec` for each client group.
+    spec_messages: HashMap<ClientGroup, Vec<u8>>,
+    channels: u16,
+    next_sequence: u64,
+    next_position: u64,
 }
 #[derive(Debug)]
 enum AppError {
-    WavFileRead,
+    SourceRead,
     Serialization,
     Broadcast,
+    UnsupportedFormat,
+    Bind,
 }
 impl Application {
-    pub fn play_wav_file(&mut self, filepath: &str) -> Result<(), AppError> {
-        const SAMPLES_PER_GROUP: usize = 1_000;
-        let mut input = match WavAudioInput::init(filepath) {
-            Ok(s) => s,
+    /// Answers a client `Hello`, accepting it only if it can decode the stream format
+    /// with one of `codecs`. New clients start `backlog_samples` before the newest frame
+    /// of `replay`, resuming ones where they asked.
+    fn handshake(
+        request: &[u8],
+        stream_format: PcmFormat,
+        codecs: &[Codec],
+        replay: &Mutex<ReplayWindow>,
+        backlog_samples: u64,
+    ) -> Handshake {
+        let mut reply = Vec::new();
+        let hello = match AudioMessage::deserialize(request) {
+            Ok(AudioMessage::Hello(hello)) => hello,
+            Ok(other) => {
+                warn!("Expected a Hello from new client, got {:?}", other);
+                return Handshake::Reject(reply);
+            }
+            Err(DeserializationError::UnsupportedProtocolVersion {
+                supported,
+                received,
+            }) => {
+                warn!(
+                    "Rejecting client speaking protocol version {}, server supports {}",
+                    received, supported
+                );
+                // Answer anyway so the client can report the mismatch on its side.
+                if AudioMessage::Welcome(Welcome::new(Codec::Pcm, SERVER_FEATURES))
+                    .serialize(&mut reply)
+                    .is_err()
+                {
+                    reply.clear();
+                }
+                return Handshake::Reject(reply);
+            }
+            Err(e) => {
+                warn!("Couldn't deserialize client Hello: {:?}", e);
+                return Handshake::Reject(reply);
+            }
+        };
+        let Some(codec) = codecs.iter().copied().find(|codec| {
+            hello.codecs.contains(codec)
+                && hello
+                    .sample_formats
+                    .contains(&codec.decoded_format(stream_format))
+        }) else {
+            warn!(
+                "Rejecting client that can't decode {:?} samples with any of {:?}",
+                stream_format, codecs
+            );
+            return Handshake::Reject(reply);
+        };
+        match hello.resume_position {
+            Some(position) => info!(
+                "Resuming the stream at sample {} for client with {:?} codec",
+                position, codec
+            ),
+            None => info!("Streaming to new client with {:?} codec", codec),
+        }
+        // Clients are grouped by codec so every frame is encoded once per codec
+        let mut group = ClientGroup::from(codec);
+        // Only when the samples decode alike, the output of the client was opened for
+        // them and can't change format mid-stream
+        if hello.codecs.contains(&DOWNGRADE_CODEC)
+            && DOWNGRADE_CODEC.decoded_format(stream_format) == codec.decoded_format(stream_format)
+        {
+            group |= DOWNGRADABLE;
+        }
+        let features = hello.features.intersection(SERVER_FEATURES);
+        if features.contains(Features::END_OF_STREAM) {
+            group |= ACKNOWLEDGES_END;
+        }
+        let welcome = Welcome::new(codec, features);
+        match AudioMessage::Welcome(welcome).serialize(&mut reply) {
+            Ok(_) => {
+                let position = match hello.resume_position {
+                    Some(position) if hello.features.contains(Features::RESUME) => position,
+                    // Filling its buffer right away, like the initial burst does for the
+                    // first clients, rather than underrunning until the next broadcasts
+                    _ => lock_window(replay)
+                        .end_position()
+                        .saturating_sub(backlog_samples),
+                };
+                Handshake::Resume {
+                    reply,
+                    group,
+                    position,
+                }
+            }
+            Err(_) => {
+                error!("Couldn't serialize Welcome message");
+                Handshake::Reject(Vec::new())
+            }
+        }
+    }
+
+    /// Listens at `address` with the handlers greeting clients and catching up those
+    /// joining the stream of `source` late, returning the window they are caught up from.
+    /// The handlers are in place before the first client is accepted, so none skips them.
+    fn bind(
+        address: &str,
+        source: &dyn AudioSource,
+        codecs: &[Codec],
+        replay_window: Duration,
+    ) -> Result<(TcpServer, Arc<Mutex<ReplayWindow>>), AppError> {
+        let spec = source.spec();
+        let stream_format = match source.format() {
+            Ok(format) => format,
             Err(_) => {
-                error!("Couldn't read wav file: {}", filepath);
-                return Err(AppError::WavFileRead);
+                error!("Unsupported sample format in audio source: {:?}", spec);
+                return Err(AppError::UnsupportedFormat);
             }
         };
-        let mut serialization_buffer = Vec::new();
+        let channels = spec.channels.max(1) as usize;
+        // Always long enough for the backlog of late joiners
+        let window_seconds = replay_window
+            .as_secs_f64()
+            .max(INITIAL_BUFFER_SECONDS as f64);
+        let window_samples = (window_seconds * spec.sample_rate as f64) as usize * channels;
+        let backlog_samples =
+            (spec.sample_rate as usize * channels * INITIAL_BUFFER_SECONDS) as u64;
+        let window = Arc::new(Mutex::new(ReplayWindow::new(window_samples)));
+
+        let codecs = codecs.to_vec();
+        let greeted = Arc::clone(&window);
+        let replayed = Arc::clone(&window);
+        let tcp = TcpServer::bind_with(
+            address,
+            move |request| {
+                Self::handshake(request, stream_format, &codecs, &greeted, backlog_samples)
+            },
+            move |group, position| {
+                // Only copied while broadcasts wait, the writer of the client encodes them
+                let window = lock_window(&replayed);
+                let frames: Vec<SamplesFrame> = window.since(position).cloned().collect();
+                let total_samples = window.total_samples();
+                move || replay_messages(&frames, total_samples, group, spec.channels)
+            },
+        )
+        .map_err(|_| {
+            error!("Couldn't connect to server at {address}");
+            AppError::Bind
+        })?;
+        Ok((tcp, window))
+    }
+
+    /// Streams `source` to every connected client until it runs out of samples.
+    pub fn play(&mut self, source: &mut dyn AudioSource) -> Result<(), AppError> {
+        const SAMPLES_PER_GROUP: usize = 1_000;
 
-        let spec = input.get_spec();
+        let spec = source.spec();
+        let stream_format = match source.format() {
+            Ok(format) => format,
+            Err(_) => {
+                error!("Unsupported sample format in audio source: {:?}", spec);
+                return Err(AppError::UnsupportedFormat);
+            }
+        };
+        if let Some(frames) = source.duration() {
+            info!(
+                "Streaming {:.1} seconds of audio",
+                frames as f64 / spec.sample_rate as f64
+            );
+        }
         /// Fraction of real‑time used to pace sending, leaving headroom for
         /// network and processing latency.
         const PLAYBACK_PACING_FACTOR: f64 = 0.8;
-        /// Amount of audio (in seconds) to preload before pacing
-        /// to build up a latency buffer on the client.
-        const INITIAL_BUFFER_SECONDS: usize = 3;
 
-        let wait_time_micros = ((SAMPLES_PER_GROUP * 1_000_000) as f64) * PLAYBACK_PACING_FACTOR
+        self.channels = spec.channels;
+        let channels = spec.channels.max(1) as usize;
+        // Whole frames only, so a message never splits the channels of a frame
+        let frames_per_group = (SAMPLES_PER_GROUP / channels).max(1);
+        let samples_per_group = frames_per_group * channels;
+
+        let wait_time_micros = ((frames_per_group * 1_000_000) as f64) * PLAYBACK_PACING_FACTOR
             / (spec.sample_rate as f64);
         // We multiply by 0.8 to account for network latency and processing time
 
-        match AudioMessage::Spec(spec).serialize(&mut serialization_buffer) {
-            Ok(_) => (),
-            Err(_) => {
-                error!("Couldn't serialize wav spec for file: {}", filepath);
+        // Each group gets the spec advertising its codec
+        let mut codecs = self.codecs.clone();
+        codecs.push(DOWNGRADE_CODEC);
+        for codec in codecs {
+            let mut serialization_buffer = Vec::new();
+            let message = AudioMessage::Spec { spec, codec };
+            if message.serialize(&mut serialization_buffer).is_err() {
+                error!("Couldn't serialize wav spec: {:?}", spec);
                 return Err(AppError::Serialization);
             }
-        };
+            for flags in [
+                0,
+                DOWNGRADABLE,
+                ACKNOWLEDGES_END,
+                DOWNGRADABLE | ACKNOWLEDGES_END,
+            ] {
+                let group = ClientGroup::from(codec) | flags;
+                self.tcp.set_group_message(group, &serialization_buffer);
+                self.spec_messages
+                    .insert(group, serialization_buffer.clone());
+            }
+        }
+
+        // HTTP clients joining at any time get a header as if the file started there, and
+        // no backlog since they skip the handshake. Their players buffer before starting.
+        self.tcp
+            .set_group_message(HTTP_GROUP, &streaming_wav_header(&spec));
+        // Members of the group can't be greeted, they get the spec every so often instead
+        if let Some(multicast) = &mut self.multicast
+            && let Some(message) = self.spec_messages.get(&ClientGroup::from(self.codecs[0]))
+        {
+            multicast.set_announcement(message);
+        }
 
-        self.tcp.set_new_client_message(&serialization_buffer);
-        while self.tcp.get_client_count() == 0 {
+        // RTP players and multicast clients can't be waited for, they just listen
+        while self.rtp.is_none() && self.multicast.is_none() && self.tcp.get_client_count() == 0 {
             info!("No clients connected, waiting for clients to connect...");
             sleep(Duration::from_secs(1));
         }
-        match self.tcp.broadcast(&serialization_buffer) {
-            Ok(r) => r,
-            Err(_) => {
+        for group in self.tcp.client_groups() {
+            // A second WAV header would end up in the middle of the samples
+            if group == HTTP_GROUP {
+                continue;
+            }
+            let Some(message) = self.spec_messages.get(&group) else {
+                self.disconnect_group(group);
+                continue;
+            };
+            if self.tcp.broadcast_to_group(group, message).is_err() {
                 error!("Couldn't send wav spec to clients");
                 return Err(AppError::Broadcast);
             }
-        };
+        }
 
-        serialization_buffer.clear();
-        let samples = input.iter_samples();
-        let mut sample_group: Vec<i16> = Vec::with_capacity(SAMPLES_PER_GROUP);
+        let mut sample_group = SampleBuffer::with_capacity(stream_format, samples_per_group);
         let mut sent_samples = 0;
-        for sample in samples {
-            match sample {
-                Ok(s) => {
-                    sample_group.push(s);
-                    if sample_group.len() < SAMPLES_PER_GROUP {
-                        continue;
-                    }
-                    self.play_samples_group(&sample_group)?;
-                    sent_samples += sample_group.len();
-                    sample_group.clear();
-                    if sent_samples > spec.sample_rate as usize * INITIAL_BUFFER_SECONDS {
-                        sleep(Duration::from_micros(wait_time_micros as u64));
-                    }
-                }
+        loop {
+            sample_group.clear();
+            let read = match source.read_samples(&mut sample_group, samples_per_group) {
+                Ok(read) => read,
                 Err(e) => {
                     error!("Error reading sample: {}", e);
-                    return Err(AppError::WavFileRead);
+                    return Err(AppError::SourceRead);
                 }
+            };
+            if read == 0 {
+                break;
+            }
+            if let Some(title) = &self.title
+                && let Some(track) = source.track_title()
+            {
+                title.set(&track);
             }
-        }
-        if !sample_group.is_empty() {
             self.play_samples_group(&sample_group)?;
+            self.check_lag(frames_per_group as f64 / spec.sample_rate as f64);
+            sent_samples += read;
+            if sent_samples > spec.sample_rate as usize * channels * INITIAL_BUFFER_SECONDS {
+                sleep(Duration::from_micros(wait_time_micros as u64));
+            }
         }
-        while self.tcp.get_client_count() > 0 {
-            info!("Waiting for clients to finish playback...");
-            sleep(Duration::from_secs(1));
+        self.end_stream(sent_samples as u64)
+    }
+
+    /// Tells clients the stream is over, then waits until each of them acknowledged it
+    /// or went away.
+    fn end_stream(&mut self, total_samples: u64) -> Result<(), AppError> {
+        let mut serialization_buffer = Vec::new();
+        let end_of_stream = AudioMessage::EndOfStream { total_samples };
+        if end_of_stream.serialize(&mut serialization_buffer).is_err() {
+            error!("Couldn't serialize end of stream");
+            return Err(AppError::Serialization);
+        }
+        // Clients resuming from now on get it with the frames they missed
+        lock_window(&self.replay).end(total_samples);
+        for group in self.tcp.client_groups() {
+            if group & ACKNOWLEDGES_END != 0
+                && self
+                    .tcp
+                    .broadcast_to_group(group, &serialization_buffer)
+                    .is_err()
+            {
+                error!("Couldn't send end of stream to clients");
+                return Err(AppError::Broadcast);
+            }
+        }
+        // The others, HTTP clients among them, don't acknowledge: their connection ends
+        // with the stream
+        for lag in self.tcp.client_lags() {
+            if lag.group & ACKNOWLEDGES_END == 0 {
+                self.tcp.finish_client(lag.id);
+            }
+        }
+        if let Some(rtp) = &mut self.rtp
+            && let Err(e) = rtp.close()
+        {
+            warn!("Couldn't end the RTP stream: {}", e);
+        }
+        if let Some(multicast) = &mut self.multicast {
+            if let Err(e) = multicast.send(&serialization_buffer) {
+                warn!(
+                    "Couldn't send end of stream to the multicast group: {:?}",
+                    e
+                );
+            }
+            multicast.close();
+        }
+        let deadline = Instant::now() + self.end_of_stream_timeout;
+        let mut acknowledged = HashSet::new();
+        loop {
+            let pending: Vec<ClientId> = self
+                .tcp
+                .client_lags()
+                .into_iter()
+                .filter(|lag| lag.group & ACKNOWLEDGES_END != 0 && !acknowledged.contains(&lag.id))
+                .map(|lag| lag.id)
+                .collect();
+            if pending.is_empty() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                for id in pending {
+                    warn!(
+                        "Disconnecting client {} which didn't acknowledge the end of the stream within {:.1} s",
+                        id,
+                        self.end_of_stream_timeout.as_secs_f64()
+                    );
+                    self.tcp.disconnect_client(id);
+                }
+                return Ok(());
+            }
+            let timeout = (deadline - now).min(Duration::from_secs(1));
+            let Some((id, frame)) = self.tcp.receive(timeout) else {
+                info!(
+                    "Waiting for {} clients to finish playback...",
+                    pending.len()
+                );
+                continue;
+            };
+            match AudioMessage::deserialize(&frame) {
+                Ok(AudioMessage::EndOfStreamAck { received_samples }) => {
+                    info!(
+                        "Client {} finished playback, received {} of {} samples",
+                        id, received_samples, total_samples
+                    );
+                    acknowledged.insert(id);
+                }
+                Ok(other) => warn!("Unexpected message from client {}: {:?}", id, other),
+                Err(e) => warn!("Couldn't deserialize message from client {}: {:?}", id, e),
+            }
+        }
+    }
+
+    /// Disconnects the clients of `group`, which the server has no spec or codec for.
+    fn disconnect_group(&mut self, group: ClientGroup) {
+        for lag in self.tcp.client_lags() {
+            if lag.group == group {
+                error!("Disconnecting client {} in unknown group {}", lag.id, group);
+                self.tcp.disconnect_client(lag.id);
+            }
         }
-        Ok(())
     }
 
-    fn play_samples_group(&mut self, samples: &[i16]) -> Result<(), AppError> {
+    /// Evicts or downgrades the clients having more than `max_lag` of audio queued,
+    /// each queued message holding `message_seconds` of audio.
+    fn check_lag(&mut self, message_seconds: f64) {
+        for lag in self.tcp.client_lags() {
+            let seconds = lag.queued_frames as f64 * message_seconds;
+            if seconds <= self.max_lag.as_secs_f64() {
+                continue;
+            }
+            let reason = format!(
+                "{:.1} s of audio ({} bytes) queued, more than the {:.1} s allowed",
+                seconds,
+                lag.queued_bytes,
+                self.max_lag.as_secs_f64()
+            );
+            let group =
+                ClientGroup::from(DOWNGRADE_CODEC) | DOWNGRADABLE | (lag.group & ACKNOWLEDGES_END);
+            if self.lag_policy == LagPolicy::Downgrade
+                && lag.group & DOWNGRADABLE != 0
+                && group_codec(lag.group) != Some(DOWNGRADE_CODEC)
+                && let Some(message) = self.spec_messages.get(&group)
+            {
+                warn!(
+                    "Downgrading client {} to {:?}: {}",
+                    lag.id, DOWNGRADE_CODEC, reason
+                );
+                self.tcp.move_client(lag.id, group, message);
+            } else {
+                warn!("Evicting client {}: {}", lag.id, reason);
+                self.tcp.disconnect_client(lag.id);
+            }
+        }
+    }
+
+    fn play_samples_group(&mut self, samples: &SampleBuffer) -> Result<(), AppError> {
         let mut serialization_buffer = Vec::new();
-        match AudioMessage::Samples(samples.to_vec()).serialize(&mut serialization_buffer) {
-            Ok(_) => (),
-            Err(_) => {
+        let frame = SamplesFrame {
+            sequence: self.next_sequence,
+            position: self.next_position,
+            samples: samples.clone(),
+        };
+        self.next_sequence += 1;
+        self.next_position += samples.len() as u64;
+        // Kept before broadcasting, so a client resuming in between gets the frame twice
+        // rather than never
+        lock_window(&self.replay).push(frame.clone());
+        if let Some(rtp) = &mut self.rtp
+            && let Err(e) = rtp.send(&frame)
+        {
+            error!("Couldn't send RTP packets: {}", e);
+            return Err(AppError::Broadcast);
+        }
+        if let Some(multicast) = &mut self.multicast {
+            let message = samples_message(&frame, self.codecs[0], self.channels);
+            if message.serialize(&mut serialization_buffer).is_err() {
                 error!("Couldn't serialize samples");
                 return Err(AppError::Serialization);
             }
-        };
-        match self.tcp.broadcast(&serialization_buffer) {
-            Ok(_) => Ok(()),
-            Err(_) => {
+            if let Err(e) = multicast.send(&serialization_buffer) {
+                error!("Couldn't send samples to the multicast group: {:?}", e);
+                return Err(AppError::Broadcast);
+            }
+        }
+        for group in self.tcp.client_groups() {
+            serialization_buffer.clear();
+            if group == HTTP_GROUP {
+                write_wav_samples(&frame.samples, &mut serialization_buffer);
+            } else {
+                let Some(codec) = group_codec(group) else {
+                    self.disconnect_group(group);
+                    continue;
+                };
+                let message = samples_message(&frame, codec, self.channels);
+                if message.serialize(&mut serialization_buffer).is_err() {
+                    error!("Couldn't serialize samples");
+                    return Err(AppError::Serialization);
+                }
+            }
+            if self
+                .tcp
+                .broadcast_to_group(group, &serialization_buffer)
+                .is_err()
+            {
                 error!("Couldn't send samples to clients");
-                Err(AppError::Broadcast)
+                return Err(AppError::Broadcast);
             }
         }
+        Ok(())
     }
 }
 
@@ -121,23 +574,476 @@ fn main() {
     let address = format!("{ip}:{port}");
     println!("Starting server at {address}");
 
-    let tcp = match TcpServer::bind(&address) {
-        Ok(t) => t,
-        Err(_) => {
-            error!("Couldn't connect to server at {address}");
-            return;
-        }
-    };
-    let mut app = Application { tcp };
-    let filepath = match cli.wav.path.to_str() {
+    let filepath = match cli.input.path.to_str() {
         Some(f) => f,
         None => {
             error!("Unexpected error: Invalid file path");
             return;
         }
     };
-    match app.play_wav_file(filepath) {
-        Ok(_) => info!("Finished playing WAV file"),
+    let mut input = match open_audio_file(filepath) {
+        Ok(input) => input,
+        Err(e) => {
+            error!("Couldn't read audio file {}: {}", filepath, e);
+            return;
+        }
+    };
+    // Plain PCM stays available to clients that can't decode the chosen codec
+    let mut codecs = vec![cli.codec];
+    if cli.codec != Codec::Pcm {
+        codecs.push(Codec::Pcm);
+    }
+    let replay_window = Duration::from_secs_f64(cli.replay_window.max(0.0));
+    let (mut tcp, replay) =
+        match Application::bind(&address, input.as_ref(), &codecs, replay_window) {
+            Ok(bound) => bound,
+            Err(e) => {
+                error!("{:?}", e);
+                return;
+            }
+        };
+    if let Some(path) = &cli.unix
+        && let Err(e) = tcp.listen_unix(path)
+    {
+        error!("Couldn't listen on {}: {}", path.display(), e);
+        return;
+    }
+    if cli.udp {
+        let mut listener = match UdpListener::bind(&address) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Couldn't listen for UDP clients on {}: {}", address, e);
+                return;
+            }
+        };
+        if cli.udp_loss > 0.0 {
+            warn!(
+                "Dropping {:.1}% of the datagrams sent to UDP clients",
+                cli.udp_loss * 100.0
+            );
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+            listener.set_loss(LossInjector::new(cli.udp_loss, seed));
+        }
+        tcp.listen_udp(listener);
+    }
+    let mut title = None;
+    if let Some(http_port) = cli.http {
+        let http_address = format!("{ip}:{http_port}");
+        let mut listener = match HttpListener::bind(&http_address) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(
+                    "Couldn't listen for HTTP clients on {}: {}",
+                    http_address, e
+                );
+                return;
+            }
+        };
+        if let Some(mount) = &cli.icecast_mount {
+            // Until the input tells which track it is, the file name will do
+            let stream_title = StreamTitle::new();
+            if let Some(stem) = cli.input.path.file_stem() {
+                stream_title.set(&stem.to_string_lossy());
+            }
+            listener.mount_icecast(mount, &cli.icy_name, stream_title.clone());
+            info!("Icecast mount point at http://{}{}", http_address, mount);
+            title = Some(stream_title);
+        }
+        if let Err(e) = tcp.listen_http(listener, HTTP_GROUP) {
+            error!(
+                "Couldn't listen for HTTP clients on {}: {}",
+                http_address, e
+            );
+            return;
+        }
+    }
+    tcp.set_queue_policy(cli.queue_policy);
+    let mut app = Application {
+        tcp,
+        rtp: None,
+        multicast: None,
+        title,
+        codecs,
+        max_lag: Duration::from_secs_f64(cli.max_lag.max(0.0)),
+        lag_policy: cli.lag_policy,
+        end_of_stream_timeout: Duration::from_secs_f64(cli.end_of_stream_timeout.max(0.0)),
+        replay,
+        spec_messages: HashMap::new(),
+        channels: 0,
+        next_sequence: 0,
+        next_position: 0,
+    };
+    if let Some(destination) = cli.rtp {
+        let rtp = match RtpSender::new(destination, &input.spec()) {
+            Ok(rtp) => rtp,
+            Err(e) => {
+                error!("Couldn't stream RTP to {}: {}", destination, e);
+                return;
+            }
+        };
+        if let Err(e) = fs::write(&cli.sdp, rtp.description().to_sdp()) {
+            error!("Couldn't write SDP file {}: {}", cli.sdp.display(), e);
+            return;
+        }
+        info!(
+            "Streaming RTP to {}, open {} to listen",
+            destination,
+            cli.sdp.display()
+        );
+        app.rtp = Some(rtp);
+    }
+    if let Some(group) = cli.multicast {
+        match MulticastSender::new(group, cli.multicast_interface) {
+            Ok(multicast) => {
+                info!("Streaming to multicast group {}", group);
+                app.multicast = Some(multicast);
+            }
+            Err(e) => {
+                error!("Couldn't stream to multicast group {}: {}", group, e);
+                return;
+            }
+        }
+    }
+    match app.play(input.as_mut()) {
+        Ok(_) => info!("Finished playing audio file"),
         Err(e) => error!("{:?}", e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Application, DOWNGRADE_CODEC, INITIAL_BUFFER_SECONDS};
+    use hound::{SampleFormat, WavSpec};
+    use sonos_challenge::audio::{
+        AudioMessage, AudioSink, AudioSource, Codec, Features, Hello, PcmFormat, ReplayWindow,
+        SampleBuffer, Serializable, SineWave, WavAudioOutput, WavFileSink, open_audio_file,
+    };
+    use sonos_challenge::cli::LagPolicy;
+    use sonos_challenge::network::memory::{self, MemorySender};
+    use sonos_challenge::network::{
+        FrameReceiver, FrameSender, TcpClient, TcpServer, TransportError,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+
+    const SAMPLE_RATE: u32 = 96_000;
+    const SECONDS: u32 = 3;
+
+    /// Sends nothing while the gate is locked, like a client that stopped reading.
+    struct GatedSender {
+        gate: Arc<Mutex<()>>,
+        sender: MemorySender,
+    }
+
+    impl FrameSender for GatedSender {
+        fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+            drop(self.gate.lock().unwrap());
+            self.sender.send(data)
+        }
+        fn close(&mut self) {
+            self.sender.close();
+        }
+    }
+
+    /// Application streaming PCM with `tcp`, downgrading clients lagging over `max_lag`.
+    fn application(
+        tcp: TcpServer,
+        replay: Arc<Mutex<ReplayWindow>>,
+        max_lag: Duration,
+    ) -> Application {
+        Application {
+            tcp,
+            rtp: None,
+            multicast: None,
+            title: None,
+            codecs: vec![Codec::Pcm],
+            max_lag,
+            lag_policy: LagPolicy::Downgrade,
+            end_of_stream_timeout: Duration::from_secs(10),
+            replay,
+            spec_messages: HashMap::new(),
+            channels: 0,
+            next_sequence: 0,
+            next_position: 0,
+        }
+    }
+
+    /// Serialized `message`.
+    fn serialized(message: AudioMessage) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        message
+            .serialize(&mut buffer)
+            .expect("Failed to serialize message");
+        buffer
+    }
+
+    /// Streams `source` to a client able to decode ADPCM that stalls for a second, long
+    /// enough for more than the two seconds of audio it may lag to pile up in its queue.
+    /// Returns the specs it was announced, and how many samples it wrote to a WAV file as
+    /// against how many the file holds in the end.
+    fn stream_to_stalled_client(
+        name: &str,
+        mut source: Box<dyn AudioSource>,
+        sample_formats: Vec<PcmFormat>,
+    ) -> (Vec<(WavSpec, Codec)>, usize, usize) {
+        let (mut tcp, replay) = Application::bind(
+            "localhost:0",
+            source.as_ref(),
+            &[Codec::Pcm],
+            Duration::ZERO,
+        )
+        .expect("Failed to start TCP server");
+        let path = std::env::temp_dir().join(format!("sonos-stalled-{}.wav", name));
+        let path = path.to_str().unwrap().to_string();
+        let (mut to_server, from_client) = memory::channel();
+        let (to_client, mut from_server) = memory::channel();
+        let gate = Arc::new(Mutex::new(()));
+        let sender = GatedSender {
+            gate: Arc::clone(&gate),
+            sender: to_client,
+        };
+        tcp.add_client(Box::new(sender), Box::new(from_client));
+        let hello = Hello::new(
+            sample_formats,
+            vec![Codec::Pcm, DOWNGRADE_CODEC],
+            Features::END_OF_STREAM,
+        );
+        to_server
+            .send(&serialized(AudioMessage::Hello(hello)))
+            .expect("Failed to send Hello");
+        let mut buffer = Vec::new();
+        from_server
+            .receive(&mut buffer)
+            .expect("Failed to receive Welcome");
+        while tcp.get_client_count() == 0 {
+            sleep(Duration::from_millis(10)); // Wait for the server to register the client
+        }
+        let (stalled, stalling) = mpsc::channel();
+        let client = spawn(move || {
+            let stall = gate.lock().unwrap();
+            stalled.send(()).unwrap();
+            sleep(Duration::from_secs(1));
+            drop(stall);
+
+            let mut sink = WavFileSink::new(&path);
+            let mut specs = Vec::new();
+            let mut written = 0;
+            while from_server.receive(&mut buffer).is_ok() {
+                let samples = match AudioMessage::deserialize(&buffer) {
+                    Ok(AudioMessage::Spec { spec, codec }) => {
+                        sink.configure(&codec.decoded_spec(&spec))
+                            .expect("Failed to configure sink");
+                        specs.push((spec, codec));
+                        continue;
+                    }
+                    Ok(AudioMessage::Samples(frame)) => frame.samples,
+                    Ok(AudioMessage::EncodedSamples(encoded)) => {
+                        encoded.decode().expect("Failed to decode frame").samples
+                    }
+                    Ok(AudioMessage::EndOfStream { total_samples }) => {
+                        let ack = serialized(AudioMessage::EndOfStreamAck {
+                            received_samples: total_samples,
+                        });
+                        to_server.send(&ack).expect("Failed to send ack");
+                        break;
+                    }
+                    other => panic!("Unexpected message {:?}", other),
+                };
+                written += samples.len();
+                sink.write_samples(&samples)
+                    .expect("Failed to write samples");
+            }
+            sink.finalize().expect("Failed to finalize sink");
+            let mut recorded = open_audio_file(&path).expect("Failed to open recording");
+            let mut samples = SampleBuffer::new(recorded.format().expect("Unknown format"));
+            recorded
+                .get_all_samples(&mut samples)
+                .expect("Failed to read recording");
+            (specs, written, samples.len())
+        });
+
+        let mut app = application(tcp, replay, Duration::from_secs(2));
+        stalling.recv().unwrap();
+        app.play(source.as_mut()).expect("Failed to stream");
+        client.join().expect("Client panicked")
+    }
+
+    #[test]
+    fn lagging_client_is_downgraded_to_the_same_spec() {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let path = std::env::temp_dir().join("sonos-downgraded-source.wav");
+        let path = path.to_str().unwrap();
+        let mut output = WavAudioOutput::new(path, spec).expect("Failed to create source");
+        let samples = (0..SAMPLE_RATE * SECONDS * 2).map(|i| (i % 2000) as i16 - 1000);
+        output
+            .write_samples(&SampleBuffer::I16(samples.collect()))
+            .expect("Failed to write source");
+        output.finalize().expect("Failed to finalize source");
+        let source = open_audio_file(path).expect("Failed to open source");
+
+        let (specs, written, recorded) =
+            stream_to_stalled_client("downgraded", source, vec![PcmFormat::I16]);
+        let codecs: Vec<Codec> = specs.iter().map(|(_, codec)| *codec).collect();
+        assert_eq!(codecs, [Codec::Pcm, DOWNGRADE_CODEC]);
+        let (first, second) = (specs[0], specs[1]);
+        assert_eq!(
+            first.1.decoded_spec(&first.0),
+            second.1.decoded_spec(&second.0)
+        );
+        // The recording goes on in the same file rather than starting over
+        assert!(written > 0);
+        assert_eq!(recorded, written);
+    }
+
+    #[test]
+    fn float_stream_client_is_evicted_rather_than_downgraded() {
+        let source =
+            SineWave::new(440.0, SAMPLE_RATE, 2).with_frames((SAMPLE_RATE * SECONDS) as u64);
+        // ADPCM decodes to 16 bits, the output opened for floats couldn't take them
+        let (specs, written, recorded) = stream_to_stalled_client(
+            "evicted",
+            Box::new(source),
+            vec![PcmFormat::F32, PcmFormat::I16],
+        );
+        let codecs: Vec<Codec> = specs.iter().map(|(_, codec)| *codec).collect();
+        assert_eq!(codecs, [Codec::Pcm]);
+        assert!(written < (SAMPLE_RATE * SECONDS * 2) as usize);
+        assert_eq!(recorded, written);
+    }
+
+    /// Joins the stream at `address` over TCP as a new client getting PCM floats.
+    fn join(address: &str, features: Features) -> TcpClient {
+        let mut client = TcpClient::connect(address).expect("Failed to connect TCP client");
+        let hello = Hello::new(vec![PcmFormat::F32], vec![Codec::Pcm], features);
+        client
+            .send(&serialized(AudioMessage::Hello(hello)))
+            .expect("Failed to send Hello");
+        let mut buffer = Vec::new();
+        client
+            .receive(&mut buffer)
+            .expect("Failed to receive Welcome");
+        client
+    }
+
+    /// Reads the stream until it ends, then acknowledges it. Returns the position and
+    /// length of every frame, along with when it arrived.
+    fn receive_frames(client: &mut TcpClient) -> Vec<(u64, usize, Instant)> {
+        let mut buffer = Vec::new();
+        client.receive(&mut buffer).expect("Failed to receive Spec");
+        let message = AudioMessage::deserialize(&buffer).expect("Failed to deserialize");
+        assert!(
+            matches!(message, AudioMessage::Spec { .. }),
+            "{:?}",
+            message
+        );
+        let mut frames = Vec::new();
+        loop {
+            client
+                .receive(&mut buffer)
+                .expect("Failed to receive frame");
+            match AudioMessage::deserialize(&buffer).expect("Failed to deserialize") {
+                AudioMessage::Samples(frame) => {
+                    frames.push((frame.position, frame.samples.len(), Instant::now()))
+                }
+                AudioMessage::EndOfStream { total_samples } => {
+                    let ack = serialized(AudioMessage::EndOfStreamAck {
+                        received_samples: total_samples,
+                    });
+                    client.send(&ack).expect("Failed to send ack");
+                    return frames;
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn late_joiner_starts_with_the_latest_seconds() {
+        const RATE: u32 = 8_000;
+        const TOTAL_SAMPLES: u64 = 5 * RATE as u64;
+        let address = "localhost:50125";
+        let mut source = SineWave::new(440.0, RATE, 1).with_frames(TOTAL_SAMPLES);
+        let (tcp, replay) = Application::bind(address, &source, &[Codec::Pcm], Duration::ZERO)
+            .expect("Failed to start TCP server");
+        let mut first = join(address, Features::END_OF_STREAM);
+        while tcp.get_client_count() == 0 {
+            sleep(Duration::from_millis(10)); // Wait for the server to register the client
+        }
+        let first = spawn(move || receive_frames(&mut first));
+        let mut app = application(tcp, replay, Duration::from_secs(60));
+        let playing = spawn(move || app.play(&mut source).is_ok());
+
+        // Well past the initial burst, the stream is paced by now
+        sleep(Duration::from_millis(500));
+        let mut late = join(address, Features::END_OF_STREAM);
+        let joined = Instant::now();
+        let frames = receive_frames(&mut late);
+        assert!(playing.join().expect("Server panicked"));
+        first.join().expect("First client panicked");
+
+        // It starts mid-stream, on a frame boundary, with the latest seconds of audio
+        let (start, length, _) = frames[0];
+        assert!(start > 0);
+        assert_eq!(start % length as u64, 0);
+        let backlog = (RATE as usize * INITIAL_BUFFER_SECONDS) as u64;
+        let (_, _, caught_up) = frames
+            .iter()
+            .find(|(position, length, _)| position + *length as u64 >= start + backlog)
+            .expect("Backlog missing");
+        // Paced, they would take more than two seconds
+        assert!(caught_up.duration_since(joined) < Duration::from_millis(500));
+        // Then the frames broadcast since, without a gap. One may come twice, if it was
+        // kept for replay while the client joined and broadcast after.
+        let mut expected = start;
+        for (position, length, _) in frames {
+            assert!(position <= expected, "gap before {}", position);
+            expected = expected.max(position + length as u64);
+        }
+        assert_eq!(expected, TOTAL_SAMPLES);
+    }
+
+    /// Reads what `client` is sent until its connection ends. Returns whether that
+    /// included an `EndOfStream`.
+    fn receive_until_closed(client: &mut TcpClient) -> bool {
+        let mut buffer = Vec::new();
+        let mut ended = false;
+        while client.receive(&mut buffer).is_ok() {
+            ended |= matches!(
+                AudioMessage::deserialize(&buffer),
+                Ok(AudioMessage::EndOfStream { .. })
+            );
+        }
+        ended
+    }
+
+    #[test]
+    fn end_of_stream_waits_only_for_clients_that_negotiated_it() {
+        const RATE: u32 = 8_000;
+        let address = "localhost:50126";
+        let mut source = SineWave::new(440.0, RATE, 1).with_frames(RATE as u64);
+        let (tcp, replay) = Application::bind(address, &source, &[Codec::Pcm], Duration::ZERO)
+            .expect("Failed to start TCP server");
+        let mut silent = join(address, Features::END_OF_STREAM);
+        let mut legacy = join(address, Features::NONE);
+        while tcp.get_client_count() < 2 {
+            sleep(Duration::from_millis(10)); // Wait for the server to register the clients
+        }
+        let mut app = application(tcp, replay, Duration::from_secs(60));
+        app.end_of_stream_timeout = Duration::from_millis(200);
+        app.play(&mut source).expect("Failed to stream");
+
+        // Never acknowledging, the first is dropped once the timeout ran out
+        assert!(receive_until_closed(&mut silent));
+        // The other is never sent a message it doesn't know, its connection just ends
+        assert!(!receive_until_closed(&mut legacy));
+    }
+}